@@ -0,0 +1,7683 @@
+// CrawChain: Modular Blockchain Codebase with Enhanced Security, Sharding, WASM, and ECC Integration.
+
+// Main Modules
+use address::Address;
+
+pub mod amount {
+    use serde::{Serialize, Deserialize};
+    use std::fmt;
+    use std::str::FromStr;
+
+    /// Number of decimal places carried by every token amount.
+    pub const DECIMALS: u32 = 8;
+    const SCALE: u64 = 10u64.pow(DECIMALS);
+
+    /// A non-negative token amount stored as an integer count of base units
+    /// (`10^-DECIMALS` of a whole token), so sums are exact and formatting is stable.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[serde(transparent)]
+    pub struct Amount(u64);
+
+    impl Amount {
+        pub const ZERO: Amount = Amount(0);
+        pub const MAX: Amount = Amount(u64::MAX);
+
+        pub const fn from_base_units(units: u64) -> Self {
+            Amount(units)
+        }
+
+        pub const fn base_units(self) -> u64 {
+            self.0
+        }
+
+        /// `tokens` whole tokens, or `None` if that does not fit.
+        pub fn from_tokens(tokens: u64) -> Option<Self> {
+            tokens.checked_mul(SCALE).map(Amount)
+        }
+
+        pub fn is_zero(self) -> bool {
+            self.0 == 0
+        }
+
+        pub fn checked_add(self, other: Amount) -> Option<Amount> {
+            self.0.checked_add(other.0).map(Amount)
+        }
+
+        pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+            self.0.checked_sub(other.0).map(Amount)
+        }
+    }
+
+    /// Formats as a decimal number of whole tokens with trailing zeros dropped,
+    /// e.g. `10`, `0.5` or `1.00000001`.
+    impl fmt::Display for Amount {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let whole = self.0 / SCALE;
+            let fraction = self.0 % SCALE;
+            if fraction == 0 {
+                return write!(f, "{}", whole);
+            }
+            let digits = format!("{:0width$}", fraction, width = DECIMALS as usize);
+            write!(f, "{}.{}", whole, digits.trim_end_matches('0'))
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum AmountError {
+        Empty,
+        InvalidDigit,
+        TooManyDecimals,
+        Overflow,
+    }
+
+    impl fmt::Display for AmountError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                AmountError::Empty => write!(f, "amount is empty"),
+                AmountError::InvalidDigit => write!(f, "amount contains an invalid character"),
+                AmountError::TooManyDecimals => write!(f, "amount has more than {} decimal places", DECIMALS),
+                AmountError::Overflow => write!(f, "amount is too large"),
+            }
+        }
+    }
+
+    impl std::error::Error for AmountError {}
+
+    /// Parses the `Display` form exactly: digits with an optional fraction of at most
+    /// `DECIMALS` digits. Signs, exponents and surrounding whitespace are rejected.
+    impl FromStr for Amount {
+        type Err = AmountError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            if s.is_empty() {
+                return Err(AmountError::Empty);
+            }
+            let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+            if whole.is_empty() || s.ends_with('.') || !whole.bytes().chain(fraction.bytes()).all(|b| b.is_ascii_digit()) {
+                return Err(AmountError::InvalidDigit);
+            }
+            if fraction.len() > DECIMALS as usize {
+                return Err(AmountError::TooManyDecimals);
+            }
+
+            let mut units: u64 = 0;
+            let padded = format!("{:0<width$}", fraction, width = DECIMALS as usize);
+            for digit in whole.bytes().chain(padded.bytes()) {
+                units = units
+                    .checked_mul(10)
+                    .and_then(|units| units.checked_add(u64::from(digit - b'0')))
+                    .ok_or(AmountError::Overflow)?;
+            }
+            Ok(Amount(units))
+        }
+    }
+
+    /// Serde helpers for writing an `Amount` as its decimal string (`"12.5"`) rather than
+    /// base units, for hand-edited files. Use with `#[serde(with = "crate::amount::decimal")]`.
+    pub mod decimal {
+        use super::Amount;
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(amount: &Amount, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(amount)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+            let s = String::deserialize(deserializer)?;
+            s.parse().map_err(serde::de::Error::custom)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn amounts_round_trip() {
+            let cases = [
+                ("0", 0),
+                ("0.00000001", 1),
+                ("10", 1_000_000_000),
+                ("2500.5", 250_050_000_000),
+                ("42.125", 4_212_500_000),
+                ("184467440737.09551615", u64::MAX),
+            ];
+            for (text, units) in cases {
+                let amount: Amount = text.parse().unwrap();
+                assert_eq!(amount, Amount::from_base_units(units), "{}", text);
+                assert_eq!(amount.to_string(), text);
+            }
+            assert_eq!(Amount::MAX.to_string().parse::<Amount>(), Ok(Amount::MAX));
+        }
+
+        #[test]
+        fn whole_amounts_have_no_fraction() {
+            assert_eq!(Amount::from_tokens(7).unwrap().to_string(), "7");
+            assert_eq!("7.00000000".parse::<Amount>().unwrap().to_string(), "7");
+            assert_eq!(Amount::from_tokens(u64::MAX), None);
+        }
+
+        #[test]
+        fn malformed_amounts_are_rejected() {
+            let cases = [
+                ("", AmountError::Empty),
+                ("5.", AmountError::InvalidDigit),
+                (".5", AmountError::InvalidDigit),
+                ("1.2.3", AmountError::InvalidDigit),
+                ("-1", AmountError::InvalidDigit),
+                ("+1", AmountError::InvalidDigit),
+                (" 1", AmountError::InvalidDigit),
+                ("1e3", AmountError::InvalidDigit),
+                ("0.000000001", AmountError::TooManyDecimals),
+                ("184467440737.09551616", AmountError::Overflow),
+            ];
+            for (text, error) in cases {
+                assert_eq!(text.parse::<Amount>(), Err(error), "{:?}", text);
+            }
+        }
+
+        #[test]
+        fn arithmetic_is_checked() {
+            let one = Amount::from_base_units(1);
+            assert_eq!(Amount::MAX.checked_add(one), None);
+            assert_eq!(Amount::MAX.checked_sub(one).and_then(|amount| amount.checked_add(one)), Some(Amount::MAX));
+            assert_eq!(Amount::ZERO.checked_sub(one), None);
+            assert_eq!(one.checked_sub(one), Some(Amount::ZERO));
+            assert!(one.checked_sub(one).unwrap().is_zero());
+        }
+    }
+}
+
+pub mod encoding {
+    //! Canonical byte encoding used for everything that is signed or hashed.
+    //!
+    //! Integers are fixed-width big-endian, byte strings and text are prefixed with their
+    //! length as a big-endian `u32`, and optional values are a `0` byte (absent) or a `1`
+    //! byte followed by the value. See `docs/signing.md` for the transaction layout.
+
+    #[derive(Debug, Default)]
+    pub struct Encoder {
+        bytes: Vec<u8>,
+    }
+
+    impl Encoder {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn u8(&mut self, value: u8) -> &mut Self {
+            self.bytes.push(value);
+            self
+        }
+
+        pub fn u32(&mut self, value: u32) -> &mut Self {
+            self.bytes.extend_from_slice(&value.to_be_bytes());
+            self
+        }
+
+        pub fn u64(&mut self, value: u64) -> &mut Self {
+            self.bytes.extend_from_slice(&value.to_be_bytes());
+            self
+        }
+
+        pub fn bytes(&mut self, value: &[u8]) -> &mut Self {
+            let len = u32::try_from(value.len()).expect("encoded field longer than u32::MAX bytes");
+            self.u32(len);
+            self.bytes.extend_from_slice(value);
+            self
+        }
+
+        /// Appends `value` without a length prefix, for fixed-size fields such as hashes.
+        pub fn raw(&mut self, value: &[u8]) -> &mut Self {
+            self.bytes.extend_from_slice(value);
+            self
+        }
+
+        pub fn str(&mut self, value: &str) -> &mut Self {
+            self.bytes(value.as_bytes())
+        }
+
+        pub fn option<T>(&mut self, value: Option<T>, encode: impl FnOnce(&mut Self, T)) -> &mut Self {
+            match value {
+                None => self.u8(0),
+                Some(value) => {
+                    self.u8(1);
+                    encode(self, value);
+                    self
+                }
+            }
+        }
+
+        pub fn finish(&mut self) -> Vec<u8> {
+            std::mem::take(&mut self.bytes)
+        }
+    }
+}
+
+pub mod hash {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use sha2::{Digest, Sha256};
+    use std::fmt;
+    use std::str::FromStr;
+
+    /// A SHA-256 digest. Shown and serialized as lowercase hex.
+    #[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct Hash(pub [u8; 32]);
+
+    impl Hash {
+        pub const ZERO: Hash = Hash([0; 32]);
+
+        pub fn digest(data: &[u8]) -> Self {
+            Hash(Sha256::digest(data).into())
+        }
+
+        pub fn as_bytes(&self) -> &[u8; 32] {
+            &self.0
+        }
+    }
+
+    impl fmt::Display for Hash {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(&hex::encode(self.0))
+        }
+    }
+
+    impl fmt::Debug for Hash {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "Hash({})", self)
+        }
+    }
+
+    impl FromStr for Hash {
+        type Err = hex::FromHexError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let mut bytes = [0; 32];
+            hex::decode_to_slice(s, &mut bytes)?;
+            Ok(Hash(bytes))
+        }
+    }
+
+    impl Serialize for Hash {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(self)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Hash {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let s = String::deserialize(deserializer)?;
+            s.parse().map_err(serde::de::Error::custom)
+        }
+    }
+}
+
+pub mod crypto {
+    //! Signature schemes accepted for transactions. Every key and every transaction names
+    //! its scheme: ECDSA over P-256 or secp256k1 with SHA-256 and DER-encoded signatures,
+    //! or Ed25519 with 64-byte signatures, verified strictly (no malleable encodings).
+
+    use ed25519_dalek::Signer as _;
+    use p256::ecdsa::signature::{Signer as _, Verifier as _};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::fmt;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+    pub enum SignatureScheme {
+        #[default]
+        P256,
+        Ed25519,
+        Secp256k1,
+    }
+
+    impl SignatureScheme {
+        /// Tag identifying the scheme in canonical encodings.
+        pub fn tag(self) -> u8 {
+            match self {
+                SignatureScheme::P256 => 0,
+                SignatureScheme::Ed25519 => 1,
+                SignatureScheme::Secp256k1 => 2,
+            }
+        }
+    }
+
+    impl fmt::Display for SignatureScheme {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(match self {
+                SignatureScheme::P256 => "P-256",
+                SignatureScheme::Ed25519 => "Ed25519",
+                SignatureScheme::Secp256k1 => "secp256k1",
+            })
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum CryptoError {
+        InvalidKey(SignatureScheme),
+        MalformedSignature(SignatureScheme),
+        InvalidSignature,
+    }
+
+    impl fmt::Display for CryptoError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                CryptoError::InvalidKey(scheme) => write!(f, "not a valid {} key", scheme),
+                CryptoError::MalformedSignature(scheme) => write!(f, "not a valid {} signature encoding", scheme),
+                CryptoError::InvalidSignature => write!(f, "signature does not verify"),
+            }
+        }
+    }
+
+    impl std::error::Error for CryptoError {}
+
+    /// A public key of any supported scheme.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum PublicKey {
+        P256(p256::ecdsa::VerifyingKey),
+        Ed25519(ed25519_dalek::VerifyingKey),
+        Secp256k1(k256::ecdsa::VerifyingKey),
+    }
+
+    impl PublicKey {
+        /// Parses a key of `scheme`: SEC1 (compressed or not) for the ECDSA curves, the
+        /// 32-byte encoding for Ed25519.
+        pub fn from_bytes(scheme: SignatureScheme, bytes: &[u8]) -> Result<Self, CryptoError> {
+            let key = match scheme {
+                SignatureScheme::P256 => p256::ecdsa::VerifyingKey::from_sec1_bytes(bytes).ok().map(PublicKey::P256),
+                SignatureScheme::Ed25519 => <&[u8; 32]>::try_from(bytes)
+                    .ok()
+                    .and_then(|bytes| ed25519_dalek::VerifyingKey::from_bytes(bytes).ok())
+                    .map(PublicKey::Ed25519),
+                SignatureScheme::Secp256k1 => k256::ecdsa::VerifyingKey::from_sec1_bytes(bytes).ok().map(PublicKey::Secp256k1),
+            };
+            key.ok_or(CryptoError::InvalidKey(scheme))
+        }
+
+        pub fn scheme(&self) -> SignatureScheme {
+            match self {
+                PublicKey::P256(_) => SignatureScheme::P256,
+                PublicKey::Ed25519(_) => SignatureScheme::Ed25519,
+                PublicKey::Secp256k1(_) => SignatureScheme::Secp256k1,
+            }
+        }
+
+        /// Canonical encoding: compressed SEC1 for the ECDSA curves, 32 bytes for Ed25519.
+        pub fn to_bytes(self) -> Vec<u8> {
+            match self {
+                PublicKey::P256(key) => key.to_encoded_point(true).as_bytes().to_vec(),
+                PublicKey::Ed25519(key) => key.to_bytes().to_vec(),
+                PublicKey::Secp256k1(key) => key.to_bytes().to_vec(),
+            }
+        }
+
+        /// Checks `signature`, in the scheme's encoding, over `message`.
+        pub fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), CryptoError> {
+            let malformed = CryptoError::MalformedSignature(self.scheme());
+            let verified = match self {
+                PublicKey::P256(key) => {
+                    let signature = p256::ecdsa::Signature::from_der(signature).map_err(|_| malformed)?;
+                    key.verify(message, &signature).is_ok()
+                }
+                PublicKey::Ed25519(key) => {
+                    let signature = ed25519_dalek::Signature::from_slice(signature).map_err(|_| malformed)?;
+                    key.verify_strict(message, &signature).is_ok()
+                }
+                PublicKey::Secp256k1(key) => {
+                    let signature = k256::ecdsa::Signature::from_der(signature).map_err(|_| malformed)?;
+                    key.verify(message, &signature).is_ok()
+                }
+            };
+            match verified {
+                true => Ok(()),
+                false => Err(CryptoError::InvalidSignature),
+            }
+        }
+    }
+
+    impl From<p256::ecdsa::VerifyingKey> for PublicKey {
+        fn from(key: p256::ecdsa::VerifyingKey) -> Self {
+            PublicKey::P256(key)
+        }
+    }
+
+    impl From<ed25519_dalek::VerifyingKey> for PublicKey {
+        fn from(key: ed25519_dalek::VerifyingKey) -> Self {
+            PublicKey::Ed25519(key)
+        }
+    }
+
+    impl From<k256::ecdsa::VerifyingKey> for PublicKey {
+        fn from(key: k256::ecdsa::VerifyingKey) -> Self {
+            PublicKey::Secp256k1(key)
+        }
+    }
+
+    /// How a `PublicKey` is serialized: its scheme and its canonical encoding in hex.
+    #[derive(Serialize, Deserialize)]
+    struct EncodedKey {
+        scheme: SignatureScheme,
+        key: String,
+    }
+
+    impl Serialize for PublicKey {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            EncodedKey {
+                scheme: self.scheme(),
+                key: hex::encode(self.to_bytes()),
+            }
+            .serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for PublicKey {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let encoded = EncodedKey::deserialize(deserializer)?;
+            let bytes = hex::decode(&encoded.key).map_err(serde::de::Error::custom)?;
+            PublicKey::from_bytes(encoded.scheme, &bytes).map_err(serde::de::Error::custom)
+        }
+    }
+
+    /// A private key of any supported scheme.
+    #[derive(Clone)]
+    pub enum SecretKey {
+        P256(p256::ecdsa::SigningKey),
+        Ed25519(ed25519_dalek::SigningKey),
+        Secp256k1(k256::ecdsa::SigningKey),
+    }
+
+    impl SecretKey {
+        /// A fresh random key of `scheme`.
+        pub fn generate(scheme: SignatureScheme) -> Self {
+            let mut rng = rand_core::OsRng;
+            match scheme {
+                SignatureScheme::P256 => SecretKey::P256(p256::ecdsa::SigningKey::random(&mut rng)),
+                SignatureScheme::Ed25519 => SecretKey::Ed25519(ed25519_dalek::SigningKey::generate(&mut rng)),
+                SignatureScheme::Secp256k1 => SecretKey::Secp256k1(k256::ecdsa::SigningKey::random(&mut rng)),
+            }
+        }
+
+        /// Parses the 32-byte secret of a key of `scheme`.
+        pub fn from_bytes(scheme: SignatureScheme, bytes: &[u8]) -> Result<Self, CryptoError> {
+            let key = match scheme {
+                SignatureScheme::P256 => p256::ecdsa::SigningKey::from_bytes(bytes).ok().map(SecretKey::P256),
+                SignatureScheme::Ed25519 => <&[u8; 32]>::try_from(bytes)
+                    .ok()
+                    .map(|bytes| SecretKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(bytes))),
+                SignatureScheme::Secp256k1 => k256::ecdsa::SigningKey::from_bytes(bytes).ok().map(SecretKey::Secp256k1),
+            };
+            key.ok_or(CryptoError::InvalidKey(scheme))
+        }
+
+        /// The 32-byte secret, as accepted by `from_bytes`.
+        pub fn to_bytes(&self) -> Vec<u8> {
+            match self {
+                SecretKey::P256(key) => key.to_bytes().to_vec(),
+                SecretKey::Ed25519(key) => key.to_bytes().to_vec(),
+                SecretKey::Secp256k1(key) => key.to_bytes().to_vec(),
+            }
+        }
+
+        pub fn scheme(&self) -> SignatureScheme {
+            match self {
+                SecretKey::P256(_) => SignatureScheme::P256,
+                SecretKey::Ed25519(_) => SignatureScheme::Ed25519,
+                SecretKey::Secp256k1(_) => SignatureScheme::Secp256k1,
+            }
+        }
+
+        pub fn public_key(&self) -> PublicKey {
+            match self {
+                SecretKey::P256(key) => PublicKey::P256(key.verifying_key()),
+                SecretKey::Ed25519(key) => PublicKey::Ed25519(key.verifying_key()),
+                SecretKey::Secp256k1(key) => PublicKey::Secp256k1(key.verifying_key()),
+            }
+        }
+
+        /// Signs `message`, returning the signature in the scheme's encoding. ECDSA
+        /// signatures are deterministic (RFC 6979).
+        pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+            match self {
+                SecretKey::P256(key) => {
+                    let signature: p256::ecdsa::Signature = key.sign(message);
+                    signature.to_der().as_bytes().to_vec()
+                }
+                SecretKey::Ed25519(key) => key.sign(message).to_bytes().to_vec(),
+                SecretKey::Secp256k1(key) => {
+                    let signature: k256::ecdsa::Signature = key.sign(message);
+                    signature.to_der().as_bytes().to_vec()
+                }
+            }
+        }
+    }
+
+    impl fmt::Debug for SecretKey {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "SecretKey({}, public key {})", self.scheme(), hex::encode(self.public_key().to_bytes()))
+        }
+    }
+}
+
+pub mod address {
+    //! Account addresses. An address is the first 20 bytes of the SHA-256 of the account's
+    //! public key, so only the holder of that key can sign for it. Its text form is `crw`
+    //! followed by the hex of those bytes and of a 4-byte checksum, which catches mistyped
+    //! addresses before anything is sent to them.
+
+    use crate::crypto::PublicKey;
+    use crate::hash::Hash;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::fmt;
+    use std::str::FromStr;
+
+    /// Prefix of the text form of every address.
+    pub const ADDRESS_PREFIX: &str = "crw";
+    /// Length of an address in bytes.
+    pub const ADDRESS_LEN: usize = 20;
+    const CHECKSUM_LEN: usize = 4;
+
+    #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct Address(pub [u8; ADDRESS_LEN]);
+
+    impl Address {
+        /// The address controlled by `key`: the digest of its canonical encoding, preceded
+        /// by its scheme tag.
+        pub fn from_key(key: &PublicKey) -> Self {
+            let mut encoded = vec![key.scheme().tag()];
+            encoded.extend(key.to_bytes());
+            Self::from_digest(&Hash::digest(&encoded))
+        }
+
+        /// The address made of the first 20 bytes of `digest`.
+        pub fn from_digest(digest: &Hash) -> Self {
+            let mut bytes = [0; ADDRESS_LEN];
+            bytes.copy_from_slice(&digest.0[..ADDRESS_LEN]);
+            Address(bytes)
+        }
+
+        pub fn as_bytes(&self) -> &[u8; ADDRESS_LEN] {
+            &self.0
+        }
+
+        /// First four bytes of the SHA-256 of the address bytes.
+        fn checksum(&self) -> [u8; CHECKSUM_LEN] {
+            let mut checksum = [0; CHECKSUM_LEN];
+            checksum.copy_from_slice(&Hash::digest(&self.0).0[..CHECKSUM_LEN]);
+            checksum
+        }
+    }
+
+    impl fmt::Display for Address {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}{}{}", ADDRESS_PREFIX, hex::encode(self.0), hex::encode(self.checksum()))
+        }
+    }
+
+    impl fmt::Debug for Address {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "Address({})", self)
+        }
+    }
+
+    /// Why a string is not a valid address.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum AddressError {
+        MissingPrefix,
+        InvalidHex,
+        InvalidLength(usize),
+        BadChecksum,
+    }
+
+    impl fmt::Display for AddressError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                AddressError::MissingPrefix => write!(f, "address does not start with \"{}\"", ADDRESS_PREFIX),
+                AddressError::InvalidHex => write!(f, "address is not valid hex"),
+                AddressError::InvalidLength(len) => {
+                    write!(f, "address has {} bytes, expected {}", len, ADDRESS_LEN + CHECKSUM_LEN)
+                }
+                AddressError::BadChecksum => write!(f, "address checksum does not match"),
+            }
+        }
+    }
+
+    impl std::error::Error for AddressError {}
+
+    impl FromStr for Address {
+        type Err = AddressError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let encoded = s.strip_prefix(ADDRESS_PREFIX).ok_or(AddressError::MissingPrefix)?;
+            let bytes = hex::decode(encoded).map_err(|_| AddressError::InvalidHex)?;
+            if bytes.len() != ADDRESS_LEN + CHECKSUM_LEN {
+                return Err(AddressError::InvalidLength(bytes.len()));
+            }
+            let (payload, checksum) = bytes.split_at(ADDRESS_LEN);
+            let mut address = Address([0; ADDRESS_LEN]);
+            address.0.copy_from_slice(payload);
+            if address.checksum() != checksum {
+                return Err(AddressError::BadChecksum);
+            }
+            Ok(address)
+        }
+    }
+
+    impl Serialize for Address {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(self)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Address {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let s = String::deserialize(deserializer)?;
+            s.parse().map_err(serde::de::Error::custom)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::crypto::SignatureScheme;
+
+        const ALICE: &str = "crw16c52c97ac79d645bb98a0117d9e51df59b2b3cbf51b68c3";
+
+        #[test]
+        fn address_vector() {
+            let key = hex::decode("0360fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6").unwrap();
+            let key = PublicKey::from_bytes(SignatureScheme::P256, &key).unwrap();
+            let address = Address::from_key(&key);
+            assert_eq!(address.to_string(), ALICE);
+            assert_eq!(ALICE.parse::<Address>(), Ok(address));
+        }
+
+        #[test]
+        fn malformed_addresses_are_rejected() {
+            let mistyped = ALICE.replacen("16c5", "16c6", 1);
+            assert_eq!(mistyped.parse::<Address>(), Err(AddressError::BadChecksum));
+            assert_eq!(ALICE[3..].parse::<Address>(), Err(AddressError::MissingPrefix));
+            assert_eq!(format!("{}zz", ALICE).parse::<Address>(), Err(AddressError::InvalidHex));
+            assert_eq!(ALICE[..ALICE.len() - 2].parse::<Address>(), Err(AddressError::InvalidLength(23)));
+        }
+
+        #[test]
+        fn addresses_serialize_as_text() {
+            let address: Address = ALICE.parse().unwrap();
+            let json = serde_json::to_string(&address).unwrap();
+            assert_eq!(json, format!("\"{}\"", ALICE));
+            assert_eq!(serde_json::from_str::<Address>(&json).unwrap(), address);
+            assert!(serde_json::from_str::<Address>(&json.replacen("16c5", "16c6", 1)).is_err());
+        }
+    }
+}
+
+pub mod multisig {
+    //! M-of-N accounts. A multisig account is controlled by a policy, an ordered set of
+    //! keys and how many of them must sign, and its address is derived from the policy.
+    //! `Authority` is what the chain records for each account that may send: a single key
+    //! or such a policy. Its `KeyHistory` keeps every authority the account has had, so
+    //! that old blocks can be verified after a key rotation.
+
+    use crate::address::Address;
+    use crate::crypto::PublicKey;
+    use crate::encoding::Encoder;
+    use crate::hash::Hash;
+    use serde::{Deserialize, Serialize};
+    use std::fmt;
+
+    /// Domain tag that starts every policy encoding.
+    pub const MULTISIG_DOMAIN: &[u8] = b"CRAWCHAIN/MULTISIG";
+    /// Most keys a policy may list.
+    pub const MAX_POLICY_KEYS: usize = 16;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct MultisigPolicy {
+        pub threshold: u32, // Signatures needed, between 1 and the number of keys
+        pub keys: Vec<PublicKey>, // Cosignatures refer to keys by their position here
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum PolicyError {
+        NoKeys,
+        TooManyKeys(usize),
+        BadThreshold { threshold: u32, keys: usize },
+        DuplicateKey(usize),
+    }
+
+    impl fmt::Display for PolicyError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                PolicyError::NoKeys => write!(f, "policy lists no keys"),
+                PolicyError::TooManyKeys(count) => write!(f, "policy lists {} keys, at most {} are allowed", count, MAX_POLICY_KEYS),
+                PolicyError::BadThreshold { threshold, keys } => {
+                    write!(f, "threshold {} is not between 1 and the {} keys of the policy", threshold, keys)
+                }
+                PolicyError::DuplicateKey(index) => write!(f, "policy key {} is listed twice", index),
+            }
+        }
+    }
+
+    impl std::error::Error for PolicyError {}
+
+    impl MultisigPolicy {
+        pub fn new(threshold: u32, keys: Vec<PublicKey>) -> Result<Self, PolicyError> {
+            let policy = MultisigPolicy { threshold, keys };
+            policy.validate()?;
+            Ok(policy)
+        }
+
+        /// Checks the limits `new` enforces, for policies that were deserialized.
+        pub fn validate(&self) -> Result<(), PolicyError> {
+            if self.keys.is_empty() {
+                return Err(PolicyError::NoKeys);
+            }
+            if self.keys.len() > MAX_POLICY_KEYS {
+                return Err(PolicyError::TooManyKeys(self.keys.len()));
+            }
+            if self.threshold == 0 || self.threshold as usize > self.keys.len() {
+                return Err(PolicyError::BadThreshold { threshold: self.threshold, keys: self.keys.len() });
+            }
+            for (index, key) in self.keys.iter().enumerate() {
+                if self.keys[..index].contains(key) {
+                    return Err(PolicyError::DuplicateKey(index));
+                }
+            }
+            Ok(())
+        }
+
+        /// Canonical encoding: domain tag, threshold, then every key with its scheme tag,
+        /// in policy order.
+        pub fn encode(&self) -> Vec<u8> {
+            let mut encoder = Encoder::new();
+            encoder.bytes(MULTISIG_DOMAIN).u32(self.threshold).u32(self.keys.len() as u32);
+            for key in &self.keys {
+                encoder.u8(key.scheme().tag()).bytes(&key.to_bytes());
+            }
+            encoder.finish()
+        }
+
+        /// The address of the account this policy controls.
+        pub fn address(&self) -> Address {
+            Address::from_digest(&Hash::digest(&self.encode()))
+        }
+    }
+
+    /// Who may sign for an account.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub enum Authority {
+        Key(PublicKey),
+        Multisig(MultisigPolicy),
+    }
+
+    impl Authority {
+        /// The address this authority derives. Only registrations must derive the sender's
+        /// address; a rotated account keeps its address under any authority.
+        pub fn address(&self) -> Address {
+            match self {
+                Authority::Key(key) => Address::from_key(key),
+                Authority::Multisig(policy) => policy.address(),
+            }
+        }
+
+        /// Canonical encoding: `0` with the key's scheme tag and key, or `1` with the policy
+        /// encoding.
+        pub fn encode(&self) -> Vec<u8> {
+            let mut encoder = Encoder::new();
+            match self {
+                Authority::Key(key) => encoder.u8(0).u8(key.scheme().tag()).bytes(&key.to_bytes()),
+                Authority::Multisig(policy) => encoder.u8(1).bytes(&policy.encode()),
+            };
+            encoder.finish()
+        }
+
+        /// Checks a policy's limits; keys are checked when they are decoded.
+        pub fn validate(&self) -> Result<(), PolicyError> {
+            match self {
+                Authority::Key(_) => Ok(()),
+                Authority::Multisig(policy) => policy.validate(),
+            }
+        }
+    }
+
+    /// Who signs for an account from block `since` of its shard on, until the next record
+    /// of its history takes over.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct KeyRecord {
+        pub since: u64,
+        pub authority: Authority,
+        pub recovery: Option<Authority>, // May replace `authority` if it is lost or leaked
+    }
+
+    /// Every `KeyRecord` of an account, oldest first, with increasing `since`.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+    #[serde(transparent)]
+    pub struct KeyHistory {
+        records: Vec<KeyRecord>,
+    }
+
+    impl KeyHistory {
+        pub fn new(record: KeyRecord) -> Self {
+            KeyHistory { records: vec![record] }
+        }
+
+        /// The record in effect at block `height` of the account's shard.
+        pub fn at(&self, height: u64) -> Option<&KeyRecord> {
+            self.records.iter().rev().find(|record| record.since <= height)
+        }
+
+        /// The newest record, which may only take effect at a later height.
+        pub fn latest(&self) -> Option<&KeyRecord> {
+            self.records.last()
+        }
+
+        pub fn records(&self) -> &[KeyRecord] {
+            &self.records
+        }
+
+        /// Appends `record`, which must not start before the newest record.
+        pub fn push(&mut self, record: KeyRecord) {
+            self.records.push(record);
+        }
+
+        /// Appends the records of `later`, a history that continues this one.
+        pub fn extend(&mut self, later: KeyHistory) {
+            self.records.extend(later.records);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::crypto::{SecretKey, SignatureScheme};
+
+        fn keys(count: u8) -> Vec<PublicKey> {
+            (1..=count).map(|i| SecretKey::from_bytes(SignatureScheme::Ed25519, &[i; 32]).unwrap().public_key()).collect()
+        }
+
+        #[test]
+        fn policy_limits() {
+            assert_eq!(MultisigPolicy::new(1, vec![]), Err(PolicyError::NoKeys));
+            assert_eq!(MultisigPolicy::new(0, keys(2)), Err(PolicyError::BadThreshold { threshold: 0, keys: 2 }));
+            assert_eq!(MultisigPolicy::new(3, keys(2)), Err(PolicyError::BadThreshold { threshold: 3, keys: 2 }));
+            assert_eq!(MultisigPolicy::new(1, keys(17)), Err(PolicyError::TooManyKeys(17)));
+            let mut repeated = keys(3);
+            repeated.push(repeated[1]);
+            assert_eq!(MultisigPolicy::new(2, repeated), Err(PolicyError::DuplicateKey(3)));
+            assert!(MultisigPolicy::new(16, keys(16)).is_ok());
+        }
+
+        #[test]
+        fn address_depends_on_threshold_and_key_order() {
+            let policy = MultisigPolicy::new(1, keys(2)).unwrap();
+            let stricter = MultisigPolicy::new(2, keys(2)).unwrap();
+            let mut reordered = keys(2);
+            reordered.reverse();
+            let reordered = MultisigPolicy::new(1, reordered).unwrap();
+            assert_ne!(policy.address(), stricter.address());
+            assert_ne!(policy.address(), reordered.address());
+        }
+    }
+}
+
+pub mod clock {
+    //! Time source for block timestamps. `Blockchain` reads the time through a `Clock`
+    //! so that tests and simulations can run on a virtual clock.
+
+    use std::fmt;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    pub trait Clock: fmt::Debug + Send + Sync {
+        /// Current unix time in milliseconds.
+        fn now_millis(&self) -> u64;
+    }
+
+    /// The system's wall clock.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct SystemClock;
+
+    impl Clock for SystemClock {
+        fn now_millis(&self) -> u64 {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64)
+        }
+    }
+
+    /// A clock that only moves when told to. Share it through an `Arc` to drive several
+    /// chains from the same virtual time.
+    #[derive(Debug, Default)]
+    pub struct ManualClock {
+        millis: AtomicU64,
+    }
+
+    impl ManualClock {
+        pub fn new(millis: u64) -> Self {
+            ManualClock { millis: AtomicU64::new(millis) }
+        }
+
+        pub fn set(&self, millis: u64) {
+            self.millis.store(millis, Ordering::SeqCst);
+        }
+
+        pub fn advance(&self, millis: u64) {
+            self.millis.fetch_add(millis, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now_millis(&self) -> u64 {
+            self.millis.load(Ordering::SeqCst)
+        }
+    }
+}
+
+pub mod merkle {
+    //! Binary Merkle tree over 32-byte leaves. Leaves and inner nodes are hashed with
+    //! different prefixes, and an odd node at the end of a level is carried up unchanged
+    //! rather than paired with itself.
+
+    use serde::{Serialize, Deserialize};
+    use crate::hash::Hash;
+
+    const LEAF_PREFIX: u8 = 0x00;
+    const NODE_PREFIX: u8 = 0x01;
+
+    /// Sibling hashes needed to recompute the root from one leaf.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct MerkleProof {
+        pub leaf_index: u64,
+        pub leaf_count: u64,
+        pub siblings: Vec<Hash>, // Bottom level first
+    }
+
+    fn hash_leaf(leaf: &Hash) -> Hash {
+        let mut data = Vec::with_capacity(33);
+        data.push(LEAF_PREFIX);
+        data.extend_from_slice(leaf.as_bytes());
+        Hash::digest(&data)
+    }
+
+    fn hash_node(left: &Hash, right: &Hash) -> Hash {
+        let mut data = Vec::with_capacity(65);
+        data.push(NODE_PREFIX);
+        data.extend_from_slice(left.as_bytes());
+        data.extend_from_slice(right.as_bytes());
+        Hash::digest(&data)
+    }
+
+    fn next_level(level: &[Hash]) -> Vec<Hash> {
+        level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => hash_node(left, right),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    /// Root of the tree over `leaves`; `Hash::ZERO` when there are none.
+    pub fn root(leaves: &[Hash]) -> Hash {
+        if leaves.is_empty() {
+            return Hash::ZERO;
+        }
+        let mut level: Vec<Hash> = leaves.iter().map(hash_leaf).collect();
+        while level.len() > 1 {
+            level = next_level(&level);
+        }
+        level[0]
+    }
+
+    pub fn prove(leaves: &[Hash], index: usize) -> Option<MerkleProof> {
+        if index >= leaves.len() {
+            return None;
+        }
+        let mut siblings = Vec::new();
+        let mut level: Vec<Hash> = leaves.iter().map(hash_leaf).collect();
+        let mut position = index;
+        while level.len() > 1 {
+            if let Some(sibling) = level.get(position ^ 1) {
+                siblings.push(*sibling);
+            }
+            level = next_level(&level);
+            position /= 2;
+        }
+        Some(MerkleProof {
+            leaf_index: index as u64,
+            leaf_count: leaves.len() as u64,
+            siblings,
+        })
+    }
+
+    /// Recomputes the root from `leaf` and `proof` and compares it with `root`.
+    pub fn verify(root: &Hash, leaf: &Hash, proof: &MerkleProof) -> bool {
+        if proof.leaf_index >= proof.leaf_count {
+            return false;
+        }
+        let mut siblings = proof.siblings.iter();
+        let mut hash = hash_leaf(leaf);
+        let mut position = proof.leaf_index;
+        let mut width = proof.leaf_count;
+        while width > 1 {
+            let sibling_position = position ^ 1;
+            if sibling_position < width {
+                let Some(sibling) = siblings.next() else {
+                    return false;
+                };
+                hash = if position & 1 == 0 {
+                    hash_node(&hash, sibling)
+                } else {
+                    hash_node(sibling, &hash)
+                };
+            }
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+        siblings.next().is_none() && hash == *root
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn leaves(count: usize) -> Vec<Hash> {
+            (0..count).map(|i| Hash::digest(&[i as u8])).collect()
+        }
+
+        #[test]
+        fn proofs_verify_for_every_leaf() {
+            for count in 1..=9 {
+                let leaves = leaves(count);
+                let root = root(&leaves);
+                for (index, leaf) in leaves.iter().enumerate() {
+                    let proof = prove(&leaves, index).unwrap();
+                    assert!(verify(&root, leaf, &proof), "leaf {} of {}", index, count);
+                    assert!(!verify(&root, &Hash::digest(b"other"), &proof));
+                }
+                assert!(prove(&leaves, count).is_none());
+            }
+        }
+
+        #[test]
+        fn tampered_proofs_fail() {
+            let leaves = leaves(5);
+            let root = root(&leaves);
+            let proof = prove(&leaves, 2).unwrap();
+            let mut moved = proof.clone();
+            moved.leaf_index = 3;
+            assert!(!verify(&root, &leaves[2], &moved));
+            let mut resized = proof.clone();
+            resized.leaf_count = 4;
+            assert!(!verify(&root, &leaves[2], &resized));
+            let mut extended = proof.clone();
+            extended.siblings.push(Hash::ZERO);
+            assert!(!verify(&root, &leaves[2], &extended));
+            let mut out_of_range = proof;
+            out_of_range.leaf_index = 5;
+            assert!(!verify(&root, &leaves[2], &out_of_range));
+        }
+
+        #[test]
+        fn odd_node_is_carried_up() {
+            let leaves = leaves(3);
+            let level = [hash_leaf(&leaves[0]), hash_leaf(&leaves[1]), hash_leaf(&leaves[2])];
+            assert_eq!(root(&leaves), hash_node(&hash_node(&level[0], &level[1]), &level[2]));
+            assert_eq!(root(&leaves[..1]), hash_leaf(&leaves[0]));
+        }
+    }
+}
+
+pub mod sigverify {
+    //! Signature verification helpers: a bounded pool of worker threads for checking a
+    //! block's signatures in parallel, and a cache of signatures already checked.
+
+    use crate::hash::Hash;
+    use std::collections::{HashSet, VecDeque};
+    use std::fmt;
+    use std::sync::Mutex;
+    use std::thread;
+
+    /// Number of verified signatures a `SignatureCache` keeps by default.
+    pub const DEFAULT_SIGNATURE_CACHE_SIZE: usize = 65_536;
+
+    /// Worker threads used by default: one per available core.
+    pub fn default_workers() -> usize {
+        thread::available_parallelism().map_or(1, |cores| cores.get())
+    }
+
+    /// Runs `check` on every job using at most `workers` threads, each taking a contiguous
+    /// run of jobs, and returns the results in job order.
+    pub fn parallel_map<T: Sync, R: Send>(jobs: &[T], workers: usize, check: impl Fn(&T) -> R + Sync) -> Vec<R> {
+        let workers = workers.clamp(1, jobs.len().max(1));
+        if workers == 1 {
+            return jobs.iter().map(check).collect();
+        }
+        let check = &check;
+        thread::scope(|scope| {
+            let handles: Vec<_> = jobs
+                .chunks(jobs.len().div_ceil(workers))
+                .map(|chunk| scope.spawn(move || chunk.iter().map(check).collect::<Vec<R>>()))
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("signature worker panicked"))
+                .collect()
+        })
+    }
+
+    /// Digests of signatures that verified, so that a transaction checked on mempool
+    /// admission is not verified again when a block includes it. Once full, the oldest
+    /// entries are forgotten first.
+    pub struct SignatureCache {
+        capacity: usize,
+        entries: Mutex<CacheEntries>,
+    }
+
+    #[derive(Default)]
+    struct CacheEntries {
+        known: HashSet<Hash>,
+        order: VecDeque<Hash>,
+    }
+
+    impl Default for SignatureCache {
+        fn default() -> Self {
+            Self::new(DEFAULT_SIGNATURE_CACHE_SIZE)
+        }
+    }
+
+    impl SignatureCache {
+        pub fn new(capacity: usize) -> Self {
+            SignatureCache {
+                capacity,
+                entries: Mutex::new(CacheEntries::default()),
+            }
+        }
+
+        pub fn contains(&self, entry: &Hash) -> bool {
+            self.lock().known.contains(entry)
+        }
+
+        pub fn insert(&self, entry: Hash) {
+            if self.capacity == 0 {
+                return;
+            }
+            let mut entries = self.lock();
+            if !entries.known.insert(entry) {
+                return;
+            }
+            entries.order.push_back(entry);
+            while entries.order.len() > self.capacity {
+                if let Some(oldest) = entries.order.pop_front() {
+                    entries.known.remove(&oldest);
+                }
+            }
+        }
+
+        pub fn len(&self) -> usize {
+            self.lock().order.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        pub fn clear(&self) {
+            *self.lock() = CacheEntries::default();
+        }
+
+        fn lock(&self) -> std::sync::MutexGuard<'_, CacheEntries> {
+            // The entries stay consistent even if a holder panicked, so a poisoned lock is fine.
+            self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+        }
+    }
+
+    impl fmt::Debug for SignatureCache {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("SignatureCache")
+                .field("capacity", &self.capacity)
+                .field("len", &self.len())
+                .finish()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn parallel_map_keeps_job_order() {
+            let jobs: Vec<u32> = (0..10).collect();
+            for workers in [1, 3, 4, 10, 64] {
+                assert_eq!(parallel_map(&jobs, workers, |job| job * 2), (0..10).map(|job| job * 2).collect::<Vec<_>>());
+            }
+            assert_eq!(parallel_map(&[] as &[u32], 4, |job| *job), Vec::<u32>::new());
+            assert_eq!(parallel_map(&[7u32], 4, |job| *job), vec![7]);
+            assert_eq!(parallel_map(&[7u32], 0, |job| *job), vec![7]);
+        }
+
+        #[test]
+        fn signature_cache_forgets_the_oldest_entries() {
+            let entry = |n: u8| Hash::digest(&[n]);
+            let cache = SignatureCache::new(2);
+            cache.insert(entry(1));
+            cache.insert(entry(2));
+            cache.insert(entry(2));
+            assert!(cache.contains(&entry(1)) && cache.contains(&entry(2)));
+            assert_eq!(cache.len(), 2);
+
+            cache.insert(entry(3));
+            assert!(!cache.contains(&entry(1)));
+            assert!(cache.contains(&entry(2)) && cache.contains(&entry(3)));
+            assert_eq!(cache.len(), 2);
+
+            let disabled = SignatureCache::new(0);
+            disabled.insert(entry(1));
+            assert!(!disabled.contains(&entry(1)));
+            assert!(disabled.is_empty());
+        }
+    }
+}
+
+pub mod blockchain {
+    use super::*;
+    use serde::{Serialize, Deserialize};
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::fmt;
+    use std::sync::Arc;
+    use crate::crypto::{CryptoError, PublicKey, SecretKey, SignatureScheme};
+    use crate::multisig::{Authority, KeyHistory, KeyRecord, MultisigPolicy, PolicyError};
+    use crate::state::{AccountState, Balances, StateChanges};
+    use crate::fork::{FinalizedFirst, ForkChoice};
+    use crate::receipts::{Receipt, ReceiptAction, ReceiptEntry, ReceiptError, ReceiptRecord, ReceiptStatus};
+    use crate::storage::{StateSnapshot, StorageBackend, StorageError};
+    use crate::genesis::{GenesisConfig, GenesisError};
+    use crate::shard::{Shard, ShardRegistry, LOCK_SHARD, VPP_SHARD};
+    use crate::beacon::{BeaconBlock, BeaconError, ShardHead};
+    use crate::clock::{Clock, SystemClock};
+    use crate::sigverify::{self, SignatureCache};
+    use crate::amount::Amount;
+    use crate::encoding::Encoder;
+    use crate::hash::Hash;
+    use crate::merkle::{self, MerkleProof};
+
+    /// Domain tag that starts every block header encoding.
+    pub const BLOCK_DOMAIN: &[u8] = b"CRAWCHAIN/BLOCK";
+    /// Version of the block header encoding.
+    pub const BLOCK_ENCODING_VERSION: u8 = 6;
+    /// How far, in milliseconds, a received block's timestamp may be ahead of the local
+    /// clock unless `Blockchain::max_clock_drift` says otherwise.
+    pub const DEFAULT_MAX_CLOCK_DRIFT: u64 = 15_000;
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Block {
+        pub index: u64,
+        pub timestamp: u64, // Unix time in milliseconds; strictly increasing along a shard
+        pub transactions: Vec<Transaction>,
+        pub tx_root: Hash, // Merkle root of the transaction ids
+        pub receipts: Vec<ReceiptEntry>, // Cross-shard receipts settled by this block
+        pub receipt_root: Hash, // Merkle root of the receipt entry hashes
+        pub previous_hash: Hash,
+        pub hash: Hash,
+        pub shard_id: Option<u64>,
+        pub proposer: Option<Address>, // Validator that proposed the block; none for genesis blocks
+        #[serde(default)]
+        pub signature: String, // Proposer's signature over `hash`, hex; empty without a proposer
+    }
+
+    /// The hashed part of a `Block`: everything except the transactions and receipts
+    /// themselves, which are committed to through `tx_root` and `receipt_root`.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct BlockHeader {
+        pub index: u64,
+        pub timestamp: u64,
+        pub tx_root: Hash,
+        pub receipt_root: Hash,
+        pub previous_hash: Hash,
+        pub shard_id: Option<u64>,
+        pub proposer: Option<Address>,
+    }
+
+    impl BlockHeader {
+        /// SHA-256 of the canonical header encoding described in `docs/hashing.md`.
+        pub fn calculate_hash(&self) -> Hash {
+            let mut encoder = Encoder::new();
+            encoder
+                .bytes(BLOCK_DOMAIN)
+                .u8(BLOCK_ENCODING_VERSION)
+                .u64(self.index)
+                .u64(self.timestamp)
+                .raw(self.previous_hash.as_bytes())
+                .option(self.shard_id, |e, shard| {
+                    e.u64(shard);
+                })
+                .option(self.proposer.as_ref(), |e, proposer| {
+                    e.raw(proposer.as_bytes());
+                })
+                .raw(self.tx_root.as_bytes())
+                .raw(self.receipt_root.as_bytes());
+            Hash::digest(&encoder.finish())
+        }
+    }
+
+    impl Block {
+        pub fn new(
+            index: u64,
+            timestamp: u64,
+            transactions: Vec<Transaction>,
+            receipts: Vec<ReceiptEntry>,
+            previous_hash: Hash,
+            shard_id: Option<u64>,
+            proposer: Option<Address>,
+        ) -> Self {
+            let tx_root = Self::transactions_root(&transactions);
+            let receipt_root = Self::receipts_root(&receipts);
+            let mut block = Block {
+                index,
+                timestamp,
+                transactions,
+                tx_root,
+                receipts,
+                receipt_root,
+                previous_hash,
+                hash: Hash::ZERO,
+                shard_id,
+                proposer,
+                signature: String::new(),
+            };
+            block.hash = block.calculate_hash();
+            block
+        }
+
+        /// Signs the block hash with `key`, which should be the key of `proposer`.
+        pub fn sign(&mut self, key: &SecretKey) {
+            self.signature = hex::encode(key.sign(self.hash.as_bytes()));
+        }
+
+        /// Checks `signature` over the block hash against the proposer's `key`. The hash
+        /// itself is not recomputed.
+        pub fn verify_signature(&self, key: &PublicKey) -> Result<(), CryptoError> {
+            let signature = hex::decode(&self.signature).map_err(|_| CryptoError::MalformedSignature(key.scheme()))?;
+            key.verify(self.hash.as_bytes(), &signature)
+        }
+
+        pub fn header(&self) -> BlockHeader {
+            BlockHeader {
+                index: self.index,
+                timestamp: self.timestamp,
+                tx_root: self.tx_root,
+                receipt_root: self.receipt_root,
+                previous_hash: self.previous_hash,
+                shard_id: self.shard_id,
+                proposer: self.proposer,
+            }
+        }
+
+        /// Recomputes the header hash from the stored fields. It trusts `tx_root` and
+        /// `receipt_root`; compare those against `transactions_root` and `receipts_root`
+        /// to check the block contents as well.
+        pub fn calculate_hash(&self) -> Hash {
+            self.header().calculate_hash()
+        }
+
+        /// Merkle root over the ids (`Transaction::hash`) of `transactions`, in order.
+        pub fn transactions_root(transactions: &[Transaction]) -> Hash {
+            let leaves: Vec<Hash> = transactions.iter().map(Transaction::hash).collect();
+            merkle::root(&leaves)
+        }
+
+        /// Merkle root over the hashes (`ReceiptEntry::hash`) of `receipts`, in order.
+        pub fn receipts_root(receipts: &[ReceiptEntry]) -> Hash {
+            let leaves: Vec<Hash> = receipts.iter().map(ReceiptEntry::hash).collect();
+            merkle::root(&leaves)
+        }
+
+        /// Proof that the transaction at `tx_index` is committed to by this block's `tx_root`,
+        /// or `None` if there is no such transaction.
+        pub fn prove_inclusion(&self, tx_index: usize) -> Option<MerkleProof> {
+            let leaves: Vec<Hash> = self.transactions.iter().map(Transaction::hash).collect();
+            merkle::prove(&leaves, tx_index)
+        }
+    }
+
+    /// Checks that the transaction with id `tx_hash` is included in the block with `header`,
+    /// using only the header and a proof from `Block::prove_inclusion`.
+    pub fn verify_inclusion(header: &BlockHeader, tx_hash: &Hash, proof: &MerkleProof) -> bool {
+        merkle::verify(&header.tx_root, tx_hash, proof)
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Transaction {
+        pub chain_id: u64,
+        pub shard_id: u64,
+        pub sender: Address,
+        pub receiver: Address,
+        pub token: Token,
+        pub nonce: u64,
+        pub contract_code: Option<Vec<u8>>,
+        pub gas_limit: u64,
+        pub zkp: Option<ZKProof>,
+        #[serde(default)]
+        pub kind: TxKind,
+        #[serde(default)]
+        pub scheme: SignatureScheme, // Scheme of the signature and of the sender's key
+        pub signature: String, // Signature in the scheme's encoding, hex; empty for multisig senders
+        #[serde(default)]
+        pub cosignatures: Vec<Cosignature>, // Signatures of a multisig sender's keys
+    }
+
+    /// A signature over a transaction by one key of its sender's multisig policy.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct Cosignature {
+        pub key: u32, // Position of the key in the policy
+        pub signature: String, // Signature in the key's scheme encoding, hex
+    }
+
+    /// What a transaction does besides advancing the sender's nonce.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+    pub enum TxKind {
+        /// Moves `token` from the sender to the receiver.
+        #[default]
+        Transfer,
+        /// Registers the sender's public key, a key of the transaction's scheme in that
+        /// scheme's encoding. The transaction is signed with that key, which proves
+        /// possession of the private key; it must derive the sender's address, and it moves
+        /// no tokens.
+        RegisterKey { public_key: Vec<u8> },
+        /// Puts the multisig policy controlling the sender on chain. The policy must derive
+        /// the sender's address, the transaction is cosigned under the policy it registers,
+        /// and it moves no tokens.
+        RegisterMultisig { policy: MultisigPolicy },
+        /// Replaces the sender's key or policy with `authority` from the next block on.
+        /// Signed by the authority being replaced; moves no tokens.
+        RotateKey { authority: Authority },
+        /// Sets the authority that may recover the sender, from the next block on. Signed by
+        /// the current recovery authority, or by the sender's authority while none is set.
+        SetRecovery { recovery: Authority },
+        /// Replaces the sender's key or policy with `authority` from the next block on,
+        /// revoking a lost or leaked one. Signed by the sender's recovery authority.
+        Recover { authority: Authority },
+    }
+
+    impl TxKind {
+        /// Tag identifying the kind in canonical encodings.
+        pub fn tag(&self) -> u8 {
+            match self {
+                TxKind::Transfer => 0,
+                TxKind::RegisterKey { .. } => 1,
+                TxKind::RegisterMultisig { .. } => 2,
+                TxKind::RotateKey { .. } => 3,
+                TxKind::SetRecovery { .. } => 4,
+                TxKind::Recover { .. } => 5,
+            }
+        }
+
+        /// Whether the transaction registers the first key or policy of its sender.
+        pub fn is_registration(&self) -> bool {
+            matches!(self, TxKind::RegisterKey { .. } | TxKind::RegisterMultisig { .. })
+        }
+    }
+
+    /// Domain tag that starts every transaction signing payload.
+    pub const TX_DOMAIN: &[u8] = b"CRAWCHAIN/TX";
+    /// Version of the transaction signing payload layout.
+    pub const TX_ENCODING_VERSION: u8 = 4;
+
+    impl Transaction {
+        /// The exact bytes the sender signs: every field except `signature`, preceded by the
+        /// domain tag and layout version. The layout is specified in `docs/signing.md`.
+        pub fn signing_bytes(&self) -> Vec<u8> {
+            let mut encoder = Encoder::new();
+            encoder
+                .bytes(TX_DOMAIN)
+                .u8(TX_ENCODING_VERSION)
+                .u8(self.scheme.tag())
+                .u64(self.chain_id)
+                .u64(self.shard_id)
+                .raw(self.sender.as_bytes())
+                .raw(self.receiver.as_bytes())
+                .u8(self.token.kind().tag())
+                .u64(self.token.amount().base_units())
+                .u64(self.nonce)
+                .u64(self.gas_limit)
+                .option(self.contract_code.as_deref(), |e, code| {
+                    e.bytes(code);
+                })
+                .option(self.zkp.as_ref(), |e, zkp| {
+                    e.bytes(&zkp.public_input).bytes(&zkp.proof);
+                })
+                .u8(self.kind.tag());
+            match &self.kind {
+                TxKind::Transfer => {}
+                TxKind::RegisterKey { public_key } => {
+                    encoder.bytes(public_key);
+                }
+                TxKind::RegisterMultisig { policy } => {
+                    encoder.bytes(&policy.encode());
+                }
+                TxKind::RotateKey { authority } | TxKind::SetRecovery { recovery: authority } | TxKind::Recover { authority } => {
+                    encoder.bytes(&authority.encode());
+                }
+            }
+            encoder.finish()
+        }
+
+        /// Sets `scheme` to the key's scheme and signs the transaction with `key`.
+        pub fn sign(&mut self, key: &SecretKey) {
+            self.scheme = key.scheme();
+            self.signature = hex::encode(key.sign(&self.signing_bytes()));
+        }
+
+        /// Adds the signature of `key`, the key at position `index` of the multisig policy
+        /// that signs for the sender.
+        pub fn cosign(&mut self, index: u32, key: &SecretKey) {
+            let signature = hex::encode(key.sign(&self.signing_bytes()));
+            self.cosignatures.push(Cosignature { key: index, signature });
+        }
+
+        /// Size of the transaction on the wire: the signing payload plus the raw signatures.
+        pub fn size(&self) -> usize {
+            let cosignatures: usize = self.cosignatures.iter().map(|c| 4 + c.signature.len() / 2).sum();
+            self.signing_bytes().len() + self.signature.len() / 2 + cosignatures
+        }
+
+        /// The transaction id: SHA-256 of `signing_bytes`. It does not cover the signature,
+        /// so re-encoding a signature never changes the id.
+        pub fn hash(&self) -> Hash {
+            Hash::digest(&self.signing_bytes())
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub enum Token {
+        CustodyToken(Amount),
+        EnergyToken(Amount),
+    }
+
+    /// The kind of a `Token`, without its amount. Balances are kept per kind.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum TokenKind {
+        Custody,
+        Energy,
+    }
+
+    impl TokenKind {
+        /// Tag identifying the kind in canonical encodings.
+        pub fn tag(self) -> u8 {
+            match self {
+                TokenKind::Custody => 0,
+                TokenKind::Energy => 1,
+            }
+        }
+    }
+
+    impl Token {
+        pub fn kind(&self) -> TokenKind {
+            match self {
+                Token::CustodyToken(_) => TokenKind::Custody,
+                Token::EnergyToken(_) => TokenKind::Energy,
+            }
+        }
+
+        pub fn amount(&self) -> Amount {
+            match self {
+                Token::CustodyToken(amount) | Token::EnergyToken(amount) => *amount,
+            }
+        }
+    }
+
+    impl fmt::Display for Token {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Token::CustodyToken(amount) => write!(f, "CustodyToken({})", amount),
+                Token::EnergyToken(amount) => write!(f, "EnergyToken({})", amount),
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct ZKProof {
+        pub public_input: Vec<u8>,
+        pub proof: Vec<u8>,
+    }
+
+    /// Why a single transaction was rejected.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum TxError {
+        WrongChainId { expected: u64, got: u64 },
+        WrongShard { expected: u64, got: u64 },
+        BadSignatureHex,
+        MalformedSignature(SignatureScheme),
+        UnknownSenderKey(Address),
+        SenderKeyMismatch(Address),
+        SchemeMismatch { expected: SignatureScheme, got: SignatureScheme },
+        InvalidPublicKey(SignatureScheme),
+        InvalidPolicy(PolicyError),
+        MultisigNeedsCosignatures,
+        UnexpectedCosignatures,
+        UnknownCosigner(u32),
+        DuplicateCosigner(u32),
+        BelowThreshold { threshold: u32, got: usize },
+        KeyAlreadyRegistered(Address),
+        NoRecovery(Address),
+        KeyChangePending(Address),
+        TransferInRegistration,
+        InvalidSignature,
+        NonceReuse(u64),
+        NonceGap { expected: u64, got: u64 },
+        NoncesExhausted(Address),
+        ZeroGas,
+        NonPositiveAmount,
+        InsufficientFunds { kind: TokenKind, needed: Amount, available: Amount },
+        BalanceOverflow(Address),
+    }
+
+    impl fmt::Display for TxError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                TxError::WrongChainId { expected, got } => write!(f, "transaction is for chain {}, not {}", got, expected),
+                TxError::WrongShard { expected, got } => write!(f, "transaction is for shard {}, not {}", got, expected),
+                TxError::BadSignatureHex => write!(f, "signature is not valid hex"),
+                TxError::MalformedSignature(scheme) => write!(f, "signature is not a valid {} signature encoding", scheme),
+                TxError::UnknownSenderKey(sender) => write!(f, "no public key or policy registered for sender {}", sender),
+                TxError::SenderKeyMismatch(sender) => write!(f, "key or policy registered for {} derives another address", sender),
+                TxError::SchemeMismatch { expected, got } => {
+                    write!(f, "transaction is signed with {}, but the signing key uses {}", got, expected)
+                }
+                TxError::InvalidPublicKey(scheme) => write!(f, "public key is not a valid {} key", scheme),
+                TxError::InvalidPolicy(error) => write!(f, "invalid multisig policy: {}", error),
+                TxError::MultisigNeedsCosignatures => write!(f, "multisig accounts sign with cosignatures, not a single signature"),
+                TxError::UnexpectedCosignatures => write!(f, "single-key accounts cannot carry cosignatures"),
+                TxError::UnknownCosigner(key) => write!(f, "cosignature by key {}, which the policy does not list", key),
+                TxError::DuplicateCosigner(key) => write!(f, "key {} cosigned more than once", key),
+                TxError::BelowThreshold { threshold, got } => write!(f, "{} cosignatures, the policy needs {}", got, threshold),
+                TxError::KeyAlreadyRegistered(sender) => write!(f, "{} already has a registered key or policy", sender),
+                TxError::NoRecovery(sender) => write!(f, "{} has no recovery authority", sender),
+                TxError::KeyChangePending(sender) => write!(f, "keys of {} already change in this block", sender),
+                TxError::TransferInRegistration => write!(f, "key registrations and changes must not transfer tokens"),
+                TxError::InvalidSignature => write!(f, "signature does not verify"),
+                TxError::NonceReuse(nonce) => write!(f, "nonce {} has already been used", nonce),
+                TxError::NonceGap { expected, got } => write!(f, "expected nonce {}, got {}", expected, got),
+                TxError::NoncesExhausted(sender) => write!(f, "{} has used its last nonce", sender),
+                TxError::ZeroGas => write!(f, "gas limit is zero"),
+                TxError::NonPositiveAmount => write!(f, "token amount is not positive"),
+                TxError::InsufficientFunds { kind, needed, available } => {
+                    write!(f, "insufficient {:?} balance: needed {}, available {}", kind, needed, available)
+                }
+                TxError::BalanceOverflow(address) => write!(f, "balance of {} would overflow", address),
+            }
+        }
+    }
+
+    /// Why `Blockchain::add_block` rejected a block. A rejected block leaves the chain untouched.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum ChainError {
+        InvalidTransaction { index: usize, error: TxError },
+        UnknownShard(u64),
+        InvalidBlock { shard_id: u64, index: u64, reason: BlockError },
+        InvalidBeacon { index: u64, reason: BeaconError },
+        MissingUndo { shard_id: u64, index: u64 },
+        RestoreFailed { shard_id: u64, index: u64, reason: BlockError },
+        Storage(String),
+    }
+
+    impl fmt::Display for ChainError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                ChainError::InvalidTransaction { index, error } => write!(f, "transaction {}: {}", index, error),
+                ChainError::UnknownShard(shard_id) => write!(f, "unknown shard ID {}", shard_id),
+                ChainError::InvalidBlock { shard_id, index, reason } => {
+                    write!(f, "shard {} block {}: {}", shard_id, index, reason)
+                }
+                ChainError::InvalidBeacon { index, reason } => write!(f, "beacon block {}: {}", index, reason),
+                ChainError::MissingUndo { shard_id, index } => {
+                    write!(f, "shard {} block {} has no undo journal and cannot be reverted", shard_id, index)
+                }
+                ChainError::RestoreFailed { shard_id, index, reason } => {
+                    write!(f, "could not restore shard {} block {} after a failed reorg: {}", shard_id, index, reason)
+                }
+                ChainError::Storage(message) => write!(f, "storage error: {}", message),
+            }
+        }
+    }
+
+    /// Why `Blockchain::verify_chain` considers a stored block invalid.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum BlockError {
+        MissingGenesis,
+        UnknownParent(Hash),
+        IndexMismatch { expected: u64, got: u64 },
+        PreviousHashMismatch { expected: Hash, got: Hash },
+        TxRootMismatch { expected: Hash, got: Hash },
+        HashMismatch { expected: Hash, got: Hash },
+        ShardMismatch { expected: u64, got: Option<u64> },
+        ReceiptRootMismatch { expected: Hash, got: Hash },
+        TimestampNotIncreasing { previous: u64, got: u64 },
+        TimestampTooFarAhead { max: u64, got: u64 },
+        MissingProposer,
+        UnknownProposer(Address),
+        InvalidProposerSignature(CryptoError),
+        InvalidTransaction { index: usize, error: TxError },
+        InvalidReceipt { index: usize, error: ReceiptError },
+    }
+
+    impl fmt::Display for BlockError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                BlockError::MissingGenesis => write!(f, "shard has no genesis block"),
+                BlockError::UnknownParent(parent) => write!(f, "parent block {} is unknown", parent),
+                BlockError::IndexMismatch { expected, got } => write!(f, "expected index {}, got {}", expected, got),
+                BlockError::PreviousHashMismatch { expected, got } => {
+                    write!(f, "previous_hash is {}, but the previous block hashes to {}", got, expected)
+                }
+                BlockError::TxRootMismatch { expected, got } => {
+                    write!(f, "tx_root is {}, but the transactions hash to {}", got, expected)
+                }
+                BlockError::HashMismatch { expected, got } => write!(f, "hash is {}, but the header hashes to {}", got, expected),
+                BlockError::ShardMismatch { expected, got } => write!(f, "block belongs to shard {:?}, not {}", got, expected),
+                BlockError::ReceiptRootMismatch { expected, got } => {
+                    write!(f, "receipt_root is {}, but the receipts hash to {}", got, expected)
+                }
+                BlockError::TimestampNotIncreasing { previous, got } => {
+                    write!(f, "timestamp {} is not after the previous block's {}", got, previous)
+                }
+                BlockError::TimestampTooFarAhead { max, got } => {
+                    write!(f, "timestamp {} is more than the allowed drift ahead of the local clock ({})", got, max)
+                }
+                BlockError::MissingProposer => write!(f, "block has no proposer, but the chain has validators"),
+                BlockError::UnknownProposer(proposer) => write!(f, "proposer {} is not a validator", proposer),
+                BlockError::InvalidProposerSignature(e) => write!(f, "proposer signature: {}", e),
+                BlockError::InvalidTransaction { index, error } => write!(f, "transaction {}: {}", index, error),
+                BlockError::InvalidReceipt { index, error } => write!(f, "receipt {}: {}", index, error),
+            }
+        }
+    }
+
+    impl std::error::Error for TxError {}
+    impl std::error::Error for ChainError {}
+    impl std::error::Error for BlockError {}
+
+    /// How a sender's nonces must advance from one transaction to the next.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum NoncePolicy {
+        /// Each nonce must be greater than the sender's last one; gaps are allowed.
+        Increasing,
+        /// Each nonce must be exactly one more than the sender's last one.
+        GapFree,
+    }
+
+    /// A block being assembled for one shard: the transactions accepted so far and the
+    /// nonces and balances they leave behind. Filled through `Blockchain::try_include`.
+    #[derive(Debug)]
+    pub struct BlockDraft {
+        shard_id: u64,
+        transactions: Vec<Transaction>,
+        nonces: HashMap<Address, u64>,
+        changes: StateChanges,
+        authorities: HashMap<Address, KeyHistory>, // Key records added by the draft's transactions
+    }
+
+    impl BlockDraft {
+        pub fn new(shard_id: u64) -> Self {
+            BlockDraft {
+                shard_id,
+                transactions: Vec::new(),
+                nonces: HashMap::new(),
+                changes: StateChanges::default(),
+                authorities: HashMap::new(),
+            }
+        }
+
+        pub fn shard_id(&self) -> u64 {
+            self.shard_id
+        }
+
+        pub fn transactions(&self) -> &[Transaction] {
+            &self.transactions
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.transactions.is_empty()
+        }
+
+        pub fn into_transactions(self) -> Vec<Transaction> {
+            self.transactions
+        }
+    }
+
+    /// What a canonical block changed, so that a reorg can roll it back: the previous
+    /// balances, nonces, receipt records and key histories of everything the block touched.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+    pub struct BlockUndo {
+        accounts: Vec<(Address, Option<Balances>)>,
+        nonces: Vec<(Address, Option<u64>)>,
+        receipts: Vec<(Hash, Option<ReceiptRecord>)>,
+        authorities: Vec<(Address, Option<KeyHistory>)>,
+    }
+
+    /// The effects of a block's contents, computed against the canonical state but not
+    /// yet applied to it.
+    struct BlockEffects {
+        nonces: HashMap<Address, u64>,
+        changes: StateChanges,
+        authorities: HashMap<Address, KeyHistory>,
+        outgoing: Vec<Receipt>,
+    }
+
+    /// Outcome of `Blockchain::batch_verify_signatures` for one block's transactions.
+    #[derive(Debug, Clone, Default)]
+    pub struct SignatureBatch {
+        pub results: Vec<Result<(), TxError>>, // One per transaction, in block order
+        pub authorities: HashMap<Address, KeyHistory>, // Key records added by the transactions that passed
+    }
+
+    impl SignatureBatch {
+        /// The added key records if every transaction passed, or else the first failure.
+        pub fn into_result(self) -> Result<HashMap<Address, KeyHistory>, (usize, TxError)> {
+            for (index, result) in self.results.into_iter().enumerate() {
+                result.map_err(|error| (index, error))?;
+            }
+            Ok(self.authorities)
+        }
+    }
+
+    /// How `Blockchain::import_block` changed a shard's canonical chain. Both lists are
+    /// empty when the imported block did not become part of it.
+    #[derive(Debug, Clone, Default)]
+    pub struct ImportOutcome {
+        pub reverted: Vec<Block>, // Former canonical blocks, highest first
+        pub applied: Vec<Block>, // New canonical blocks, lowest first
+    }
+
+    #[derive(Debug)]
+    pub struct Blockchain {
+        pub chain_id: u64, // Signed into every transaction so signatures don't replay across networks
+        pub genesis_hash: Hash, // Hash of the genesis configuration; the genesis blocks link to it
+        pub shards: ShardRegistry,
+        pub beacon: Vec<BeaconBlock>, // Beacon chain committing the shard heads, genesis first
+        pub validators: HashMap<Address, PublicKey>, // Key each validator signs its blocks with
+        pub stakes: HashMap<Address, Amount>,
+        pub nonces: HashMap<Address, u64>, // Last committed nonce of each sender
+        pub nonce_policy: NoncePolicy,
+        pub authorities: HashMap<Address, KeyHistory>, // Keys and policies of each account that has sent, over time
+        pub state: AccountState,
+        genesis_state: AccountState, // Balances the genesis configuration funds, which `verify_chain` replays from
+        genesis_authorities: HashMap<Address, KeyHistory>, // Keys the genesis configuration registers, which `verify_chain` replays from
+        pub receipts: BTreeMap<Hash, ReceiptRecord>, // Cross-shard receipts by transaction id, until settled in a finalized block
+        pub fork_choice: Box<dyn ForkChoice>,
+        pub clock: Arc<dyn Clock>, // Source of new block timestamps and of the drift check
+        pub max_clock_drift: u64, // Milliseconds a received block may be ahead of `clock`
+        pub verify_workers: usize, // Threads verifying a block's signatures
+        pub signature_cache: SignatureCache, // Signatures already verified
+        undo: HashMap<Hash, BlockUndo>, // Journal of each canonical block not yet finalized
+        storage: Option<Box<dyn StorageBackend>>,
+    }
+
+    impl Default for Blockchain {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Blockchain {
+        /// A chain started from `GenesisConfig::default()`.
+        pub fn new() -> Self {
+            Self::from_genesis(&GenesisConfig::default()).expect("the default genesis is valid")
+        }
+
+        /// A chain started from an otherwise empty genesis with the given chain id.
+        pub fn with_chain_id(chain_id: u64) -> Self {
+            let genesis = GenesisConfig {
+                chain_id,
+                ..GenesisConfig::default()
+            };
+            Self::from_genesis(&genesis).expect("an empty genesis is valid")
+        }
+
+        /// Builds the chain described by `genesis`. The genesis blocks carry the fixed
+        /// genesis time and link to `genesis.hash()`, so every node loading the same
+        /// configuration ends up with identical genesis blocks.
+        pub fn from_genesis(genesis: &GenesisConfig) -> Result<Self, GenesisError> {
+            let genesis_hash = genesis.hash()?;
+            let genesis_time = genesis.genesis_millis()?;
+            let shards = genesis
+                .shards
+                .iter()
+                .enumerate()
+                .map(|(id, info)| {
+                    let id = id as u64;
+                    let genesis_block = Block::new(0, genesis_time, vec![], vec![], genesis_hash, Some(id), None);
+                    Shard::new(id, info.clone(), vec![genesis_block])
+                })
+                .collect();
+            let shards = ShardRegistry::new(shards).expect("the genesis hash requires a shard");
+            let beacon_genesis = BeaconBlock::new(0, genesis_time, Self::heads_of(&shards), genesis_hash);
+
+            let mut chain = Blockchain {
+                chain_id: genesis.chain_id,
+                genesis_hash,
+                shards,
+                beacon: vec![beacon_genesis],
+                validators: HashMap::new(),
+                stakes: HashMap::new(),
+                nonces: HashMap::new(),
+                nonce_policy: genesis.nonce_policy,
+                authorities: HashMap::new(),
+                state: AccountState::default(),
+                genesis_state: AccountState::default(),
+                genesis_authorities: HashMap::new(),
+                receipts: BTreeMap::new(),
+                fork_choice: Box::new(FinalizedFirst),
+                clock: Arc::new(SystemClock),
+                max_clock_drift: DEFAULT_MAX_CLOCK_DRIFT,
+                verify_workers: sigverify::default_workers(),
+                signature_cache: SignatureCache::default(),
+                undo: HashMap::new(),
+                storage: None,
+            };
+            for validator in &genesis.validators {
+                chain.validators.insert(validator.address, validator.public_key()?);
+                chain.stakes.insert(validator.address, validator.stake);
+            }
+            for account in &genesis.accounts {
+                for token in [Token::CustodyToken(account.custody), Token::EnergyToken(account.energy)] {
+                    chain
+                        .state
+                        .credit(&account.address, &token)
+                        .map_err(|_| GenesisError::Invalid(format!("balance of {} overflows", account.address)))?;
+                }
+            }
+            for key in &genesis.public_keys {
+                let record = KeyRecord { since: 0, authority: Authority::Key(key.public_key()?), recovery: None };
+                chain.authorities.insert(key.address, KeyHistory::new(record));
+            }
+            chain.genesis_state = chain.state.clone();
+            chain.genesis_authorities = chain.authorities.clone();
+            Ok(chain)
+        }
+
+        /// Loads the chain kept in `storage`, or starts one from `genesis` there if it is
+        /// empty. A stored chain must have been started from the same genesis.
+        ///
+        /// A shard's canonical chain is the one ending in the head named by the last saved
+        /// state snapshot; the other logged blocks of the shard that branch off above its
+        /// finalized block, including blocks logged after that snapshot, are restored as
+        /// forks. The loaded chain must pass `verify_chain`, its validators must be the
+        /// genesis validators, and the nonces, receipts, key histories and balances
+        /// replayed from the genesis through its blocks must match the snapshot, so balances
+        /// can only change through blocks.
+        pub fn open(mut storage: Box<dyn StorageBackend>, genesis: &GenesisConfig) -> Result<Self, StorageError> {
+            let Some(snapshot) = storage.load_snapshot()? else {
+                let mut chain = Self::from_genesis(genesis).map_err(StorageError::Genesis)?;
+                for shard in chain.shards.iter() {
+                    storage.truncate_blocks(shard.id, 0)?;
+                    for block in &shard.blocks {
+                        storage.append_block(shard.id, block)?;
+                    }
+                }
+                storage.truncate_beacon_blocks(0)?;
+                for block in &chain.beacon {
+                    storage.append_beacon_block(block)?;
+                }
+                storage.save_snapshot(&chain.snapshot())?;
+                chain.storage = Some(storage);
+                return Ok(chain);
+            };
+
+            let expected = genesis.hash().map_err(StorageError::Genesis)?;
+            if snapshot.genesis_hash != expected {
+                return Err(StorageError::GenesisMismatch { stored: snapshot.genesis_hash, expected });
+            }
+            let initial = Self::from_genesis(genesis).map_err(StorageError::Genesis)?;
+            if snapshot.chain_id != initial.chain_id {
+                return Err(StorageError::Corrupt("stored chain id does not match the genesis".to_string()));
+            }
+            if snapshot.nonce_policy != initial.nonce_policy {
+                return Err(StorageError::Corrupt("stored nonce policy does not match the genesis".to_string()));
+            }
+            if snapshot.validators != initial.validators || snapshot.stakes != initial.stakes {
+                return Err(StorageError::Corrupt("stored validators do not match the genesis".to_string()));
+            }
+
+            let mut shards = Vec::new();
+            for (shard_id, info) in genesis.shards.iter().enumerate() {
+                let shard_id = shard_id as u64;
+                let head = snapshot
+                    .heads
+                    .get(&shard_id)
+                    .ok_or_else(|| StorageError::Corrupt(format!("snapshot has no head for shard {}", shard_id)))?;
+                let mut logged: HashMap<Hash, Block> =
+                    storage.load_blocks(shard_id)?.into_iter().map(|block| (block.hash, block)).collect();
+                let mut blocks = Vec::new();
+                let mut next = Some(*head);
+                while let Some(hash) = next {
+                    let block = logged.remove(&hash).ok_or_else(|| {
+                        StorageError::Corrupt(format!("block {} of shard {} is not in the block log", hash, shard_id))
+                    })?;
+                    next = (block.index > 0).then_some(block.previous_hash);
+                    blocks.push(block);
+                }
+                blocks.reverse();
+                let mut shard = Shard::new(shard_id, info.clone(), blocks);
+                shard.forks = logged;
+                shards.push(shard);
+            }
+
+            let mut beacon = storage.load_beacon_blocks()?;
+            let committed = beacon
+                .iter()
+                .position(|block| block.hash == snapshot.beacon_head)
+                .ok_or_else(|| {
+                    StorageError::Corrupt(format!("beacon head {} is not in the beacon log", snapshot.beacon_head))
+                })? + 1;
+            if committed < beacon.len() {
+                storage.truncate_beacon_blocks(committed as u64)?;
+                beacon.truncate(committed);
+            }
+
+            let mut chain = Blockchain {
+                chain_id: initial.chain_id,
+                genesis_hash: snapshot.genesis_hash,
+                shards: ShardRegistry::new(shards).expect("the genesis hash requires a shard"),
+                beacon,
+                validators: snapshot.validators,
+                stakes: snapshot.stakes,
+                nonces: snapshot.nonces,
+                nonce_policy: initial.nonce_policy,
+                authorities: snapshot.authorities,
+                state: snapshot.state,
+                genesis_state: initial.genesis_state,
+                genesis_authorities: initial.genesis_authorities,
+                receipts: snapshot.receipts,
+                fork_choice: Box::new(FinalizedFirst),
+                clock: Arc::new(SystemClock),
+                max_clock_drift: DEFAULT_MAX_CLOCK_DRIFT,
+                verify_workers: sigverify::default_workers(),
+                signature_cache: SignatureCache::default(),
+                undo: snapshot.undo,
+                storage: Some(storage),
+            };
+            let (nonces, receipts, authorities) = chain.replay().map_err(StorageError::InvalidChain)?;
+            if authorities != chain.authorities {
+                return Err(StorageError::Corrupt("stored keys and policies do not match the block log".to_string()));
+            }
+            if nonces != chain.nonces {
+                return Err(StorageError::Corrupt("stored nonces do not match the block log".to_string()));
+            }
+            if receipts != chain.receipts {
+                return Err(StorageError::Corrupt("stored receipts do not match the block log".to_string()));
+            }
+            if chain.replay_balances(chain.genesis_state.clone()).map_err(StorageError::InvalidChain)? != chain.state {
+                return Err(StorageError::Corrupt("stored balances do not match the block log".to_string()));
+            }
+            chain.prune_finalized();
+            if !chain.rebuild_undo().unwrap_or(false) {
+                return Err(StorageError::Corrupt("stored undo journals do not match the block log".to_string()));
+            }
+            Ok(chain)
+        }
+
+        /// Writes a snapshot of the current tables and account state to storage, e.g. after
+        /// registering keys or changing stakes. Does nothing for a chain without storage.
+        pub fn save_state(&mut self) -> Result<(), StorageError> {
+            let snapshot = self.snapshot();
+            match self.storage.as_mut() {
+                Some(storage) => storage.save_snapshot(&snapshot),
+                None => Ok(()),
+            }
+        }
+
+        fn snapshot(&self) -> StateSnapshot {
+            StateSnapshot {
+                chain_id: self.chain_id,
+                genesis_hash: self.genesis_hash,
+                heads: self.shards.iter().map(|shard| (shard.id, shard.head().hash)).collect(),
+                beacon_head: self.beacon_head().hash,
+                validators: self.validators.clone(),
+                stakes: self.stakes.clone(),
+                nonces: self.nonces.clone(),
+                nonce_policy: self.nonce_policy,
+                authorities: self.authorities.clone(),
+                state: self.state.clone(),
+                receipts: self.receipts.clone(),
+                undo: self.undo.clone(),
+            }
+        }
+
+        /// Appends `block` to the shard's log. It only becomes part of the stored canonical
+        /// chain once `save_state` names it, or a descendant, as the shard's head.
+        fn log_block(&mut self, shard_id: u64, block: &Block) -> Result<(), StorageError> {
+            match self.storage.as_mut() {
+                Some(storage) => storage.append_block(shard_id, block),
+                None => Ok(()),
+            }
+        }
+
+        /// Appends `block` to the beacon log and saves a snapshot naming it as the new
+        /// beacon head. If the snapshot fails, the logged block is removed again.
+        fn persist_beacon_block(&mut self, block: &BeaconBlock) -> Result<(), StorageError> {
+            let snapshot = self.snapshot();
+            let Some(storage) = self.storage.as_mut() else {
+                return Ok(());
+            };
+            storage.append_beacon_block(block)?;
+            if let Err(e) = storage.save_snapshot(&snapshot) {
+                let _ = storage.truncate_beacon_blocks(block.index);
+                return Err(e);
+            }
+            Ok(())
+        }
+
+        fn heads_of(shards: &ShardRegistry) -> Vec<ShardHead> {
+            shards
+                .iter()
+                .map(|shard| ShardHead {
+                    shard_id: shard.id,
+                    index: shard.head().index,
+                    hash: shard.head().hash,
+                })
+                .collect()
+        }
+
+        /// The latest beacon block. The beacon chain always has its genesis block.
+        pub fn beacon_head(&self) -> &BeaconBlock {
+            self.beacon.last().expect("the beacon chain always has a genesis block")
+        }
+
+        /// Appends a beacon block committing the current head of every shard.
+        pub fn commit_beacon(&mut self) -> Result<BeaconBlock, ChainError> {
+            let previous = self.beacon_head();
+            let timestamp = self.next_timestamp(previous.timestamp);
+            let block = BeaconBlock::new(previous.index + 1, timestamp, Self::heads_of(&self.shards), previous.hash);
+            self.beacon.push(block.clone());
+            let (undo, receipts) = (self.undo.clone(), self.receipts.clone());
+            self.prune_finalized();
+            if let Err(e) = self.persist_beacon_block(&block) {
+                self.beacon.pop();
+                self.undo = undo;
+                self.receipts = receipts;
+                return Err(ChainError::Storage(e.to_string()));
+            }
+            Ok(block)
+        }
+
+        /// Index of the last block of `shard_id` committed by the beacon chain. Finalized
+        /// blocks are never reverted.
+        pub fn finalized_index(&self, shard_id: u64) -> u64 {
+            self.beacon_head().shard_head(shard_id).map_or(0, |head| head.index)
+        }
+
+        /// Drops the undo journals of finalized blocks, forks that branch off below a
+        /// finalized block, and receipts settled for good, since none of them can matter to
+        /// a reorg any more.
+        fn prune_finalized(&mut self) {
+            let beacon_head = self.beacon.last().expect("the beacon chain always has a genesis block");
+            self.receipts.retain(|_, record| !Self::is_final(record, beacon_head));
+            let finalized: Vec<(u64, u64)> =
+                self.shards.iter().map(|shard| (shard.id, self.finalized_index(shard.id))).collect();
+            for (shard_id, finalized_index) in finalized {
+                let Some(shard) = self.shards.get_mut(shard_id) else {
+                    continue;
+                };
+                for block in &shard.blocks[..=finalized_index as usize] {
+                    self.undo.remove(&block.hash);
+                }
+                shard.prune_forks(finalized_index);
+            }
+        }
+
+        /// Rebuilds the undo journals of the blocks above the finalized ones by rolling those
+        /// blocks back with the current journals and applying them again. Returns whether
+        /// that ends in the state it started from, so that a journal which does not undo its
+        /// block exactly is caught on `open` rather than on the next reorg.
+        fn rebuild_undo(&mut self) -> Result<bool, ChainError> {
+            let before = (self.state.clone(), self.nonces.clone(), self.receipts.clone(), self.authorities.clone());
+            let mut reverted = Vec::new();
+            let shard_ids: Vec<u64> = self.shards.iter().map(|shard| shard.id).collect();
+            for shard_id in shard_ids {
+                let finalized = self.finalized_index(shard_id);
+                while let Some(index) = self.shards.get(shard_id).map(|shard| shard.head().index).filter(|index| *index > finalized) {
+                    let block = self.revert_head(shard_id).ok_or(ChainError::MissingUndo { shard_id, index })?;
+                    reverted.push((shard_id, block));
+                }
+            }
+            self.undo.clear();
+            for (shard_id, block) in reverted.into_iter().rev() {
+                let effects = self
+                    .execute_block(shard_id, &block)
+                    .map_err(|reason| ChainError::InvalidBlock { shard_id, index: block.index, reason })?;
+                self.apply_block(shard_id, block, effects);
+            }
+            Ok(before == (self.state.clone(), self.nonces.clone(), self.receipts.clone(), self.authorities.clone()))
+        }
+
+        /// Height of the first beacon block that commits block `index` of `shard_id`, or
+        /// `None` if the beacon chain has not reached it yet.
+        pub fn committed_height(&self, shard_id: u64, index: u64) -> Option<u64> {
+            let height = self.beacon.partition_point(|block| !block.commits(shard_id, index));
+            (height < self.beacon.len()).then_some(height as u64)
+        }
+
+        /// Headers of `shard_id` from block `from` up to the head committed by the beacon
+        /// block at `beacon_height`; what a light client following the beacon chain needs
+        /// to check the block at `from` with `beacon::verify_shard_headers`.
+        pub fn shard_headers(&self, shard_id: u64, from: u64, beacon_height: u64) -> Option<Vec<BlockHeader>> {
+            let head = self.beacon.get(beacon_height as usize)?.shard_head(shard_id)?;
+            let blocks = self.shards.get(shard_id)?.blocks.get(from as usize..=head.index as usize)?;
+            Some(blocks.iter().map(Block::header).collect())
+        }
+
+        pub fn shard(&self, shard_id: u64) -> Option<&Shard> {
+            self.shards.get(shard_id)
+        }
+
+        /// Blocks of the default lock shard.
+        pub fn lock_shard(&self) -> &[Block] {
+            self.shard(LOCK_SHARD).map_or(&[], |shard| &shard.blocks)
+        }
+
+        /// Blocks of the default VPP shard.
+        pub fn vpp_shard(&self) -> &[Block] {
+            self.shard(VPP_SHARD).map_or(&[], |shard| &shard.blocks)
+        }
+
+        /// The shard that `sender`'s transactions go to.
+        pub fn assign_shard(&self, sender: &Address) -> u64 {
+            self.shards.assign(sender)
+        }
+
+        /// Validates `transactions` and appends them to the given shard as a new block.
+        /// Nothing is recorded unless every transaction passes.
+        ///
+        /// A transfer to a receiver on another shard only debits the sender here and
+        /// leaves a pending receipt. After its transactions, the block settles the receipts
+        /// that are due on this shard: pending receipts addressed to it are credited (or
+        /// rejected if the credit fails), and receipts that originated here are refunded
+        /// once rejected or once `RECEIPT_TIMEOUT_BLOCKS` source blocks have passed. Only
+        /// receipts whose source block the beacon chain has committed are settled.
+        pub fn add_block(&mut self, transactions: Vec<Transaction>, shard_id: u64) -> Result<Block, ChainError> {
+            self.propose_block(transactions, shard_id, None)
+        }
+
+        /// `add_block`, signed by `proposer` and recording its address as the validator that
+        /// proposed the block. A chain with validators only accepts blocks signed by one.
+        pub fn propose_block(
+            &mut self,
+            transactions: Vec<Transaction>,
+            shard_id: u64,
+            proposer: Option<&SecretKey>,
+        ) -> Result<Block, ChainError> {
+            let Some(shard) = self.shards.get(shard_id) else {
+                return Err(ChainError::UnknownShard(shard_id));
+            };
+            let last_block = shard.head();
+            let (index, previous_hash) = (last_block.index + 1, last_block.hash);
+            let timestamp = self.next_timestamp(last_block.timestamp);
+
+            let mut effects = self
+                .execute_transactions(&transactions, shard_id, index)
+                .map_err(|(index, error)| ChainError::InvalidTransaction { index, error })?;
+            let settled = self.settle_receipts(shard_id, index, &mut effects.changes);
+
+            let proposer_address = proposer.map(|key| Address::from_key(&key.public_key()));
+            let mut new_block =
+                Block::new(index, timestamp, transactions, settled, previous_hash, Some(shard_id), proposer_address);
+            if let Some(key) = proposer {
+                new_block.sign(key);
+            }
+            self.check_proposer(&new_block, false)
+                .map_err(|reason| ChainError::InvalidBlock { shard_id, index, reason })?;
+            self.apply_block(shard_id, new_block.clone(), effects);
+
+            if let Err(e) = self.log_block(shard_id, &new_block).and_then(|_| self.save_state()) {
+                self.revert_head(shard_id);
+                return Err(ChainError::Storage(e.to_string()));
+            }
+            Ok(new_block)
+        }
+
+        /// Timestamp for a block following one stamped `previous`: the clock's time, or
+        /// one millisecond after `previous` if the clock is behind it.
+        fn next_timestamp(&self, previous: u64) -> u64 {
+            self.clock.now_millis().max(previous + 1)
+        }
+
+        /// Adds a block received from another validator to the shard's block tree, then
+        /// lets the fork choice rule pick the shard's head. If the head moves to another
+        /// branch, the canonical blocks above the common ancestor are rolled back and the
+        /// new branch is validated and applied block by block. A branch with an invalid
+        /// block is dropped and the previous head restored. A block stamped more than
+        /// `max_clock_drift` ahead of the local clock is refused and can be imported again
+        /// once the clock has caught up.
+        ///
+        /// Branches that would revert a finalized block are never chosen. Forks are logged
+        /// to storage but only restored, not re-chosen, when the chain is opened again.
+        pub fn import_block(&mut self, shard_id: u64, block: Block) -> Result<ImportOutcome, ChainError> {
+            let Some(shard) = self.shards.get(shard_id) else {
+                return Err(ChainError::UnknownShard(shard_id));
+            };
+            if shard.contains(&block.hash) {
+                return Ok(ImportOutcome::default());
+            }
+            let invalid = |reason: BlockError| ChainError::InvalidBlock { shard_id, index: block.index, reason };
+            let parent = shard
+                .block(&block.previous_hash)
+                .ok_or_else(|| invalid(BlockError::UnknownParent(block.previous_hash)))?;
+            self.check_block_header(&block, shard_id, Some(parent)).map_err(invalid)?;
+            let max = self.clock.now_millis().saturating_add(self.max_clock_drift);
+            if block.timestamp > max {
+                return Err(invalid(BlockError::TimestampTooFarAhead { max, got: block.timestamp }));
+            }
+
+            self.log_block(shard_id, &block)
+                .map_err(|e| ChainError::Storage(e.to_string()))?;
+            if let Some(shard) = self.shards.get_mut(shard_id) {
+                shard.forks.insert(block.hash, block);
+            }
+            match self.choose_head(shard_id) {
+                Some(tip) => self.switch_head(shard_id, tip),
+                None => Ok(ImportOutcome::default()),
+            }
+        }
+
+        /// The fork tip the fork choice rule prefers over the current head of `shard_id`,
+        /// if any. Ties keep the current head.
+        fn choose_head(&self, shard_id: u64) -> Option<Hash> {
+            let shard = self.shards.get(shard_id)?;
+            let finalized = self.finalized_index(shard_id);
+            let current: Vec<&Block> = shard.blocks.iter().collect();
+            let mut best_score = self.fork_choice.score(&current, &self.stakes);
+            let mut best = None;
+            for tip in shard.fork_tips() {
+                let Some(branch) = shard.branch(&tip) else {
+                    continue;
+                };
+                let fork_point = branch.iter().position(|block| !shard.is_canonical(block)).unwrap_or(branch.len());
+                if (fork_point as u64) <= finalized {
+                    continue;
+                }
+                let score = self.fork_choice.score(&branch, &self.stakes);
+                if score > best_score {
+                    best_score = score;
+                    best = Some(tip);
+                }
+            }
+            best
+        }
+
+        /// Makes the branch ending in the fork block `tip` canonical. See `import_block`.
+        fn switch_head(&mut self, shard_id: u64, tip: Hash) -> Result<ImportOutcome, ChainError> {
+            let shard = self.shards.get(shard_id).ok_or(ChainError::UnknownShard(shard_id))?;
+            let branch: Vec<Block> = shard
+                .branch(&tip)
+                .ok_or(ChainError::UnknownShard(shard_id))?
+                .into_iter()
+                .filter(|block| !shard.is_canonical(block))
+                .cloned()
+                .collect();
+            let ancestor = branch.first().map_or(0, |block| block.index - 1);
+            // Every block to revert needs its undo journal; check them all before changing
+            // anything, e.g. after `open` found journals pruned above the committed beacon.
+            let missing = shard.blocks.iter().find(|block| block.index > ancestor && !self.undo.contains_key(&block.hash));
+            if let Some(block) = missing {
+                return Err(ChainError::MissingUndo { shard_id, index: block.index });
+            }
+
+            let mut outcome = ImportOutcome::default();
+            while let Some(head) = self.shards.get(shard_id).map(|shard| shard.head().index).filter(|index| *index > ancestor) {
+                let Some(block) = self.revert_head(shard_id) else {
+                    return Err(ChainError::MissingUndo { shard_id, index: head });
+                };
+                if let Some(shard) = self.shards.get_mut(shard_id) {
+                    shard.forks.insert(block.hash, block.clone());
+                }
+                outcome.reverted.push(block);
+            }
+            let mut failure = None;
+            for block in branch {
+                if let Some(shard) = self.shards.get_mut(shard_id) {
+                    shard.forks.remove(&block.hash);
+                }
+                match self.execute_block(shard_id, &block) {
+                    Ok(effects) => {
+                        self.apply_block(shard_id, block.clone(), effects);
+                        outcome.applied.push(block);
+                    }
+                    Err(reason) => {
+                        failure = Some(ChainError::InvalidBlock { shard_id, index: block.index, reason });
+                        break;
+                    }
+                }
+            }
+
+            let result = match failure {
+                None => self.save_state().map_err(|e| ChainError::Storage(e.to_string())),
+                Some(error) => Err(error),
+            };
+            if let Err(error) = result {
+                // Put the old branch back; it was valid on exactly this state before.
+                for _ in 0..outcome.applied.len() {
+                    if let Some(block) = self.revert_head(shard_id) {
+                        if let Some(shard) = self.shards.get_mut(shard_id) {
+                            shard.forks.insert(block.hash, block);
+                        }
+                    }
+                }
+                for block in outcome.reverted.into_iter().rev() {
+                    if let Some(shard) = self.shards.get_mut(shard_id) {
+                        shard.forks.remove(&block.hash);
+                    }
+                    let effects = self
+                        .execute_block(shard_id, &block)
+                        .map_err(|reason| ChainError::RestoreFailed { shard_id, index: block.index, reason })?;
+                    self.apply_block(shard_id, block, effects);
+                }
+                // The block that failed was taken out of the forks; its descendants go too.
+                if let Some(shard) = self.shards.get_mut(shard_id) {
+                    shard.prune_orphans();
+                }
+                return Err(error);
+            }
+            Ok(outcome)
+        }
+
+        /// Validates the transactions and receipt entries of `block` on top of the current
+        /// head of `shard_id`, which must be its parent.
+        fn execute_block(&self, shard_id: u64, block: &Block) -> Result<BlockEffects, BlockError> {
+            let mut effects = self
+                .execute_transactions(&block.transactions, shard_id, block.index)
+                .map_err(|(index, error)| BlockError::InvalidTransaction { index, error })?;
+            let changes = &mut effects.changes;
+
+            let mut settled = HashSet::new();
+            for (index, entry) in block.receipts.iter().enumerate() {
+                let invalid = |error| BlockError::InvalidReceipt { index, error };
+                self.check_receipt_entry(entry, shard_id, block.index).map_err(invalid)?;
+                let receipt = &entry.receipt;
+                if !settled.insert(receipt.tx_hash) {
+                    return Err(invalid(ReceiptError::AlreadySettled));
+                }
+                let record = self.receipts.get(&receipt.tx_hash).ok_or(invalid(ReceiptError::NotDue))?;
+                let committed = matches!(self.settlement_height(record), Some(height) if height <= entry.beacon_height);
+                if !committed {
+                    return Err(invalid(ReceiptError::NotCommitted));
+                }
+                let credited = match (Self::due_action(record, shard_id, block.index), entry.action) {
+                    (Some(ReceiptAction::Credit), ReceiptAction::Credit) => {
+                        changes.credit(&self.state, &receipt.receiver, &receipt.token).is_ok()
+                    }
+                    (Some(ReceiptAction::Credit), ReceiptAction::Reject) => {
+                        changes.credit(&self.state, &receipt.receiver, &receipt.token).is_err()
+                    }
+                    (Some(ReceiptAction::Refund), ReceiptAction::Refund) => {
+                        changes.credit(&self.state, &receipt.sender, &receipt.token).is_ok()
+                    }
+                    _ => false,
+                };
+                if !credited {
+                    return Err(invalid(ReceiptError::NotDue));
+                }
+            }
+            Ok(effects)
+        }
+
+        /// Signature, nonce and field checks for `transactions`, to be included in block
+        /// `index` on top of the canonical state, and what they change: balances, nonces,
+        /// key records and the receipts they leave behind.
+        fn execute_transactions(
+            &self,
+            transactions: &[Transaction],
+            shard_id: u64,
+            index: u64,
+        ) -> Result<BlockEffects, (usize, TxError)> {
+            let authorities = self
+                .batch_verify_signatures(transactions, index, |address| self.key_record(address, index))
+                .into_result()?;
+            let nonces = self.validate_block_transactions(transactions, shard_id, &self.nonces)?;
+            let mut changes = StateChanges::default();
+            for (tx_index, tx) in transactions.iter().enumerate() {
+                self.apply_transfer(&self.state, &mut changes, tx).map_err(|error| (tx_index, error))?;
+            }
+            let outgoing = transactions
+                .iter()
+                .filter_map(|tx| self.outgoing_receipt(tx, index))
+                .collect();
+            Ok(BlockEffects { nonces, changes, authorities, outgoing })
+        }
+
+        /// Applies `effects` and appends `block` to the canonical chain of `shard_id`,
+        /// keeping an undo journal for it.
+        fn apply_block(&mut self, shard_id: u64, block: Block, effects: BlockEffects) {
+            let mut undo = BlockUndo::default();
+            for address in effects.changes.addresses() {
+                undo.accounts.push((*address, self.state.account(address)));
+            }
+            for address in effects.nonces.keys() {
+                undo.nonces.push((*address, self.nonces.get(address).copied()));
+            }
+            for address in effects.authorities.keys() {
+                undo.authorities.push((*address, self.authorities.get(address).cloned()));
+            }
+            let touched = effects
+                .outgoing
+                .iter()
+                .map(|receipt| receipt.tx_hash)
+                .chain(block.receipts.iter().map(|entry| entry.receipt.tx_hash));
+            for hash in touched {
+                undo.receipts.push((hash, self.receipts.get(&hash).cloned()));
+            }
+
+            self.nonces.extend(effects.nonces);
+            self.state.apply(effects.changes);
+            for (address, records) in effects.authorities {
+                self.authorities.entry(address).or_default().extend(records);
+            }
+            Self::record_receipts(&mut self.receipts, effects.outgoing, &block.receipts, block.index);
+            self.undo.insert(block.hash, undo);
+            self.shards.push_block(shard_id, block);
+        }
+
+        /// Removes the head of `shard_id` from the canonical chain and rolls back its
+        /// effects. Does nothing for finalized blocks, which have no undo journal.
+        fn revert_head(&mut self, shard_id: u64) -> Option<Block> {
+            let head = self.shards.get(shard_id)?.head().hash;
+            let undo = self.undo.remove(&head)?;
+            for (address, balances) in undo.accounts.into_iter().rev() {
+                self.state.restore(&address, balances);
+            }
+            for (address, nonce) in undo.nonces.into_iter().rev() {
+                match nonce {
+                    Some(nonce) => self.nonces.insert(address, nonce),
+                    None => self.nonces.remove(&address),
+                };
+            }
+            for (hash, record) in undo.receipts.into_iter().rev() {
+                match record {
+                    Some(record) => self.receipts.insert(hash, record),
+                    None => self.receipts.remove(&hash),
+                };
+            }
+            for (address, history) in undo.authorities.into_iter().rev() {
+                match history {
+                    Some(history) => self.authorities.insert(address, history),
+                    None => self.authorities.remove(&address),
+                };
+            }
+            self.shards.pop_block(shard_id)
+        }
+
+        /// Adds `transaction` to `draft` if it is valid on top of the transactions already
+        /// in the draft; otherwise the draft is left as it was.
+        pub fn try_include(&self, draft: &mut BlockDraft, transaction: Transaction) -> Result<(), TxError> {
+            let sender = transaction.sender;
+            let index = self.next_index(draft.shard_id);
+            let added = draft.authorities.get(&sender);
+            let known = added.and_then(|history| history.at(index)).or_else(|| self.key_record(&sender, index));
+            let record = Self::verify_signature(&self.signature_cache, &transaction, known, index)?;
+            if record.as_ref().is_some_and(|record| record.since > index) && Self::key_change_pending(added, index) {
+                return Err(TxError::KeyChangePending(sender));
+            }
+            self.validate_nonce(&transaction, &self.nonces, &draft.nonces)?;
+            self.validate_transaction(&transaction, draft.shard_id)?;
+            self.apply_transfer(&self.state, &mut draft.changes, &transaction)?;
+            draft.nonces.insert(sender, transaction.nonce);
+            if let Some(record) = record {
+                draft.authorities.entry(sender).or_default().push(record);
+            }
+            draft.transactions.push(transaction);
+            Ok(())
+        }
+
+        /// Whether the next block of `shard_id` would settle any receipts, so that it is
+        /// worth producing even without transactions.
+        pub fn has_due_receipts(&self, shard_id: u64) -> bool {
+            let Some(shard) = self.shards.get(shard_id) else {
+                return false;
+            };
+            !self.settle_receipts(shard_id, shard.height() + 1, &mut StateChanges::default()).is_empty()
+        }
+
+        /// The receipt a transfer committed in block `source_index` of its shard leaves
+        /// behind, or `None` if the receiver lives on the same shard.
+        pub fn outgoing_receipt(&self, transaction: &Transaction, source_index: u64) -> Option<Receipt> {
+            let destination = self.assign_shard(&transaction.receiver);
+            (destination != transaction.shard_id).then(|| Receipt::new(transaction, source_index, destination))
+        }
+
+        /// Proof that the transaction behind `receipt` is included in its source block.
+        pub fn prove_receipt(&self, receipt: &Receipt) -> Option<MerkleProof> {
+            let block = self.shards.get(receipt.source_shard)?.blocks.get(receipt.source_index as usize)?;
+            let position = block.transactions.iter().position(|tx| tx.hash() == receipt.tx_hash)?;
+            block.prove_inclusion(position)
+        }
+
+        /// Debits the sender and, unless the receiver lives on another shard, credits the
+        /// receiver, recording the new balances over `base` in `changes`. Cross-shard
+        /// receivers are credited later through a receipt.
+        fn apply_transfer(
+            &self,
+            base: &AccountState,
+            changes: &mut StateChanges,
+            transaction: &Transaction,
+        ) -> Result<(), TxError> {
+            if transaction.kind != TxKind::Transfer {
+                return Ok(());
+            }
+            if self.assign_shard(&transaction.receiver) == transaction.shard_id {
+                changes.transfer(base, &transaction.sender, &transaction.receiver, &transaction.token)
+            } else {
+                changes.debit(base, &transaction.sender, &transaction.token)
+            }
+        }
+
+        /// What the block at `index` of `shard_id` has to do with `record`, if anything.
+        /// `Credit` stands for either a credit or a rejection, depending on the outcome.
+        fn due_action(record: &ReceiptRecord, shard_id: u64, index: u64) -> Option<ReceiptAction> {
+            let receipt = &record.receipt;
+            match record.status {
+                ReceiptStatus::Pending if receipt.destination_shard == shard_id => Some(ReceiptAction::Credit),
+                ReceiptStatus::Pending if receipt.source_shard == shard_id && index > receipt.deadline() => {
+                    Some(ReceiptAction::Refund)
+                }
+                ReceiptStatus::Rejected if receipt.source_shard == shard_id => Some(ReceiptAction::Refund),
+                _ => None,
+            }
+        }
+
+        /// Whether `record` was credited or refunded by a block that `beacon` commits, so
+        /// that nothing can settle or revert it any more.
+        fn is_final(record: &ReceiptRecord, beacon: &BeaconBlock) -> bool {
+            let shard_id = match record.status {
+                ReceiptStatus::Credited => record.receipt.destination_shard,
+                ReceiptStatus::Refunded => record.receipt.source_shard,
+                ReceiptStatus::Pending | ReceiptStatus::Rejected => return false,
+            };
+            record.settled_at.is_some_and(|index| beacon.commits(shard_id, index))
+        }
+
+        /// The first beacon height at which `record` may be settled: once its source block
+        /// is committed, or for a rejected receipt, once the rejecting block is. Requiring
+        /// the rejection to be final keeps a reorg of the destination shard from undoing a
+        /// rejection that the source shard already refunded.
+        fn settlement_height(&self, record: &ReceiptRecord) -> Option<u64> {
+            let receipt = &record.receipt;
+            match record.status {
+                ReceiptStatus::Rejected => record
+                    .settled_at
+                    .and_then(|index| self.committed_height(receipt.destination_shard, index)),
+                _ => self.committed_height(receipt.source_shard, receipt.source_index),
+            }
+        }
+
+        /// Applies the receipts due in block `index` of `shard_id` to `changes` and returns
+        /// the entries to record in the block. A refund that cannot be credited yet is left
+        /// for a later block.
+        fn settle_receipts(&self, shard_id: u64, index: u64, changes: &mut StateChanges) -> Vec<ReceiptEntry> {
+            let mut entries = Vec::new();
+            for record in self.receipts.values() {
+                let Some(due) = Self::due_action(record, shard_id, index) else {
+                    continue;
+                };
+                let receipt = &record.receipt;
+                let Some(beacon_height) = self.settlement_height(record) else {
+                    continue;
+                };
+                let Some(proof) = self.prove_receipt(receipt) else {
+                    continue;
+                };
+                let action = match due {
+                    ReceiptAction::Credit => match changes.credit(&self.state, &receipt.receiver, &receipt.token) {
+                        Ok(()) => ReceiptAction::Credit,
+                        Err(_) => ReceiptAction::Reject,
+                    },
+                    _ => match changes.credit(&self.state, &receipt.sender, &receipt.token) {
+                        Ok(()) => ReceiptAction::Refund,
+                        Err(_) => continue,
+                    },
+                };
+                entries.push(ReceiptEntry {
+                    receipt: receipt.clone(),
+                    action,
+                    beacon_height,
+                    proof,
+                });
+            }
+            entries
+        }
+
+        /// Adds the receipts emitted by the block at `index` and moves the ones it settled on.
+        fn record_receipts(
+            receipts: &mut BTreeMap<Hash, ReceiptRecord>,
+            outgoing: Vec<Receipt>,
+            settled: &[ReceiptEntry],
+            index: u64,
+        ) {
+            for receipt in outgoing {
+                receipts.insert(receipt.tx_hash, ReceiptRecord { receipt, status: ReceiptStatus::Pending, settled_at: None });
+            }
+            for entry in settled {
+                if let Some(record) = receipts.get_mut(&entry.receipt.tx_hash) {
+                    record.status = entry.action.status();
+                    record.settled_at = Some(index);
+                }
+            }
+        }
+
+        /// Checks a receipt entry recorded in block `index` of `shard_id`: the shard is the
+        /// one the action belongs on, the referenced beacon block commits the source block
+        /// but not yet the settling one, the proof ties the receipt to a transaction in its
+        /// source block, and the receipt matches that transaction.
+        fn check_receipt_entry(&self, entry: &ReceiptEntry, shard_id: u64, index: u64) -> Result<(), ReceiptError> {
+            let receipt = &entry.receipt;
+            let expected_shard = match entry.action {
+                ReceiptAction::Credit | ReceiptAction::Reject => receipt.destination_shard,
+                ReceiptAction::Refund => receipt.source_shard,
+            };
+            if shard_id != expected_shard {
+                return Err(ReceiptError::WrongShard { expected: expected_shard, got: shard_id });
+            }
+            let committed = self
+                .beacon
+                .get(entry.beacon_height as usize)
+                .is_some_and(|beacon| {
+                    beacon.commits(receipt.source_shard, receipt.source_index) && !beacon.commits(shard_id, index)
+                });
+            if !committed {
+                return Err(ReceiptError::NotCommitted);
+            }
+            let source = self
+                .shards
+                .get(receipt.source_shard)
+                .and_then(|shard| shard.blocks.get(receipt.source_index as usize))
+                .ok_or(ReceiptError::UnknownSource)?;
+            if !receipt.verify(&source.header(), &entry.proof) {
+                return Err(ReceiptError::InvalidProof);
+            }
+            let transaction = source
+                .transactions
+                .get(entry.proof.leaf_index as usize)
+                .ok_or(ReceiptError::InvalidProof)?;
+            if self.outgoing_receipt(transaction, receipt.source_index).as_ref() != Some(receipt) {
+                return Err(ReceiptError::Mismatch);
+            }
+            Ok(())
+        }
+
+        /// Current balance of `address` in tokens of `kind`.
+        pub fn balance(&self, address: &Address, kind: TokenKind) -> Amount {
+            self.state.balance(address, kind)
+        }
+
+        /// The public key that signs for `address` in the next block of its shard, from the
+        /// genesis or a key registration or rotation.
+        pub fn public_key(&self, address: &Address) -> Option<&PublicKey> {
+            match self.authority(address) {
+                Some(Authority::Key(key)) => Some(key),
+                _ => None,
+            }
+        }
+
+        /// The key or multisig policy that signs for `address` in the next block of its shard.
+        pub fn authority(&self, address: &Address) -> Option<&Authority> {
+            let index = self.next_index(self.assign_shard(address));
+            self.key_record(address, index).map(|record| &record.authority)
+        }
+
+        /// Every key or policy `address` has had, with the heights they took effect at.
+        pub fn key_history(&self, address: &Address) -> Option<&KeyHistory> {
+            self.authorities.get(address)
+        }
+
+        /// The key record of `address` in effect at block `index` of its shard.
+        fn key_record(&self, address: &Address, index: u64) -> Option<&KeyRecord> {
+            self.authorities.get(address).and_then(|history| history.at(index))
+        }
+
+        /// Whether `history`, the key records a block adds for one account, already changes
+        /// the account's keys after block `index`.
+        fn key_change_pending(history: Option<&KeyHistory>, index: u64) -> bool {
+            history.and_then(KeyHistory::latest).is_some_and(|record| record.since > index)
+        }
+
+        /// Index of the next block of `shard_id`.
+        fn next_index(&self, shard_id: u64) -> u64 {
+            self.shards.get(shard_id).map_or(0, |shard| shard.height() + 1)
+        }
+
+        /// The lowest nonce `address` may use in its next transaction, or `None` once it has
+        /// used nonce `u64::MAX` and can send nothing more.
+        pub fn next_nonce(&self, address: &Address) -> Option<u64> {
+            self.nonces.get(address).map_or(Some(0), |last| last.checked_add(1))
+        }
+
+        /// Checks a transaction on its own, before it is placed in a block: signature,
+        /// chain id, shard, fields, that its nonce has not been used yet, and that the
+        /// sender's current balance covers a transfer.
+        pub fn check_transaction(&self, transaction: &Transaction) -> Result<(), TxError> {
+            let index = self.next_index(transaction.shard_id);
+            Self::verify_signature(&self.signature_cache, transaction, self.key_record(&transaction.sender, index), index)?;
+            self.validate_transaction(transaction, transaction.shard_id)?;
+            let next = self.next_nonce(&transaction.sender).ok_or(TxError::NoncesExhausted(transaction.sender))?;
+            if transaction.nonce < next {
+                return Err(TxError::NonceReuse(transaction.nonce));
+            }
+            if transaction.kind == TxKind::Transfer {
+                StateChanges::default().debit(&self.state, &transaction.sender, &transaction.token)?;
+            }
+            Ok(())
+        }
+
+        /// Checks the beacon chain with `verify_beacon`, then every shard from its genesis
+        /// block: index continuity, `previous_hash`
+        /// links, `tx_root` and `receipt_root`, the recomputed block hash, shard ids, each
+        /// transaction's signature, nonce and fields, and each settled receipt's proof.
+        /// Every receipt must be credited or rejected at most once, and refunded at most
+        /// once, only after a rejection or its timeout, and never after a credit. Returns
+        /// the first invalid block found.
+        ///
+        /// Nonces are replayed per shard, which is sound because a sender only transacts
+        /// on its own shard. Balances are replayed from the genesis balances with
+        /// `replay_balances`, so a block spending more than its sender held does not verify.
+        pub fn verify_chain(&self) -> Result<(), ChainError> {
+            self.replay()?;
+            self.replay_balances(self.genesis_state.clone()).map(|_| ())
+        }
+
+        /// Re-executes the transfers and receipt settlements of every canonical block on top
+        /// of the genesis balances `state`. An account's balance only changes in blocks of
+        /// its own shard, so the shards are replayed one after the other.
+        fn replay_balances(&self, mut state: AccountState) -> Result<AccountState, ChainError> {
+            for shard in self.shards.iter() {
+                let shard_id = shard.id;
+                for block in &shard.blocks {
+                    let invalid = |reason| ChainError::InvalidBlock { shard_id, index: block.index, reason };
+                    let mut changes = StateChanges::default();
+                    for (index, transaction) in block.transactions.iter().enumerate() {
+                        self.apply_transfer(&state, &mut changes, transaction)
+                            .map_err(|error| invalid(BlockError::InvalidTransaction { index, error }))?;
+                    }
+                    for (index, entry) in block.receipts.iter().enumerate() {
+                        let receipt = &entry.receipt;
+                        let credited = match entry.action {
+                            ReceiptAction::Credit => changes.credit(&state, &receipt.receiver, &receipt.token).is_ok(),
+                            ReceiptAction::Reject => changes.credit(&state, &receipt.receiver, &receipt.token).is_err(),
+                            ReceiptAction::Refund => changes.credit(&state, &receipt.sender, &receipt.token).is_ok(),
+                        };
+                        if !credited {
+                            return Err(invalid(BlockError::InvalidReceipt { index, error: ReceiptError::NotDue }));
+                        }
+                    }
+                    state.apply(changes);
+                }
+            }
+            Ok(state)
+        }
+
+        /// The checks behind `verify_chain`, returning the nonces, receipts and key histories
+        /// the replay ends with.
+        #[allow(clippy::type_complexity)]
+        fn replay(
+            &self,
+        ) -> Result<(HashMap<Address, u64>, BTreeMap<Hash, ReceiptRecord>, HashMap<Address, KeyHistory>), ChainError> {
+            self.verify_beacon()?;
+            let mut all_nonces = HashMap::new();
+            let mut all_authorities = HashMap::new();
+            let mut receipts = BTreeMap::new();
+            let mut settlements = Vec::new();
+            for shard in self.shards.iter() {
+                let (shard_id, blocks) = (shard.id, &shard.blocks);
+                let invalid = |index: u64, reason: BlockError| ChainError::InvalidBlock { shard_id, index, reason };
+
+                if blocks.is_empty() {
+                    return Err(invalid(0, BlockError::MissingGenesis));
+                }
+                // Only the keys of the genesis configuration are taken as given; every later
+                // record is rebuilt from the blocks, so each block is checked against the keys
+                // that were in effect at its height, whatever the live tables hold.
+                let mut authorities: HashMap<Address, KeyHistory> = self
+                    .genesis_authorities
+                    .iter()
+                    .filter(|(address, _)| self.assign_shard(address) == shard_id)
+                    .map(|(address, history)| (*address, history.clone()))
+                    .collect();
+                let mut nonces = HashMap::new();
+                let mut previous: Option<&Block> = None;
+                for block in blocks {
+                    self.check_block_header(block, shard_id, previous)
+                        .map_err(|reason| invalid(block.index, reason))?;
+
+                    let known = |address: &Address| authorities.get(address).and_then(|history| history.at(block.index));
+                    let (block_authorities, block_nonces) = self
+                        .batch_verify_signatures(&block.transactions, block.index, known)
+                        .into_result()
+                        .and_then(|block_authorities| {
+                            let block_nonces = self.validate_block_transactions(&block.transactions, shard_id, &nonces)?;
+                            Ok((block_authorities, block_nonces))
+                        })
+                        .map_err(|(index, error)| invalid(block.index, BlockError::InvalidTransaction { index, error }))?;
+                    for (address, records) in block_authorities {
+                        authorities.entry(address).or_default().extend(records);
+                    }
+                    nonces.extend(block_nonces);
+                    let outgoing = block
+                        .transactions
+                        .iter()
+                        .filter_map(|tx| self.outgoing_receipt(tx, block.index));
+                    Self::record_receipts(&mut receipts, outgoing.collect(), &[], block.index);
+
+                    for (index, entry) in block.receipts.iter().enumerate() {
+                        self.check_receipt_entry(entry, shard_id, block.index)
+                            .map_err(|error| invalid(block.index, BlockError::InvalidReceipt { index, error }))?;
+                        settlements.push((shard_id, block.index, index, entry));
+                    }
+                    previous = Some(block);
+                }
+                all_nonces.extend(nonces);
+                all_authorities.extend(authorities);
+            }
+
+            // Settlements on different shards are not ordered relative to each other, so
+            // check credits and rejections first, then the refunds that depend on them.
+            let invalid_receipt = |(shard_id, block_index, index, _): &(u64, u64, usize, &ReceiptEntry), error| {
+                let reason = BlockError::InvalidReceipt { index: *index, error };
+                ChainError::InvalidBlock { shard_id: *shard_id, index: *block_index, reason }
+            };
+            let mut outcomes: HashMap<Hash, (ReceiptAction, u64)> = HashMap::new();
+            for settlement in settlements.iter().filter(|(.., entry)| entry.action != ReceiptAction::Refund) {
+                let (_, block_index, _, entry) = *settlement;
+                if outcomes.insert(entry.receipt.tx_hash, (entry.action, block_index)).is_some() {
+                    return Err(invalid_receipt(settlement, ReceiptError::AlreadySettled));
+                }
+                Self::record_receipts(&mut receipts, Vec::new(), std::slice::from_ref(entry), block_index);
+            }
+            let mut refunded = HashSet::new();
+            for settlement in settlements.iter().filter(|(.., entry)| entry.action == ReceiptAction::Refund) {
+                let (_, block_index, _, entry) = *settlement;
+                let receipt = &entry.receipt;
+                let refundable = match outcomes.get(&receipt.tx_hash) {
+                    Some((ReceiptAction::Reject, rejected_at)) => {
+                        let committed = self
+                            .beacon
+                            .get(entry.beacon_height as usize)
+                            .is_some_and(|beacon| beacon.commits(receipt.destination_shard, *rejected_at));
+                        if !committed {
+                            return Err(invalid_receipt(settlement, ReceiptError::NotCommitted));
+                        }
+                        true
+                    }
+                    Some(_) => false,
+                    None => block_index > receipt.deadline(),
+                };
+                if !refundable {
+                    return Err(invalid_receipt(settlement, ReceiptError::NotRefundable));
+                }
+                if !refunded.insert(receipt.tx_hash) {
+                    return Err(invalid_receipt(settlement, ReceiptError::AlreadySettled));
+                }
+                Self::record_receipts(&mut receipts, Vec::new(), std::slice::from_ref(entry), block_index);
+            }
+            let beacon_head = self.beacon_head();
+            receipts.retain(|_, record| !Self::is_final(record, beacon_head));
+            Ok((all_nonces, receipts, all_authorities))
+        }
+
+        /// Checks everything about `block` that does not depend on chain state: its index,
+        /// `previous_hash` and timestamp against `parent` (none for a genesis block), shard
+        /// id, `tx_root`, `receipt_root` and recomputed hash.
+        fn check_block_header(&self, block: &Block, shard_id: u64, parent: Option<&Block>) -> Result<(), BlockError> {
+            let expected_index = parent.map_or(0, |parent| parent.index + 1);
+            let expected_previous = parent.map_or(self.genesis_hash, |parent| parent.hash);
+            if block.index != expected_index {
+                return Err(BlockError::IndexMismatch { expected: expected_index, got: block.index });
+            }
+            if block.previous_hash != expected_previous {
+                return Err(BlockError::PreviousHashMismatch { expected: expected_previous, got: block.previous_hash });
+            }
+            if let Some(parent) = parent.filter(|parent| block.timestamp <= parent.timestamp) {
+                return Err(BlockError::TimestampNotIncreasing { previous: parent.timestamp, got: block.timestamp });
+            }
+            if block.shard_id != Some(shard_id) {
+                return Err(BlockError::ShardMismatch { expected: shard_id, got: block.shard_id });
+            }
+            let tx_root = Block::transactions_root(&block.transactions);
+            if block.tx_root != tx_root {
+                return Err(BlockError::TxRootMismatch { expected: tx_root, got: block.tx_root });
+            }
+            let receipt_root = Block::receipts_root(&block.receipts);
+            if block.receipt_root != receipt_root {
+                return Err(BlockError::ReceiptRootMismatch { expected: receipt_root, got: block.receipt_root });
+            }
+            let hash = block.calculate_hash();
+            if block.hash != hash {
+                return Err(BlockError::HashMismatch { expected: hash, got: block.hash });
+            }
+            self.check_proposer(block, parent.is_none())
+        }
+
+        /// A block must be signed by the validator it names as proposer. Only genesis
+        /// blocks, and the blocks of a chain without validators, may have no proposer.
+        fn check_proposer(&self, block: &Block, genesis: bool) -> Result<(), BlockError> {
+            match &block.proposer {
+                Some(proposer) => {
+                    let key = self.validators.get(proposer).ok_or(BlockError::UnknownProposer(*proposer))?;
+                    block.verify_signature(key).map_err(BlockError::InvalidProposerSignature)
+                }
+                None if genesis || self.validators.is_empty() => Ok(()),
+                None => Err(BlockError::MissingProposer),
+            }
+        }
+
+        /// Checks the beacon chain from its genesis block: index continuity, `previous_hash`
+        /// links, increasing timestamps, the recomputed hashes, and that each block commits one head per shard,
+        /// naming a block that exists with that hash and not below the previous head.
+        fn verify_beacon(&self) -> Result<(), ChainError> {
+            let invalid = |index: u64, reason: BeaconError| ChainError::InvalidBeacon { index, reason };
+            if self.beacon.is_empty() {
+                return Err(invalid(0, BeaconError::MissingGenesis));
+            }
+            let mut previous: Option<&BeaconBlock> = None;
+            for block in &self.beacon {
+                let expected_index = previous.map_or(0, |prev| prev.index + 1);
+                if block.index != expected_index {
+                    return Err(invalid(block.index, BeaconError::IndexMismatch { expected: expected_index, got: block.index }));
+                }
+                let expected_previous = previous.map_or(self.genesis_hash, |prev| prev.hash);
+                if block.previous_hash != expected_previous {
+                    return Err(invalid(
+                        block.index,
+                        BeaconError::PreviousHashMismatch { expected: expected_previous, got: block.previous_hash },
+                    ));
+                }
+                if let Some(prev) = previous.filter(|prev| block.timestamp <= prev.timestamp) {
+                    return Err(invalid(
+                        block.index,
+                        BeaconError::TimestampNotIncreasing { previous: prev.timestamp, got: block.timestamp },
+                    ));
+                }
+                let hash = block.calculate_hash();
+                if block.hash != hash {
+                    return Err(invalid(block.index, BeaconError::HashMismatch { expected: hash, got: block.hash }));
+                }
+                if block.shard_heads.len() != self.shards.len() {
+                    return Err(invalid(block.index, BeaconError::ShardCountMismatch {
+                        expected: self.shards.len(),
+                        got: block.shard_heads.len(),
+                    }));
+                }
+                for (position, head) in block.shard_heads.iter().enumerate() {
+                    let shard_id = position as u64;
+                    let exists = head.shard_id == shard_id
+                        && self
+                            .shards
+                            .get(shard_id)
+                            .and_then(|shard| shard.blocks.get(head.index as usize))
+                            .is_some_and(|committed| committed.hash == head.hash);
+                    if !exists {
+                        return Err(invalid(block.index, BeaconError::UnknownHead { shard_id }));
+                    }
+                    if previous.and_then(|prev| prev.shard_head(shard_id)).is_some_and(|prev| prev.index > head.index) {
+                        return Err(invalid(block.index, BeaconError::HeadRegressed { shard_id }));
+                    }
+                }
+                previous = Some(block);
+            }
+            Ok(())
+        }
+
+        /// Nonce and field checks for the transactions of one block, starting from the
+        /// `committed` nonces. Returns the nonces the block leaves behind, or the
+        /// index of the first bad transaction.
+        fn validate_block_transactions(
+            &self,
+            transactions: &[Transaction],
+            shard_id: u64,
+            committed: &HashMap<Address, u64>,
+        ) -> Result<HashMap<Address, u64>, (usize, TxError)> {
+            let mut block_nonces = HashMap::new();
+            for (index, tx) in transactions.iter().enumerate() {
+                self.validate_nonce(tx, committed, &block_nonces)
+                    .and_then(|_| self.validate_transaction(tx, shard_id))
+                    .map_err(|error| (index, error))?;
+                block_nonces.insert(tx.sender, tx.nonce);
+            }
+            Ok(block_nonces)
+        }
+
+        /// Checks `transaction.nonce` against the sender's last `committed` nonce, taking
+        /// earlier transactions of the block being validated (`block_nonces`) into account.
+        fn validate_nonce(
+            &self,
+            transaction: &Transaction,
+            committed: &HashMap<Address, u64>,
+            block_nonces: &HashMap<Address, u64>,
+        ) -> Result<(), TxError> {
+            let last = block_nonces
+                .get(&transaction.sender)
+                .or_else(|| committed.get(&transaction.sender))
+                .copied();
+            let expected = match last {
+                Some(last) => last.checked_add(1).ok_or(TxError::NoncesExhausted(transaction.sender))?,
+                None => 0,
+            };
+            if transaction.nonce < expected {
+                return Err(TxError::NonceReuse(transaction.nonce));
+            }
+            if self.nonce_policy == NoncePolicy::GapFree && transaction.nonce != expected {
+                return Err(TxError::NonceGap { expected, got: transaction.nonce });
+            }
+            Ok(())
+        }
+
+        /// Checks the transaction's fields for a block of `shard_id`. A transaction is only
+        /// accepted on its sender's home shard, `assign_shard(sender)`, where the sender's
+        /// nonces and balances live; transfers to other shards leave receipts behind.
+        fn validate_transaction(&self, transaction: &Transaction, shard_id: u64) -> Result<(), TxError> {
+            if transaction.chain_id != self.chain_id {
+                return Err(TxError::WrongChainId { expected: self.chain_id, got: transaction.chain_id });
+            }
+            if transaction.shard_id != shard_id {
+                return Err(TxError::WrongShard { expected: shard_id, got: transaction.shard_id });
+            }
+            let home_shard = self.assign_shard(&transaction.sender);
+            if transaction.shard_id != home_shard {
+                return Err(TxError::WrongShard { expected: home_shard, got: transaction.shard_id });
+            }
+            if transaction.gas_limit == 0 {
+                return Err(TxError::ZeroGas);
+            }
+            match transaction.kind {
+                TxKind::Transfer if transaction.token.amount().is_zero() => return Err(TxError::NonPositiveAmount),
+                TxKind::Transfer => {}
+                _ if transaction.receiver != transaction.sender || !transaction.token.amount().is_zero() => {
+                    return Err(TxError::TransferInRegistration);
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+
+        /// Verifies the signatures of every transaction of block `index` on up to
+        /// `verify_workers` threads, looking up the senders' key records in effect with
+        /// `known` unless an earlier transaction of the block registered them. Registrations
+        /// are verified first, since the transactions after them may depend on what they
+        /// register; a registration that fails registers nothing. Key changes only take
+        /// effect after the block, and a sender's keys change at most once per block.
+        /// Signatures found in `signature_cache` are not verified again.
+        pub fn batch_verify_signatures<'k>(
+            &self,
+            transactions: &[Transaction],
+            index: u64,
+            known: impl Fn(&Address) -> Option<&'k KeyRecord>,
+        ) -> SignatureBatch {
+            let registrations: Vec<&Transaction> = transactions.iter().filter(|tx| tx.kind.is_registration()).collect();
+            let cache = &self.signature_cache;
+            let mut registration_results = sigverify::parallel_map(&registrations, self.verify_workers, |tx| {
+                Self::verify_signature(cache, tx, None, index)
+            })
+            .into_iter();
+
+            let mut batch = SignatureBatch::default();
+            let mut pending = Vec::new();
+            let mut changes = Vec::new();
+            for (tx_index, transaction) in transactions.iter().enumerate() {
+                let sender = transaction.sender;
+                let record = batch
+                    .authorities
+                    .get(&sender)
+                    .and_then(|history| history.at(index))
+                    .or_else(|| known(&sender));
+                let result = if transaction.kind.is_registration() {
+                    let verified = registration_results.next().expect("one result per registration");
+                    match record {
+                        Some(_) => Err(TxError::KeyAlreadyRegistered(sender)),
+                        None => verified.map(|registered| {
+                            if let Some(registered) = registered {
+                                batch.authorities.entry(sender).or_default().push(registered);
+                            }
+                        }),
+                    }
+                } else {
+                    Self::required_signatures(transaction, record, index).map(|(checks, change)| {
+                        pending.extend(checks.into_iter().map(|(key, signature)| (tx_index, key, signature)));
+                        changes.extend(change.map(|change| (tx_index, change)));
+                    })
+                };
+                batch.results.push(result);
+            }
+
+            let results = sigverify::parallel_map(&pending, self.verify_workers, |(tx_index, key, signature)| {
+                Self::check_signature(cache, &transactions[*tx_index], key, signature)
+            });
+            for ((tx_index, _, _), result) in pending.iter().zip(results) {
+                if batch.results[*tx_index].is_ok() {
+                    batch.results[*tx_index] = result;
+                }
+            }
+            for (tx_index, change) in changes {
+                let sender = transactions[tx_index].sender;
+                if batch.results[tx_index].is_err() {
+                    continue;
+                }
+                if Self::key_change_pending(batch.authorities.get(&sender), index) {
+                    batch.results[tx_index] = Err(TxError::KeyChangePending(sender));
+                } else {
+                    batch.authorities.entry(sender).or_default().push(change);
+                }
+            }
+            batch
+        }
+
+        /// Checks the transaction's signatures for block `index` against `record`, the
+        /// sender's key record in effect there, and returns the record the transaction adds
+        /// to the sender's key history, if any. Takes the cache rather than the chain so
+        /// that worker threads can call it.
+        fn verify_signature(
+            cache: &SignatureCache,
+            transaction: &Transaction,
+            record: Option<&KeyRecord>,
+            index: u64,
+        ) -> Result<Option<KeyRecord>, TxError> {
+            let (checks, added) = Self::required_signatures(transaction, record, index)?;
+            for (key, signature) in checks {
+                Self::check_signature(cache, transaction, &key, signature)?;
+            }
+            Ok(added)
+        }
+
+        /// The signatures that must verify for the transaction to be authorized in block
+        /// `index`, each with the key it must verify under, and the key record the
+        /// transaction adds. A registration is signed under the key or policy it registers,
+        /// which must derive the sender's address, and takes effect at once. Any other
+        /// transaction is signed under the sender's authority in `record`, except `Recover`
+        /// and, once a recovery authority is set, `SetRecovery`, which that authority signs;
+        /// their changes take effect from the next block.
+        ///
+        /// A single key signs `signature`. A multisig policy leaves `signature` empty and
+        /// needs cosignatures by at least `threshold` distinct keys of the policy; every
+        /// cosignature given must verify.
+        #[allow(clippy::type_complexity)]
+        fn required_signatures<'t>(
+            transaction: &'t Transaction,
+            record: Option<&KeyRecord>,
+            index: u64,
+        ) -> Result<(Vec<(PublicKey, &'t str)>, Option<KeyRecord>), TxError> {
+            let sender = transaction.sender;
+            let change = |authority: &Authority, recovery: Option<&Authority>| {
+                authority.validate().map_err(TxError::InvalidPolicy)?;
+                recovery.map_or(Ok(()), Authority::validate).map_err(TxError::InvalidPolicy)?;
+                Ok(Some(KeyRecord { since: index + 1, authority: authority.clone(), recovery: recovery.cloned() }))
+            };
+            let (signer, added) = match (&transaction.kind, record) {
+                (TxKind::RegisterKey { .. } | TxKind::RegisterMultisig { .. }, Some(_)) => {
+                    return Err(TxError::KeyAlreadyRegistered(sender));
+                }
+                (TxKind::RegisterKey { public_key }, None) => {
+                    let key = PublicKey::from_bytes(transaction.scheme, public_key)
+                        .map_err(|_| TxError::InvalidPublicKey(transaction.scheme))?;
+                    (None, Some(KeyRecord { since: index, authority: Authority::Key(key), recovery: None }))
+                }
+                (TxKind::RegisterMultisig { policy }, None) => {
+                    policy.validate().map_err(TxError::InvalidPolicy)?;
+                    (None, Some(KeyRecord { since: index, authority: Authority::Multisig(policy.clone()), recovery: None }))
+                }
+                (_, None) => return Err(TxError::UnknownSenderKey(sender)),
+                (TxKind::Transfer, Some(record)) => (Some(&record.authority), None),
+                (TxKind::RotateKey { authority }, Some(record)) => {
+                    (Some(&record.authority), change(authority, record.recovery.as_ref())?)
+                }
+                (TxKind::SetRecovery { recovery }, Some(record)) => {
+                    let signer = record.recovery.as_ref().unwrap_or(&record.authority);
+                    (Some(signer), change(&record.authority, Some(recovery))?)
+                }
+                (TxKind::Recover { authority }, Some(record)) => {
+                    let signer = record.recovery.as_ref().ok_or(TxError::NoRecovery(sender))?;
+                    (Some(signer), change(authority, Some(signer))?)
+                }
+            };
+            let signer = match (signer, &added) {
+                (Some(signer), _) => signer,
+                (None, Some(added)) if added.authority.address() != sender => return Err(TxError::SenderKeyMismatch(sender)),
+                (None, Some(added)) => &added.authority,
+                (None, None) => return Err(TxError::UnknownSenderKey(sender)),
+            };
+
+            let checks = match signer {
+                Authority::Key(key) => {
+                    if !transaction.cosignatures.is_empty() {
+                        return Err(TxError::UnexpectedCosignatures);
+                    }
+                    if key.scheme() != transaction.scheme {
+                        return Err(TxError::SchemeMismatch { expected: key.scheme(), got: transaction.scheme });
+                    }
+                    vec![(*key, transaction.signature.as_str())]
+                }
+                Authority::Multisig(policy) => {
+                    if !transaction.signature.is_empty() {
+                        return Err(TxError::MultisigNeedsCosignatures);
+                    }
+                    let mut signed = vec![false; policy.keys.len()];
+                    let mut checks = Vec::with_capacity(transaction.cosignatures.len());
+                    for cosignature in &transaction.cosignatures {
+                        let position = cosignature.key as usize;
+                        let key = policy.keys.get(position).ok_or(TxError::UnknownCosigner(cosignature.key))?;
+                        if std::mem::replace(&mut signed[position], true) {
+                            return Err(TxError::DuplicateCosigner(cosignature.key));
+                        }
+                        checks.push((*key, cosignature.signature.as_str()));
+                    }
+                    if checks.len() < policy.threshold as usize {
+                        return Err(TxError::BelowThreshold { threshold: policy.threshold, got: checks.len() });
+                    }
+                    checks
+                }
+            };
+            Ok((checks, added))
+        }
+
+        /// Verifies `signature`, one of the transaction's signatures, under `public_key` with
+        /// the verifier of the key's scheme, unless `cache` says it already did.
+        fn check_signature(cache: &SignatureCache, transaction: &Transaction, public_key: &PublicKey, signature: &str) -> Result<(), TxError> {
+            let mut encoder = Encoder::new();
+            encoder
+                .raw(transaction.hash().as_bytes())
+                .str(signature)
+                .u8(public_key.scheme().tag())
+                .bytes(&public_key.to_bytes());
+            let entry = Hash::digest(&encoder.finish());
+            if cache.contains(&entry) {
+                return Ok(());
+            }
+            let signature = hex::decode(signature).map_err(|_| TxError::BadSignatureHex)?;
+            public_key
+                .verify(&transaction.signing_bytes(), &signature)
+                .map_err(|error| match error {
+                    CryptoError::MalformedSignature(scheme) => TxError::MalformedSignature(scheme),
+                    _ => TxError::InvalidSignature,
+                })?;
+            cache.insert(entry);
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) mod tests {
+        use super::*;
+        use crate::beacon::verify_shard_headers;
+        use crate::builder::TransactionBuilder;
+        use crate::clock::ManualClock;
+        use crate::genesis::{GenesisAccount, GenesisKey, GenesisValidator};
+        use crate::receipts::RECEIPT_TIMEOUT_BLOCKS;
+
+        /// The P-256 key that signs the vectors in `docs/signing.md`.
+        pub(crate) fn alice_key() -> SecretKey {
+            let secret = hex::decode("c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721").unwrap();
+            SecretKey::from_bytes(SignatureScheme::P256, &secret).unwrap()
+        }
+
+        pub(crate) fn alice() -> Address {
+            Address::from_key(&alice_key().public_key())
+        }
+
+        pub(crate) fn bob() -> Address {
+            "crwbbb47e396351524a1298a3b6d355f224ec3d9e022642a3b8".parse().unwrap()
+        }
+
+        /// Vector 1 of `docs/signing.md` sent by `sender` under `scheme`, unsigned.
+        fn transfer(sender: Address, scheme: SignatureScheme) -> Transaction {
+            Transaction {
+                chain_id: 1,
+                shard_id: 1,
+                sender,
+                receiver: bob(),
+                token: Token::CustodyToken("10".parse().unwrap()),
+                nonce: 1,
+                contract_code: None,
+                gas_limit: 1000,
+                zkp: None,
+                kind: TxKind::Transfer,
+                scheme,
+                signature: String::new(),
+                cosignatures: Vec::new(),
+            }
+        }
+
+        fn vector_1() -> Transaction {
+            transfer(alice(), SignatureScheme::P256)
+        }
+
+        fn vector_2() -> Transaction {
+            Transaction {
+                chain_id: 7,
+                shard_id: 1,
+                token: Token::EnergyToken("0.5".parse().unwrap()),
+                nonce: 2,
+                contract_code: Some(hex::decode("0061736d").unwrap()),
+                gas_limit: 500,
+                zkp: Some(ZKProof { public_input: vec![1, 2], proof: vec![3, 4, 5] }),
+                ..vector_1()
+            }
+        }
+
+        fn vector_3() -> Transaction {
+            Transaction {
+                receiver: alice(),
+                token: Token::CustodyToken(Amount::ZERO),
+                nonce: 0,
+                kind: TxKind::RegisterKey { public_key: alice_key().public_key().to_bytes() },
+                ..vector_1()
+            }
+        }
+
+        fn signed(mut transaction: Transaction, key: &SecretKey) -> Transaction {
+            transaction.sign(key);
+            transaction
+        }
+
+        #[test]
+        fn signing_vectors() {
+            let vectors = [
+                (
+                    vector_1(),
+                    "0000000c43524157434841494e2f545804000000000000000001000000000000000116c52c97ac79d645bb98a0117d9e51df59b2b3cbbbb47e396351524a1298a3b6d355f224ec3d9e0200000000003b9aca00000000000000000100000000000003e8000000",
+                    "3046022100b17b4dbfab97637ad045557b0ec02e396e94230fee15a4ac5a1d15d08866b7ee022100c9fc656b1f6d1282e222569dabac7ec326c6f2e61f6ced149443993abd6766be",
+                    "e5cea0c9c2593b14e222fdeb17f9ebdd51e8d6d25924c431704c9237eecd20b0",
+                ),
+                (
+                    vector_2(),
+                    "0000000c43524157434841494e2f545804000000000000000007000000000000000116c52c97ac79d645bb98a0117d9e51df59b2b3cbbbb47e396351524a1298a3b6d355f224ec3d9e02010000000002faf080000000000000000200000000000001f401000000040061736d010000000201020000000303040500",
+                    "30440220179798c005e7077a2f9f02771b558f6005427415f4e8f1cd7d95191652c24be902201e1c91cac376aa895502e179b6a1a3b8c977171f50e4cf755481cd722c5b6c06",
+                    "493a7ff430704d846f1402aea123242dc8efc91538c36d154bbd6a2b8e7165b7",
+                ),
+                (
+                    vector_3(),
+                    "0000000c43524157434841494e2f545804000000000000000001000000000000000116c52c97ac79d645bb98a0117d9e51df59b2b3cb16c52c97ac79d645bb98a0117d9e51df59b2b3cb000000000000000000000000000000000000000000000003e8000001000000210360fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6",
+                    "30450220735d9205c25e78de218d76beda4a8d9e1a45de4645420609a9f56e86c5f1625a022100f3165088ef7c8e69ff8177dfa067ec44466980a18e3c8d4c28c3cf46fc7d8933",
+                    "998da9a12948d7f51dcef91601470eba76970903fbd167c92d104c502c11c89f",
+                ),
+            ];
+            for (transaction, signing_bytes, signature, id) in vectors {
+                assert_eq!(hex::encode(transaction.signing_bytes()), signing_bytes);
+                assert_eq!(transaction.hash().to_string(), id);
+                let transaction = signed(transaction, &alice_key());
+                assert_eq!(transaction.signature, signature);
+                assert_eq!(transaction.hash().to_string(), id, "the id does not cover the signature");
+            }
+        }
+
+        #[test]
+        fn node_accepts_vector_signatures() {
+            let genesis = GenesisConfig {
+                accounts: vec![GenesisAccount { address: alice(), custody: Amount::from_tokens(100).unwrap(), energy: Amount::ZERO }],
+                public_keys: vec![GenesisKey {
+                    address: alice(),
+                    scheme: SignatureScheme::P256,
+                    public_key: hex::encode(alice_key().public_key().to_bytes()),
+                }],
+                ..GenesisConfig::default()
+            };
+            let chain = Blockchain::from_genesis(&genesis).unwrap();
+            assert_eq!(chain.check_transaction(&signed(vector_1(), &alice_key())), Ok(()));
+
+            let mut replayed = signed(vector_1(), &alice_key());
+            replayed.chain_id = 7;
+            assert_eq!(chain.check_transaction(&replayed), Err(TxError::InvalidSignature));
+        }
+
+        #[test]
+        fn block_hash_vectors() {
+            let genesis = Block::new(0, 1_704_067_200_000, vec![], vec![], Hash::ZERO, Some(0), None);
+            assert_eq!(genesis.hash.to_string(), "f8cc84ad890376948777f16d85d63e49485951fdfc011bccfcc688002f07e726");
+            let transactions = vec![signed(vector_1(), &alice_key()), signed(vector_2(), &alice_key())];
+            let block = Block::new(1, 1_704_067_205_000, transactions, vec![], genesis.hash, Some(0), Some(alice()));
+            assert_eq!(block.hash.to_string(), "76279ce6f47a9cf9eb2cc269f230eaab31207801d1540c1792dc8ef72009ca72");
+            assert_eq!(block.header().calculate_hash(), block.hash);
+        }
+
+        #[test]
+        fn block_hash_covers_the_header() {
+            let block = Block::new(1, 1_704_067_205_000, vec![], vec![], Hash::ZERO, Some(0), Some(alice()));
+            let changed = [
+                Block { index: 2, ..block.clone() },
+                Block { timestamp: block.timestamp + 1, ..block.clone() },
+                Block { previous_hash: Hash::digest(b"parent"), ..block.clone() },
+                Block { shard_id: Some(1), ..block.clone() },
+                Block { proposer: Some(bob()), ..block.clone() },
+                Block { proposer: None, ..block.clone() },
+                Block { tx_root: Hash::digest(b"transactions"), ..block.clone() },
+                Block { receipt_root: Hash::digest(b"receipts"), ..block.clone() },
+            ];
+            for block in changed {
+                assert_ne!(block.calculate_hash(), block.hash);
+            }
+            let signed = Block { signature: "00".to_string(), ..block.clone() };
+            assert_eq!(signed.calculate_hash(), block.hash, "the proposer signature is not hashed");
+        }
+
+        #[test]
+        fn merkle_vectors() {
+            let (first, second) = (signed(vector_1(), &alice_key()), signed(vector_2(), &alice_key()));
+            assert_eq!(Block::transactions_root(&[]), Hash::ZERO);
+            assert_eq!(
+                Block::transactions_root(std::slice::from_ref(&first)).to_string(),
+                "552c928d6df614244b71831f89e6a123f6f5a33a00449d7b11fd568776ee2440"
+            );
+            let block = Block::new(1, 1_704_067_205_000, vec![first, second.clone()], vec![], Hash::ZERO, Some(0), Some(alice()));
+            assert_eq!(block.tx_root.to_string(), "2fad35f0da21748ce7115f7aada638d0d26e04345a1a91090935a06b7459e80d");
+
+            let proof = block.prove_inclusion(1).unwrap();
+            assert_eq!(proof.leaf_index, 1);
+            assert_eq!(proof.leaf_count, 2);
+            assert_eq!(proof.siblings, vec!["552c928d6df614244b71831f89e6a123f6f5a33a00449d7b11fd568776ee2440".parse().unwrap()]);
+            assert!(verify_inclusion(&block.header(), &second.hash(), &proof));
+            assert!(!verify_inclusion(&block.header(), &vector_3().hash(), &proof));
+            assert!(block.prove_inclusion(2).is_none());
+        }
+
+        #[test]
+        fn receipt_vectors() {
+            let first = signed(vector_1(), &alice_key());
+            let source = Block::new(1, 1_704_067_205_000, vec![first.clone()], vec![], Hash::ZERO, Some(1), Some(alice()));
+            let entry = ReceiptEntry {
+                receipt: Receipt::new(&first, 1, 0),
+                action: ReceiptAction::Credit,
+                beacon_height: 1,
+                proof: source.prove_inclusion(0).unwrap(),
+            };
+            assert_eq!(entry.hash().to_string(), "cdb4518f3f00e34cf1632f1c709ff858cba82316572f13ac68e4d6e733d152c3");
+            assert!(entry.receipt.verify(&source.header(), &entry.proof));
+            assert_eq!(
+                Block::receipts_root(&[entry]).to_string(),
+                "b6014e37a31d5a47afadd93ff0e54e579c6d8c2655649b3d0555a5193e4088d3"
+            );
+        }
+
+        #[test]
+        fn scheme_vectors() {
+            let vectors = [
+                (
+                    SignatureScheme::Ed25519,
+                    [5u8; 32],
+                    "6e7a1cdd29b0b78fd13af4c5598feff4ef2a97166e3ca6f2e4fbfccd80505bf1",
+                    "crwf446b958b2dd0154df28137f92716e9b1d9a0d7239701138",
+                    "0000000c43524157434841494e2f5458040100000000000000010000000000000001f446b958b2dd0154df28137f92716e9b1d9a0d72bbb47e396351524a1298a3b6d355f224ec3d9e0200000000003b9aca00000000000000000100000000000003e8000000",
+                    "baff946261fd6d07a6554feea5c7cf05df5a5b106a85feffb040f34ae9051b5f4e10b2cbc33525d78ebb092264f3307d5ecc79bac4d9f40ecf6716b89c578003",
+                ),
+                (
+                    SignatureScheme::Secp256k1,
+                    [6u8; 32],
+                    "03f006a18d5653c4edf5391ff23a61f03ff83d237e880ee61187fa9f379a028e0a",
+                    "crw07b417792aeefa3e1a1428883433b61e6084cd5aa561ce5f",
+                    "0000000c43524157434841494e2f545804020000000000000001000000000000000107b417792aeefa3e1a1428883433b61e6084cd5abbb47e396351524a1298a3b6d355f224ec3d9e0200000000003b9aca00000000000000000100000000000003e8000000",
+                    "304402207e5d7090e8824b4e338ab31367926c332bac56f68ac6eccb11ebf140bbee15e302201d1d9c7dd17d78c01211a7d4af81413d36bd21a036ae4b0c60e67383f51dddf3",
+                ),
+            ];
+            for (scheme, secret, public_key, address, signing_bytes, signature) in vectors {
+                let key = SecretKey::from_bytes(scheme, &secret).unwrap();
+                assert_eq!(hex::encode(key.public_key().to_bytes()), public_key);
+                let sender = Address::from_key(&key.public_key());
+                assert_eq!(sender.to_string(), address);
+                let transaction = signed(transfer(sender, scheme), &key);
+                assert_eq!(hex::encode(transaction.signing_bytes()), signing_bytes);
+                assert_eq!(transaction.signature, signature);
+                let signature = hex::decode(signature).unwrap();
+                assert_eq!(key.public_key().verify(&transaction.signing_bytes(), &signature), Ok(()));
+            }
+        }
+
+        #[test]
+        fn transaction_scheme_must_match_the_key() {
+            let key = SecretKey::from_bytes(SignatureScheme::Ed25519, &[5u8; 32]).unwrap();
+            let sender = Address::from_key(&key.public_key());
+            let genesis = GenesisConfig {
+                accounts: vec![GenesisAccount { address: sender, custody: Amount::from_tokens(100).unwrap(), energy: Amount::ZERO }],
+                public_keys: vec![GenesisKey {
+                    address: sender,
+                    scheme: SignatureScheme::Ed25519,
+                    public_key: hex::encode(key.public_key().to_bytes()),
+                }],
+                ..GenesisConfig::default()
+            };
+            let chain = Blockchain::from_genesis(&genesis).unwrap();
+            let mut transaction = transfer(sender, SignatureScheme::Ed25519);
+            transaction.shard_id = chain.assign_shard(&sender);
+            assert_eq!(chain.check_transaction(&signed(transaction.clone(), &key)), Ok(()));
+
+            let mut relabeled = signed(transaction, &key);
+            relabeled.scheme = SignatureScheme::P256;
+            assert_eq!(
+                chain.check_transaction(&relabeled),
+                Err(TxError::SchemeMismatch { expected: SignatureScheme::Ed25519, got: SignatureScheme::P256 })
+            );
+        }
+
+        /// The 2-of-2 policy of vector 6: Alice's key and the Ed25519 key of vector 4.
+        fn vault_policy() -> MultisigPolicy {
+            let ed25519 = SecretKey::from_bytes(SignatureScheme::Ed25519, &[5u8; 32]).unwrap();
+            MultisigPolicy::new(2, vec![alice_key().public_key(), ed25519.public_key()]).unwrap()
+        }
+
+        fn vector_6() -> Transaction {
+            let vault = vault_policy().address();
+            Transaction {
+                sender: vault,
+                receiver: vault,
+                token: Token::CustodyToken(Amount::ZERO),
+                nonce: 0,
+                kind: TxKind::RegisterMultisig { policy: vault_policy() },
+                ..vector_1()
+            }
+        }
+
+        #[test]
+        fn multisig_vector() {
+            let policy = vault_policy();
+            assert_eq!(
+                hex::encode(policy.encode()),
+                "0000001243524157434841494e2f4d554c5449534947000000020000000200000000210360fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb601000000206e7a1cdd29b0b78fd13af4c5598feff4ef2a97166e3ca6f2e4fbfccd80505bf1"
+            );
+            assert_eq!(policy.address().to_string(), "crw61b8f342c1db777c09181d963b27e0631c142d9c1d4c284f");
+
+            let mut transaction = vector_6();
+            assert_eq!(
+                hex::encode(transaction.signing_bytes()),
+                "0000000c43524157434841494e2f545804000000000000000001000000000000000161b8f342c1db777c09181d963b27e0631c142d9c61b8f342c1db777c09181d963b27e0631c142d9c000000000000000000000000000000000000000000000003e8000002000000690000001243524157434841494e2f4d554c5449534947000000020000000200000000210360fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb601000000206e7a1cdd29b0b78fd13af4c5598feff4ef2a97166e3ca6f2e4fbfccd80505bf1"
+            );
+            transaction.cosign(0, &alice_key());
+            transaction.cosign(1, &SecretKey::from_bytes(SignatureScheme::Ed25519, &[5u8; 32]).unwrap());
+            assert_eq!(
+                transaction.cosignatures[0].signature,
+                "30450220390e2b0a4274c1143da35cd3a70aa8ecc356202f306cd6c75398081dbcf3568b022100a46b8c7969bd199b5971fbe0230b0e06973596a4df66c6a9ad65f672a954f97d"
+            );
+            assert_eq!(
+                transaction.cosignatures[1].signature,
+                "bbd35ae17752321b59d1d1ee3e8c266f512defca8397c6fb8a7f9e413df66c3f733ec850c64ab8054e69cb71ef2784feaa8a08cdd3bb5558ce1ca853468fd60a"
+            );
+        }
+
+        #[test]
+        fn multisig_registration_needs_the_threshold() {
+            let chain = Blockchain::new();
+            let ed25519 = SecretKey::from_bytes(SignatureScheme::Ed25519, &[5u8; 32]).unwrap();
+            let mut transaction = vector_6();
+            transaction.shard_id = chain.assign_shard(&transaction.sender);
+
+            let mut alone = transaction.clone();
+            alone.cosign(0, &alice_key());
+            assert_eq!(chain.check_transaction(&alone), Err(TxError::BelowThreshold { threshold: 2, got: 1 }));
+            let mut twice = alone.clone();
+            twice.cosign(0, &alice_key());
+            assert_eq!(chain.check_transaction(&twice), Err(TxError::DuplicateCosigner(0)));
+            let mut stranger = alone.clone();
+            stranger.cosign(2, &ed25519);
+            assert_eq!(chain.check_transaction(&stranger), Err(TxError::UnknownCosigner(2)));
+            let mut swapped = transaction.clone();
+            swapped.cosign(0, &ed25519);
+            swapped.cosign(1, &alice_key());
+            assert!(chain.check_transaction(&swapped).is_err());
+
+            let mut both = alone;
+            both.cosign(1, &ed25519);
+            assert_eq!(chain.check_transaction(&both), Ok(()));
+        }
+
+        /// The default genesis with Alice's key registered and 100 CustodyToken in her account.
+        pub(crate) fn alice_genesis() -> GenesisConfig {
+            GenesisConfig {
+                accounts: vec![GenesisAccount { address: alice(), custody: Amount::from_tokens(100).unwrap(), energy: Amount::ZERO }],
+                public_keys: vec![GenesisKey {
+                    address: alice(),
+                    scheme: SignatureScheme::P256,
+                    public_key: hex::encode(alice_key().public_key().to_bytes()),
+                }],
+                ..GenesisConfig::default()
+            }
+        }
+
+        /// A chain started from `genesis` that reads its time from `clock`, so that chains
+        /// sharing a clock accept each other's blocks.
+        pub(crate) fn chain_at(genesis: &GenesisConfig, clock: &Arc<ManualClock>) -> Blockchain {
+            let mut chain = Blockchain::from_genesis(genesis).unwrap();
+            chain.clock = clock.clone();
+            chain
+        }
+
+        pub(crate) fn genesis_clock() -> Arc<ManualClock> {
+            Arc::new(ManualClock::new(1_704_067_200_000))
+        }
+
+        /// Alice's next transfer of `tokens` CustodyToken to Bob on `chain`.
+        pub(crate) fn pay_bob(chain: &Blockchain, tokens: u64) -> Transaction {
+            let token = Token::CustodyToken(Amount::from_tokens(tokens).unwrap());
+            TransactionBuilder::transfer(bob(), token).build(chain, &alice_key()).unwrap().0
+        }
+
+        pub(crate) fn assert_same_state(chain: &Blockchain, expected: &Blockchain) {
+            assert_eq!(chain.state, expected.state);
+            assert_eq!(chain.nonces, expected.nonces);
+            assert_eq!(chain.authorities, expected.authorities);
+            assert_eq!(chain.receipts, expected.receipts);
+        }
+
+        #[test]
+        fn longer_branch_replaces_the_head() {
+            let (genesis, clock) = (alice_genesis(), genesis_clock());
+            let mut chain = chain_at(&genesis, &clock);
+            let mut rival = chain_at(&genesis, &clock);
+            let shard_id = chain.assign_shard(&alice());
+            let paid = chain.add_block(vec![pay_bob(&chain, 10)], shard_id).unwrap();
+            let branch = [rival.add_block(vec![], shard_id).unwrap(), rival.add_block(vec![], shard_id).unwrap()];
+
+            let tie = chain.import_block(shard_id, branch[0].clone()).unwrap();
+            assert!(tie.reverted.is_empty() && tie.applied.is_empty(), "a tie keeps the current head");
+            let outcome = chain.import_block(shard_id, branch[1].clone()).unwrap();
+            assert_eq!(outcome.reverted.iter().map(|block| block.hash).collect::<Vec<_>>(), vec![paid.hash]);
+            assert_eq!(outcome.applied.iter().map(|block| block.hash).collect::<Vec<_>>(), vec![branch[0].hash, branch[1].hash]);
+            let shard = chain.shards.get(shard_id).unwrap();
+            assert_eq!(shard.head().hash, branch[1].hash);
+            assert!(branch.iter().all(|block| shard.block(&block.hash).is_some_and(|known| shard.is_canonical(known))));
+            assert!(shard.block(&paid.hash).is_some_and(|known| !shard.is_canonical(known)), "the reverted block stays known as a fork");
+            assert_same_state(&chain, &rival);
+            assert_eq!(chain.verify_chain(), Ok(()));
+        }
+
+        #[test]
+        fn invalid_branch_restores_the_old_head() {
+            let (genesis, clock) = (alice_genesis(), genesis_clock());
+            let mut chain = chain_at(&genesis, &clock);
+            let mut rival = chain_at(&genesis, &clock);
+            let shard_id = chain.assign_shard(&alice());
+            let paid = chain.add_block(vec![pay_bob(&chain, 10)], shard_id).unwrap();
+            let mut before = chain_at(&genesis, &clock);
+            before.import_block(shard_id, paid.clone()).unwrap();
+
+            let first = rival.add_block(vec![], shard_id).unwrap();
+            // Bob never registered a key, so this transfer cannot be valid on any branch.
+            let mut forged = pay_bob(&rival, 10);
+            forged.sender = bob();
+            let invalid = Block::new(2, first.timestamp + 1, vec![forged], vec![], first.hash, Some(shard_id), None);
+
+            chain.import_block(shard_id, first).unwrap();
+            let error = chain.import_block(shard_id, invalid).unwrap_err();
+            assert!(matches!(
+                error,
+                ChainError::InvalidBlock { index: 2, reason: BlockError::InvalidTransaction { index: 0, .. }, .. }
+            ));
+            assert_eq!(chain.shards.get(shard_id).unwrap().head().hash, paid.hash);
+            assert_same_state(&chain, &before);
+            assert_eq!(chain.verify_chain(), Ok(()));
+        }
+
+        #[test]
+        fn beacon_committed_blocks_are_not_reverted() {
+            let (genesis, clock) = (alice_genesis(), genesis_clock());
+            let mut chain = chain_at(&genesis, &clock);
+            let mut rival = chain_at(&genesis, &clock);
+            let shard_id = chain.assign_shard(&alice());
+            let paid = chain.add_block(vec![pay_bob(&chain, 10)], shard_id).unwrap();
+            chain.commit_beacon().unwrap();
+            assert_eq!(chain.finalized_index(shard_id), 1);
+
+            for _ in 0..3 {
+                let block = rival.add_block(vec![], shard_id).unwrap();
+                let outcome = chain.import_block(shard_id, block).unwrap();
+                assert!(outcome.reverted.is_empty() && outcome.applied.is_empty());
+            }
+            assert_eq!(chain.shards.get(shard_id).unwrap().head().hash, paid.hash);
+        }
+
+        #[test]
+        fn revert_then_reapply_restores_the_state() {
+            let (genesis, clock) = (alice_genesis(), genesis_clock());
+            let mut chain = chain_at(&genesis, &clock);
+            let mut rival = chain_at(&genesis, &clock);
+            let mut replica = chain_at(&genesis, &clock);
+            let shard_id = chain.assign_shard(&alice());
+            let ours = [
+                chain.add_block(vec![pay_bob(&chain, 10)], shard_id).unwrap(),
+                chain.add_block(vec![pay_bob(&chain, 5)], shard_id).unwrap(),
+            ];
+            for block in &ours {
+                replica.import_block(shard_id, block.clone()).unwrap();
+            }
+            for _ in 0..3 {
+                let block = rival.add_block(vec![], shard_id).unwrap();
+                chain.import_block(shard_id, block).unwrap();
+            }
+            assert_same_state(&chain, &rival);
+
+            // The replica extends the original branch past the rival one, and the chain
+            // switches back to it, re-applying the two blocks it reverted.
+            let extension = [
+                replica.add_block(vec![pay_bob(&replica, 1)], shard_id).unwrap(),
+                replica.add_block(vec![], shard_id).unwrap(),
+            ];
+            chain.import_block(shard_id, extension[0].clone()).unwrap();
+            let outcome = chain.import_block(shard_id, extension[1].clone()).unwrap();
+            assert_eq!(outcome.reverted.len(), 3);
+            assert_eq!(outcome.applied.len(), 4);
+            assert_eq!(chain.shards.get(shard_id).unwrap().head().hash, extension[1].hash);
+            assert_same_state(&chain, &replica);
+            assert_eq!(chain.verify_chain(), Ok(()));
+        }
+
+        #[test]
+        fn reorg_without_undo_journal_fails() {
+            let (genesis, clock) = (alice_genesis(), genesis_clock());
+            let mut chain = chain_at(&genesis, &clock);
+            let mut rival = chain_at(&genesis, &clock);
+            let shard_id = chain.assign_shard(&alice());
+            let paid = chain.add_block(vec![pay_bob(&chain, 10)], shard_id).unwrap();
+            chain.undo.clear();
+
+            chain.import_block(shard_id, rival.add_block(vec![], shard_id).unwrap()).unwrap();
+            let error = chain.import_block(shard_id, rival.add_block(vec![], shard_id).unwrap()).unwrap_err();
+            assert_eq!(error, ChainError::MissingUndo { shard_id, index: 1 });
+            assert_eq!(chain.shards.get(shard_id).unwrap().head().hash, paid.hash);
+        }
+
+        #[test]
+        fn blocks_must_be_signed_by_a_validator() {
+            let validator = SecretKey::from_bytes(SignatureScheme::Ed25519, &[7u8; 32]).unwrap();
+            let outsider = SecretKey::from_bytes(SignatureScheme::Ed25519, &[8u8; 32]).unwrap();
+            let genesis = GenesisConfig {
+                validators: vec![GenesisValidator {
+                    address: Address::from_key(&validator.public_key()),
+                    stake: Amount::from_tokens(10).unwrap(),
+                    scheme: SignatureScheme::Ed25519,
+                    public_key: hex::encode(validator.public_key().to_bytes()),
+                }],
+                ..GenesisConfig::default()
+            };
+            let clock = genesis_clock();
+            let mut chain = chain_at(&genesis, &clock);
+            let mut proposer = chain_at(&genesis, &clock);
+            let parent = chain.shards.get(0).unwrap().head().clone();
+            let unsigned = Block::new(1, parent.timestamp + 1, vec![], vec![], parent.hash, Some(0), None);
+            let reason = |error: ChainError| match error {
+                ChainError::InvalidBlock { reason, .. } => reason,
+                other => panic!("unexpected error {}", other),
+            };
+
+            assert_eq!(reason(chain.import_block(0, unsigned.clone()).unwrap_err()), BlockError::MissingProposer);
+            assert_eq!(reason(chain.add_block(vec![], 0).unwrap_err()), BlockError::MissingProposer);
+            let outsider_address = Address::from_key(&outsider.public_key());
+            let mut foreign = Block { proposer: Some(outsider_address), ..unsigned.clone() };
+            foreign.hash = foreign.calculate_hash();
+            foreign.sign(&outsider);
+            assert_eq!(reason(chain.import_block(0, foreign).unwrap_err()), BlockError::UnknownProposer(outsider_address));
+            let mut impostor = Block { proposer: Some(Address::from_key(&validator.public_key())), ..unsigned };
+            impostor.hash = impostor.calculate_hash();
+            impostor.sign(&outsider);
+            assert_eq!(
+                reason(chain.import_block(0, impostor).unwrap_err()),
+                BlockError::InvalidProposerSignature(CryptoError::InvalidSignature)
+            );
+
+            let block = proposer.propose_block(vec![], 0, Some(&validator)).unwrap();
+            assert_eq!(chain.import_block(0, block).unwrap().applied.len(), 1);
+            assert_eq!(chain.verify_chain(), Ok(()));
+            chain.shards.get_mut(0).unwrap().blocks[1].signature.clear();
+            assert!(matches!(
+                chain.verify_chain(),
+                Err(ChainError::InvalidBlock { reason: BlockError::InvalidProposerSignature(_), .. })
+            ));
+        }
+
+        #[test]
+        fn blocks_are_stamped_from_the_clock() {
+            let clock = genesis_clock();
+            let mut chain = chain_at(&GenesisConfig::default(), &clock);
+            clock.set(1_704_067_260_000);
+            assert_eq!(chain.add_block(vec![], 0).unwrap().timestamp, 1_704_067_260_000);
+            // A clock behind the parent still yields increasing timestamps.
+            clock.set(1_704_067_200_000);
+            assert_eq!(chain.add_block(vec![], 0).unwrap().timestamp, 1_704_067_260_001);
+            // So does the beacon chain, whose parent here is its genesis block.
+            assert_eq!(chain.commit_beacon().unwrap().timestamp, 1_704_067_200_001);
+        }
+
+        #[test]
+        fn timestamps_must_increase() {
+            let clock = genesis_clock();
+            let mut chain = chain_at(&GenesisConfig::default(), &clock);
+            let parent = chain.shards.get(0).unwrap().head().clone();
+            let stale = Block::new(1, parent.timestamp, vec![], vec![], parent.hash, Some(0), None);
+            assert_eq!(
+                chain.import_block(0, stale).unwrap_err(),
+                ChainError::InvalidBlock {
+                    shard_id: 0,
+                    index: 1,
+                    reason: BlockError::TimestampNotIncreasing { previous: parent.timestamp, got: parent.timestamp },
+                }
+            );
+
+            chain.add_block(vec![], 0).unwrap();
+            chain.shards.get_mut(0).unwrap().blocks[1].timestamp = parent.timestamp;
+            assert!(matches!(
+                chain.verify_chain(),
+                Err(ChainError::InvalidBlock { index: 1, reason: BlockError::TimestampNotIncreasing { .. }, .. })
+            ));
+        }
+
+        #[test]
+        fn blocks_from_the_future_wait_for_the_clock() {
+            let (genesis, clock) = (GenesisConfig::default(), genesis_clock());
+            let mut chain = chain_at(&genesis, &clock);
+            let ahead = Arc::new(ManualClock::new(1_704_067_200_000 + DEFAULT_MAX_CLOCK_DRIFT + 1));
+            let block = chain_at(&genesis, &ahead).add_block(vec![], 0).unwrap();
+
+            let max = 1_704_067_200_000 + DEFAULT_MAX_CLOCK_DRIFT;
+            assert_eq!(
+                chain.import_block(0, block.clone()).unwrap_err(),
+                ChainError::InvalidBlock {
+                    shard_id: 0,
+                    index: 1,
+                    reason: BlockError::TimestampTooFarAhead { max, got: block.timestamp },
+                }
+            );
+            assert_eq!(chain.shards.get(0).unwrap().height(), 0);
+
+            clock.advance(1);
+            assert_eq!(chain.import_block(0, block.clone()).unwrap().applied.len(), 1);
+            assert_eq!(chain.shards.get(0).unwrap().head().hash, block.hash);
+        }
+
+        /// The Ed25519 key of vector 4, which Alice rotates to in vector 7.
+        fn rotated_key() -> SecretKey {
+            SecretKey::from_bytes(SignatureScheme::Ed25519, &[5u8; 32]).unwrap()
+        }
+
+        /// A P-256 key that Alice names as her recovery authority.
+        fn recovery_key() -> SecretKey {
+            SecretKey::from_bytes(SignatureScheme::P256, &[9u8; 32]).unwrap()
+        }
+
+        /// Alice's next key change of `kind` on `chain`, signed with `key`.
+        fn key_change(chain: &Blockchain, kind: TxKind, key: &SecretKey) -> Transaction {
+            TransactionBuilder::new(kind).sender(alice()).build(chain, key).unwrap().0
+        }
+
+        fn rotate_to(key: &SecretKey) -> TxKind {
+            TxKind::RotateKey { authority: Authority::Key(key.public_key()) }
+        }
+
+        /// Alice's next transfer of one CustodyToken to Bob on `chain`, signed with `key`.
+        fn pay_bob_with(chain: &Blockchain, key: &SecretKey) -> Transaction {
+            let token = Token::CustodyToken(Amount::from_tokens(1).unwrap());
+            TransactionBuilder::transfer(bob(), token).sender(alice()).build(chain, key).unwrap().0
+        }
+
+        #[test]
+        fn genesis_keys_cannot_be_registered_again() {
+            let clock = genesis_clock();
+            let mut chain = chain_at(&alice_genesis(), &clock);
+            let shard_id = chain.assign_shard(&alice());
+            let (register, _) = TransactionBuilder::register_key(&alice_key().public_key()).build(&chain, &alice_key()).unwrap();
+            let error = TxError::KeyAlreadyRegistered(alice());
+            assert_eq!(
+                chain.add_block(vec![register.clone()], shard_id).unwrap_err(),
+                ChainError::InvalidTransaction { index: 0, error: error.clone() }
+            );
+
+            // Replay starts from the genesis keys too, so it refuses the same block.
+            let head = chain.shards.get(shard_id).unwrap().head();
+            let block = Block::new(head.index + 1, head.timestamp + 1, vec![register], vec![], head.hash, Some(shard_id), None);
+            chain.shards.push_block(shard_id, block);
+            assert_eq!(
+                chain.verify_chain(),
+                Err(ChainError::InvalidBlock { shard_id, index: 1, reason: BlockError::InvalidTransaction { index: 0, error } })
+            );
+        }
+
+        #[test]
+        fn rotation_vector() {
+            let authority = Authority::Key(rotated_key().public_key());
+            assert_eq!(
+                hex::encode(authority.encode()),
+                "0001000000206e7a1cdd29b0b78fd13af4c5598feff4ef2a97166e3ca6f2e4fbfccd80505bf1"
+            );
+            let transaction = Transaction {
+                receiver: alice(),
+                token: Token::CustodyToken(Amount::ZERO),
+                kind: TxKind::RotateKey { authority },
+                ..vector_1()
+            };
+            assert_eq!(
+                hex::encode(transaction.signing_bytes()),
+                "0000000c43524157434841494e2f545804000000000000000001000000000000000116c52c97ac79d645bb98a0117d9e51df59b2b3cb16c52c97ac79d645bb98a0117d9e51df59b2b3cb000000000000000000000000000000000100000000000003e8000003000000260001000000206e7a1cdd29b0b78fd13af4c5598feff4ef2a97166e3ca6f2e4fbfccd80505bf1"
+            );
+            assert_eq!(
+                signed(transaction, &alice_key()).signature,
+                "3045022100aca37e468597773610838fd4da72f60c264bb42e4af06692d632a72b241c453c02200efe757121b6ae156276a8ee11407006043421d68170e7f183d26b606986f20c"
+            );
+        }
+
+        #[test]
+        fn rotated_key_signs_from_the_next_block() {
+            let (genesis, clock) = (alice_genesis(), genesis_clock());
+            let mut chain = chain_at(&genesis, &clock);
+            let shard_id = chain.assign_shard(&alice());
+            let paid = pay_bob(&chain, 10);
+            let mut rotation = key_change(&chain, rotate_to(&rotated_key()), &alice_key());
+            rotation.nonce = paid.nonce + 1;
+            rotation.sign(&alice_key());
+            // Alice's transfers after the rotation in the same block are still under her old key.
+            let mut after = pay_bob(&chain, 5);
+            after.nonce = rotation.nonce + 1;
+            after.sign(&alice_key());
+            chain.add_block(vec![paid, rotation, after], shard_id).unwrap();
+
+            let history = chain.key_history(&alice()).unwrap().records();
+            assert_eq!(history.len(), 2);
+            assert_eq!(history[1].since, 2);
+            assert_eq!(chain.authority(&alice()), Some(&Authority::Key(rotated_key().public_key())));
+
+            let stale = pay_bob_with(&chain, &alice_key());
+            let error = TxError::SchemeMismatch { expected: SignatureScheme::Ed25519, got: SignatureScheme::P256 };
+            assert_eq!(chain.check_transaction(&stale), Err(error.clone()));
+            let head = chain.shards.get(shard_id).unwrap().head().clone();
+            let block = Block::new(2, head.timestamp + 1, vec![stale], vec![], head.hash, Some(shard_id), None);
+            assert_eq!(
+                chain.import_block(shard_id, block).unwrap_err(),
+                ChainError::InvalidBlock { shard_id, index: 2, reason: BlockError::InvalidTransaction { index: 0, error } }
+            );
+
+            chain.add_block(vec![pay_bob_with(&chain, &rotated_key())], shard_id).unwrap();
+            assert_eq!(chain.state.balances(&alice()).custody, Amount::from_tokens(84).unwrap());
+            // Block 1 is still checked against the P-256 key it was signed with.
+            assert_eq!(chain.verify_chain(), Ok(()));
+        }
+
+        #[test]
+        fn keys_change_at_most_once_per_block() {
+            let (genesis, clock) = (alice_genesis(), genesis_clock());
+            let mut chain = chain_at(&genesis, &clock);
+            let shard_id = chain.assign_shard(&alice());
+            let first = key_change(&chain, rotate_to(&rotated_key()), &alice_key());
+            let mut second = key_change(&chain, rotate_to(&recovery_key()), &alice_key());
+            second.nonce = first.nonce + 1;
+            second.sign(&alice_key());
+
+            let error = chain.add_block(vec![first.clone(), second.clone()], shard_id).unwrap_err();
+            assert_eq!(error, ChainError::InvalidTransaction { index: 1, error: TxError::KeyChangePending(alice()) });
+
+            let mut draft = BlockDraft::new(shard_id);
+            chain.try_include(&mut draft, first).unwrap();
+            assert_eq!(chain.try_include(&mut draft, second), Err(TxError::KeyChangePending(alice())));
+            assert_eq!(draft.transactions.len(), 1);
+        }
+
+        #[test]
+        fn recovery_authority_replaces_the_key() {
+            let (genesis, clock) = (alice_genesis(), genesis_clock());
+            let mut chain = chain_at(&genesis, &clock);
+            let shard_id = chain.assign_shard(&alice());
+            let set = TxKind::SetRecovery { recovery: Authority::Key(recovery_key().public_key()) };
+            let recover = TxKind::Recover { authority: Authority::Key(rotated_key().public_key()) };
+            assert_eq!(
+                chain.check_transaction(&key_change(&chain, recover.clone(), &recovery_key())),
+                Err(TxError::NoRecovery(alice()))
+            );
+            chain.add_block(vec![key_change(&chain, set, &alice_key())], shard_id).unwrap();
+
+            // Once a recovery authority is set, only it may replace it; a leaked account key cannot.
+            let takeover = TxKind::SetRecovery { recovery: Authority::Key(alice_key().public_key()) };
+            assert_eq!(
+                chain.check_transaction(&key_change(&chain, takeover.clone(), &alice_key())),
+                Err(TxError::InvalidSignature)
+            );
+            assert_eq!(chain.check_transaction(&key_change(&chain, recover.clone(), &alice_key())), Err(TxError::InvalidSignature));
+
+            chain.add_block(vec![key_change(&chain, recover, &recovery_key())], shard_id).unwrap();
+            let record = chain.key_history(&alice()).unwrap().latest().unwrap();
+            assert_eq!(record.authority, Authority::Key(rotated_key().public_key()));
+            assert_eq!(record.recovery, Some(Authority::Key(recovery_key().public_key())));
+            assert!(chain.check_transaction(&pay_bob_with(&chain, &alice_key())).is_err());
+            assert_eq!(chain.check_transaction(&pay_bob_with(&chain, &rotated_key())), Ok(()));
+            assert_eq!(chain.check_transaction(&key_change(&chain, takeover, &recovery_key())), Ok(()));
+            assert_eq!(chain.verify_chain(), Ok(()));
+        }
+
+        #[test]
+        fn reorg_undoes_a_rotation() {
+            let (genesis, clock) = (alice_genesis(), genesis_clock());
+            let mut chain = chain_at(&genesis, &clock);
+            let mut rival = chain_at(&genesis, &clock);
+            let shard_id = chain.assign_shard(&alice());
+            chain.add_block(vec![key_change(&chain, rotate_to(&rotated_key()), &alice_key())], shard_id).unwrap();
+            chain.add_block(vec![pay_bob_with(&chain, &rotated_key())], shard_id).unwrap();
+
+            for _ in 0..3 {
+                chain.import_block(shard_id, rival.add_block(vec![], shard_id).unwrap()).unwrap();
+            }
+            assert_same_state(&chain, &rival);
+            assert_eq!(chain.key_history(&alice()).unwrap().records().len(), 1);
+            assert_eq!(chain.check_transaction(&pay_bob(&chain, 1)), Ok(()));
+            assert_eq!(chain.verify_chain(), Ok(()));
+        }
+
+        #[test]
+        fn transactions_belong_to_the_sender_shard() {
+            let chain = Blockchain::from_genesis(&alice_genesis()).unwrap();
+            let home = chain.assign_shard(&alice());
+            let other = chain.shards.iter().map(|shard| shard.id).find(|id| *id != home).unwrap();
+            let mut transaction = pay_bob(&chain, 10);
+            transaction.shard_id = other;
+            let transaction = signed(transaction, &alice_key());
+            assert_eq!(chain.check_transaction(&transaction), Err(TxError::WrongShard { expected: home, got: other }));
+        }
+
+        /// A chain on which Bob lives on another shard than Alice and starts with
+        /// `bob_custody` CustodyToken, and the ids of Alice's and Bob's shards.
+        fn cross_shard(bob_custody: Amount) -> (Blockchain, u64, u64) {
+            let mut genesis = alice_genesis();
+            genesis.accounts.push(GenesisAccount { address: bob(), custody: bob_custody, energy: Amount::ZERO });
+            let chain = chain_at(&genesis, &genesis_clock());
+            let (source, destination) = (chain.assign_shard(&alice()), chain.assign_shard(&bob()));
+            assert_ne!(source, destination, "Bob must live on another shard than Alice");
+            (chain, source, destination)
+        }
+
+        fn custody(chain: &Blockchain, address: &Address) -> Amount {
+            chain.state.balances(address).custody
+        }
+
+        fn tokens(tokens: u64) -> Amount {
+            Amount::from_tokens(tokens).unwrap()
+        }
+
+        /// A block on top of the head of `shard_id` recording `receipts` and nothing else.
+        fn receipt_block(chain: &Blockchain, shard_id: u64, receipts: Vec<ReceiptEntry>) -> Block {
+            let head = chain.shards.get(shard_id).unwrap().head();
+            Block::new(head.index + 1, head.timestamp + 1, vec![], receipts, head.hash, Some(shard_id), None)
+        }
+
+        #[test]
+        fn overspending_blocks_do_not_verify() {
+            let clock = genesis_clock();
+            let mut chain = chain_at(&alice_genesis(), &clock);
+            let shard_id = chain.assign_shard(&alice());
+            chain.add_block(vec![pay_bob(&chain, 60)], shard_id).unwrap();
+            assert_eq!(chain.verify_chain(), Ok(()));
+
+            // Pushed past `add_block`, which would refuse it: a second 60 tokens out of 40.
+            let head = chain.shards.get(shard_id).unwrap().head();
+            let overspend = Block::new(head.index + 1, head.timestamp + 1, vec![pay_bob(&chain, 60)], vec![], head.hash, Some(shard_id), None);
+            chain.shards.push_block(shard_id, overspend);
+            let error = TxError::InsufficientFunds { kind: TokenKind::Custody, needed: tokens(60), available: tokens(40) };
+            assert_eq!(
+                chain.verify_chain(),
+                Err(ChainError::InvalidBlock { shard_id, index: 2, reason: BlockError::InvalidTransaction { index: 0, error } })
+            );
+        }
+
+        #[test]
+        fn receipts_are_credited_once() {
+            let (mut chain, source, destination) = cross_shard(Amount::ZERO);
+            chain.add_block(vec![pay_bob(&chain, 10)], source).unwrap();
+            assert_eq!(custody(&chain, &alice()), tokens(90));
+
+            // Nothing is settled before the beacon chain commits the source block.
+            assert!(chain.add_block(vec![], destination).unwrap().receipts.is_empty());
+            chain.commit_beacon().unwrap();
+            let credit = chain.add_block(vec![], destination).unwrap();
+            assert_eq!(credit.receipts.len(), 1);
+            assert_eq!(credit.receipts[0].action, ReceiptAction::Credit);
+            assert_eq!(custody(&chain, &bob()), tokens(10));
+            assert!(!chain.has_due_receipts(destination));
+            assert!(chain.add_block(vec![], destination).unwrap().receipts.is_empty());
+
+            let twice = receipt_block(&chain, destination, credit.receipts.clone());
+            let reason = BlockError::InvalidReceipt { index: 0, error: ReceiptError::NotDue };
+            assert_eq!(
+                chain.import_block(destination, twice.clone()).unwrap_err(),
+                ChainError::InvalidBlock { shard_id: destination, index: twice.index, reason }
+            );
+
+            chain.commit_beacon().unwrap();
+            let refund = ReceiptEntry {
+                action: ReceiptAction::Refund,
+                beacon_height: chain.beacon_head().index,
+                ..credit.receipts[0].clone()
+            };
+            let refund = receipt_block(&chain, source, vec![refund]);
+            let reason = BlockError::InvalidReceipt { index: 0, error: ReceiptError::NotDue };
+            assert_eq!(
+                chain.import_block(source, refund.clone()).unwrap_err(),
+                ChainError::InvalidBlock { shard_id: source, index: refund.index, reason }
+            );
+            assert_eq!((custody(&chain, &alice()), custody(&chain, &bob())), (tokens(90), tokens(10)));
+            assert_eq!(chain.verify_chain(), Ok(()));
+
+            // A chain whose destination shard credits the receipt a second time does not verify.
+            chain.shards.push_block(destination, twice.clone());
+            let reason = BlockError::InvalidReceipt { index: 0, error: ReceiptError::AlreadySettled };
+            assert_eq!(
+                chain.verify_chain(),
+                Err(ChainError::InvalidBlock { shard_id: destination, index: twice.index, reason })
+            );
+        }
+
+        #[test]
+        fn rejected_receipts_are_refunded_once_the_rejection_is_committed() {
+            // Bob cannot hold any more CustodyToken, so his shard rejects the transfer.
+            let (mut chain, source, destination) = cross_shard(Amount::MAX);
+            chain.add_block(vec![pay_bob(&chain, 10)], source).unwrap();
+            chain.commit_beacon().unwrap();
+            let rejection = chain.add_block(vec![], destination).unwrap();
+            assert_eq!(rejection.receipts.len(), 1);
+            assert_eq!(rejection.receipts[0].action, ReceiptAction::Reject);
+            assert_eq!((custody(&chain, &alice()), custody(&chain, &bob())), (tokens(90), Amount::MAX));
+
+            assert!(!chain.has_due_receipts(source));
+            assert!(chain.add_block(vec![], source).unwrap().receipts.is_empty());
+            chain.commit_beacon().unwrap();
+            let refund = chain.add_block(vec![], source).unwrap();
+            assert_eq!(refund.receipts.len(), 1);
+            assert_eq!(refund.receipts[0].action, ReceiptAction::Refund);
+            assert_eq!(custody(&chain, &alice()), tokens(100));
+            assert!(chain.add_block(vec![], source).unwrap().receipts.is_empty());
+            assert_eq!(chain.verify_chain(), Ok(()));
+        }
+
+        #[test]
+        fn unsettled_receipts_are_refunded_after_the_timeout() {
+            let (mut chain, source, destination) = cross_shard(Amount::ZERO);
+            let paid = chain.add_block(vec![pay_bob(&chain, 10)], source).unwrap();
+            chain.commit_beacon().unwrap();
+            let deadline = paid.index + RECEIPT_TIMEOUT_BLOCKS;
+            while chain.shards.get(source).unwrap().height() < deadline {
+                assert!(chain.add_block(vec![], source).unwrap().receipts.is_empty());
+            }
+            assert!(chain.has_due_receipts(source));
+            let refund = chain.add_block(vec![], source).unwrap();
+            assert_eq!(refund.index, deadline + 1);
+            assert_eq!(refund.receipts.len(), 1);
+            assert_eq!(refund.receipts[0].action, ReceiptAction::Refund);
+            assert_eq!(custody(&chain, &alice()), tokens(100));
+
+            // The destination shard no longer credits a refunded receipt.
+            assert!(!chain.has_due_receipts(destination));
+            assert!(chain.add_block(vec![], destination).unwrap().receipts.is_empty());
+            assert_eq!(custody(&chain, &bob()), Amount::ZERO);
+            assert_eq!(chain.verify_chain(), Ok(()));
+        }
+
+        #[test]
+        fn signature_batches_report_the_failing_transaction() {
+            let batch = |workers: usize| {
+                let clock = genesis_clock();
+                let mut chain = chain_at(&alice_genesis(), &clock);
+                chain.verify_workers = workers;
+                let first = pay_bob(&chain, 1);
+                let mut transactions: Vec<Transaction> = (0..6)
+                    .map(|offset| {
+                        let mut transaction = first.clone();
+                        transaction.nonce += offset;
+                        signed(transaction, &alice_key())
+                    })
+                    .collect();
+                transactions[3].gas_limit += 1;
+                let index = chain.next_index(chain.assign_shard(&alice()));
+                chain.batch_verify_signatures(&transactions, index, |address| chain.key_record(address, index)).results
+            };
+            let parallel = batch(4);
+            let mut expected = vec![Ok(()); 6];
+            expected[3] = Err(TxError::InvalidSignature);
+            assert_eq!(parallel, expected);
+            assert_eq!(batch(1), parallel);
+            let report = SignatureBatch { results: parallel, authorities: HashMap::new() };
+            assert_eq!(report.into_result(), Err((3, TxError::InvalidSignature)));
+        }
+
+        #[test]
+        fn beacon_blocks_commit_shard_headers() {
+            let clock = genesis_clock();
+            let mut chain = chain_at(&GenesisConfig::default(), &clock);
+            for _ in 0..3 {
+                chain.add_block(vec![], 0).unwrap();
+            }
+            let beacon = chain.commit_beacon().unwrap();
+            chain.add_block(vec![], 0).unwrap();
+
+            assert_eq!(chain.committed_height(0, 0), Some(0));
+            assert_eq!(chain.committed_height(0, 3), Some(1));
+            assert_eq!(chain.committed_height(0, 4), None);
+            assert_eq!(chain.committed_height(1, 0), Some(0));
+            assert_eq!(chain.committed_height(1, 1), None);
+
+            // A light client holding only the beacon block checks blocks 1 and 2 of shard 0.
+            let headers = chain.shard_headers(0, 1, beacon.index).unwrap();
+            assert_eq!(headers.iter().map(|header| header.index).collect::<Vec<_>>(), vec![1, 2, 3]);
+            assert!(verify_shard_headers(&beacon, &headers));
+            assert!(verify_shard_headers(&beacon, &headers[1..]));
+
+            // Headers that stop short of, or run past, the committed head do not verify.
+            assert!(!verify_shard_headers(&beacon, &headers[..2]));
+            let past: Vec<BlockHeader> = chain.shards.get(0).unwrap().blocks[1..].iter().map(Block::header).collect();
+            assert!(!verify_shard_headers(&beacon, &past));
+            assert!(!verify_shard_headers(&beacon, &[]));
+            assert!(!verify_shard_headers(&chain.beacon[0], &headers));
+            assert_eq!(chain.shard_headers(0, 4, beacon.index), Some(vec![]));
+            assert_eq!(chain.shard_headers(0, 1, beacon.index + 1), None);
+            let mut tampered = headers.clone();
+            tampered[1].timestamp += 1;
+            assert!(!verify_shard_headers(&beacon, &tampered));
+        }
+
+        #[test]
+        fn beacon_blocks_must_link_to_their_parent() {
+            let clock = genesis_clock();
+            let mut chain = chain_at(&GenesisConfig::default(), &clock);
+            chain.add_block(vec![], 0).unwrap();
+            chain.commit_beacon().unwrap();
+            assert_eq!(chain.verify_chain(), Ok(()));
+
+            let expected = chain.beacon[0].hash;
+            let tampered = &mut chain.beacon[1];
+            tampered.previous_hash = Hash::ZERO;
+            tampered.hash = tampered.calculate_hash();
+            assert_eq!(
+                chain.verify_chain(),
+                Err(ChainError::InvalidBeacon {
+                    index: 1,
+                    reason: BeaconError::PreviousHashMismatch { expected, got: Hash::ZERO },
+                })
+            );
+        }
+
+        /// Alice's transfer of one CustodyToken to Bob with the given nonce.
+        fn pay_bob_nonce(chain: &Blockchain, nonce: u64) -> Transaction {
+            let token = Token::CustodyToken(Amount::from_tokens(1).unwrap());
+            TransactionBuilder::transfer(bob(), token).nonce(nonce).build(chain, &alice_key()).unwrap().0
+        }
+
+        #[test]
+        fn nonces_are_tracked_per_sender() {
+            let carol_key = SecretKey::from_bytes(SignatureScheme::Ed25519, &[3u8; 32]).unwrap();
+            let carol = Address::from_key(&carol_key.public_key());
+            let mut genesis = alice_genesis();
+            genesis.accounts.push(GenesisAccount { address: carol, custody: Amount::from_tokens(100).unwrap(), energy: Amount::ZERO });
+            genesis.public_keys.push(GenesisKey {
+                address: carol,
+                scheme: SignatureScheme::Ed25519,
+                public_key: hex::encode(carol_key.public_key().to_bytes()),
+            });
+            let clock = genesis_clock();
+            let mut chain = chain_at(&genesis, &clock);
+            let token = Token::CustodyToken(Amount::from_tokens(1).unwrap());
+            let pay = |chain: &Blockchain, nonce| TransactionBuilder::transfer(bob(), token.clone()).nonce(nonce).build(chain, &carol_key).unwrap().0;
+
+            chain.add_block(vec![pay_bob_nonce(&chain, 0), pay_bob_nonce(&chain, 1)], chain.assign_shard(&alice())).unwrap();
+            assert_eq!((chain.next_nonce(&alice()), chain.next_nonce(&carol)), (Some(2), Some(0)));
+            // Alice's nonces do not use up Carol's.
+            let carol_shard = chain.assign_shard(&carol);
+            chain.add_block(vec![pay(&chain, 0)], carol_shard).unwrap();
+            assert_eq!((chain.next_nonce(&alice()), chain.next_nonce(&carol)), (Some(2), Some(1)));
+            assert_eq!(
+                chain.add_block(vec![pay(&chain, 0)], carol_shard).unwrap_err(),
+                ChainError::InvalidTransaction { index: 0, error: TxError::NonceReuse(0) }
+            );
+        }
+
+        #[test]
+        fn nonce_policies_differ_only_on_gaps() {
+            for policy in [NoncePolicy::GapFree, NoncePolicy::Increasing] {
+                let clock = genesis_clock();
+                let mut chain = chain_at(&GenesisConfig { nonce_policy: policy, ..alice_genesis() }, &clock);
+                let shard_id = chain.assign_shard(&alice());
+                chain.add_block(vec![pay_bob_nonce(&chain, 0)], shard_id).unwrap();
+
+                let gap = chain.add_block(vec![pay_bob_nonce(&chain, 2)], shard_id);
+                match policy {
+                    NoncePolicy::GapFree => {
+                        let error = TxError::NonceGap { expected: 1, got: 2 };
+                        assert_eq!(gap.unwrap_err(), ChainError::InvalidTransaction { index: 0, error });
+                        assert_eq!(chain.next_nonce(&alice()), Some(1));
+                        chain.add_block(vec![pay_bob_nonce(&chain, 1), pay_bob_nonce(&chain, 2)], shard_id).unwrap();
+                    }
+                    NoncePolicy::Increasing => {
+                        gap.unwrap();
+                    }
+                }
+                assert_eq!(chain.next_nonce(&alice()), Some(3), "{:?}", policy);
+
+                // Reusing a nonce is refused either way, also within a block, and a refused
+                // block leaves the next nonce as it was.
+                for reused in [vec![pay_bob_nonce(&chain, 2)], vec![pay_bob_nonce(&chain, 3), pay_bob_nonce(&chain, 3)]] {
+                    let index = reused.len() - 1;
+                    let nonce = reused[index].nonce;
+                    assert_eq!(
+                        chain.add_block(reused, shard_id).unwrap_err(),
+                        ChainError::InvalidTransaction { index, error: TxError::NonceReuse(nonce) }
+                    );
+                    assert_eq!(chain.next_nonce(&alice()), Some(3), "{:?}", policy);
+                }
+            }
+        }
+
+        /// An Ed25519 key that no genesis registers.
+        pub(crate) fn dave_key() -> SecretKey {
+            SecretKey::from_bytes(SignatureScheme::Ed25519, &[4u8; 32]).unwrap()
+        }
+
+        pub(crate) fn dave() -> Address {
+            Address::from_key(&dave_key().public_key())
+        }
+
+        /// `alice_genesis` with 100 CustodyToken for Dave, whose key is left unregistered.
+        pub(crate) fn dave_genesis() -> GenesisConfig {
+            let mut genesis = alice_genesis();
+            genesis.accounts.push(GenesisAccount { address: dave(), custody: Amount::from_tokens(100).unwrap(), energy: Amount::ZERO });
+            genesis
+        }
+
+        /// The registration of `dave_key` followed by a transfer it signs, as the next
+        /// two transactions of Dave on `chain`.
+        pub(crate) fn register_and_pay(chain: &Blockchain) -> Vec<Transaction> {
+            let (register, _) = TransactionBuilder::register_key(&dave_key().public_key()).build(chain, &dave_key()).unwrap();
+            let token = Token::CustodyToken(Amount::from_tokens(1).unwrap());
+            let builder = TransactionBuilder::transfer(bob(), token).nonce(register.nonce + 1);
+            vec![register, builder.build(chain, &dave_key()).unwrap().0]
+        }
+
+        #[test]
+        fn registered_keys_sign_from_the_same_block() {
+            let clock = genesis_clock();
+            let mut chain = chain_at(&dave_genesis(), &clock);
+            let shard_id = chain.assign_shard(&dave());
+            let token = Token::CustodyToken(Amount::from_tokens(1).unwrap());
+            let (unregistered, _) = TransactionBuilder::transfer(bob(), token).build(&chain, &dave_key()).unwrap();
+            assert_eq!(chain.check_transaction(&unregistered), Err(TxError::UnknownSenderKey(dave())));
+
+            chain.add_block(register_and_pay(&chain), shard_id).unwrap();
+            let registered = chain.key_history(&dave()).and_then(KeyHistory::latest).map(|record| &record.authority);
+            assert_eq!(registered, Some(&Authority::Key(dave_key().public_key())));
+            assert_eq!(chain.state.balance(&dave(), TokenKind::Custody), Amount::from_tokens(99).unwrap());
+            assert_eq!(chain.next_nonce(&dave()), Some(2));
+
+            let (again, _) = TransactionBuilder::register_key(&dave_key().public_key()).build(&chain, &dave_key()).unwrap();
+            assert_eq!(
+                chain.add_block(vec![again], shard_id).unwrap_err(),
+                ChainError::InvalidTransaction { index: 0, error: TxError::KeyAlreadyRegistered(dave()) }
+            );
+            assert_eq!(chain.verify_chain(), Ok(()));
+        }
+
+        #[test]
+        fn registered_keys_must_derive_the_sender() {
+            let clock = genesis_clock();
+            let mut chain = chain_at(&dave_genesis(), &clock);
+            let other = SecretKey::from_bytes(SignatureScheme::Ed25519, &[6u8; 32]).unwrap();
+            let builder = TransactionBuilder::register_key(&other.public_key()).sender(dave());
+            let (register, _) = builder.build(&chain, &other).unwrap();
+            assert_eq!(
+                chain.add_block(vec![register], chain.assign_shard(&dave())).unwrap_err(),
+                ChainError::InvalidTransaction { index: 0, error: TxError::SenderKeyMismatch(dave()) }
+            );
+            assert_eq!(chain.key_history(&dave()), None);
+        }
+
+        #[test]
+        fn malformed_transactions_are_rejected() {
+            let clock = genesis_clock();
+            let mut chain = chain_at(&alice_genesis(), &clock);
+            let shard_id = chain.assign_shard(&alice());
+            let other_shard = chain.shards.iter().map(|shard| shard.id).find(|id| *id != shard_id).unwrap();
+            chain.add_block(vec![pay_bob(&chain, 1)], shard_id).unwrap();
+
+            type Malform = fn(&mut Transaction);
+            let cases: [(Malform, TxError); 6] = [
+                (|tx| tx.signature = "not hex".to_string(), TxError::BadSignatureHex),
+                (|tx| tx.signature = "3000".to_string(), TxError::MalformedSignature(SignatureScheme::P256)),
+                (|tx| tx.sender = dave(), TxError::UnknownSenderKey(dave())),
+                (
+                    |tx| {
+                        tx.gas_limit = 0;
+                        tx.sign(&alice_key());
+                    },
+                    TxError::ZeroGas,
+                ),
+                (
+                    |tx| {
+                        tx.token = Token::CustodyToken(Amount::ZERO);
+                        tx.sign(&alice_key());
+                    },
+                    TxError::NonPositiveAmount,
+                ),
+                (
+                    |tx| {
+                        tx.shard_id = if tx.shard_id == 0 { 1 } else { 0 };
+                        tx.sign(&alice_key());
+                    },
+                    TxError::WrongShard { expected: shard_id, got: other_shard },
+                ),
+            ];
+            for (malform, error) in cases {
+                let valid = pay_bob(&chain, 1);
+                let mut malformed = valid.clone();
+                malformed.nonce += 1;
+                malformed.sign(&alice_key());
+                malform(&mut malformed);
+
+                let (head, nonces, state) = (chain.shards.get(shard_id).unwrap().head().hash, chain.nonces.clone(), chain.state.clone());
+                assert_eq!(
+                    chain.add_block(vec![valid, malformed], shard_id).unwrap_err(),
+                    ChainError::InvalidTransaction { index: 1, error }
+                );
+                assert_eq!(chain.shards.get(shard_id).unwrap().head().hash, head);
+                assert_eq!((&chain.nonces, &chain.state), (&nonces, &state));
+            }
+        }
+    }
+}
+
+pub mod state {
+    use super::*;
+    use crate::blockchain::{Token, TokenKind, TxError};
+    use crate::amount::Amount;
+    use serde::{Serialize, Deserialize};
+    use std::collections::HashMap;
+
+    /// Token balances held by a single account.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct Balances {
+        pub custody: Amount,
+        pub energy: Amount,
+    }
+
+    impl Balances {
+        pub fn get(&self, kind: TokenKind) -> Amount {
+            match kind {
+                TokenKind::Custody => self.custody,
+                TokenKind::Energy => self.energy,
+            }
+        }
+
+        fn get_mut(&mut self, kind: TokenKind) -> &mut Amount {
+            match kind {
+                TokenKind::Custody => &mut self.custody,
+                TokenKind::Energy => &mut self.energy,
+            }
+        }
+    }
+
+    /// Committed balances of every account, keyed by address.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+    pub struct AccountState {
+        accounts: HashMap<Address, Balances>,
+    }
+
+    impl AccountState {
+        pub fn balances(&self, address: &Address) -> Balances {
+            self.accounts.get(address).copied().unwrap_or_default()
+        }
+
+        pub fn balance(&self, address: &Address, kind: TokenKind) -> Amount {
+            self.balances(address).get(kind)
+        }
+
+        /// Adds `token` to the balance of `address` outside of any transaction, e.g. to fund
+        /// accounts before the first block.
+        pub fn credit(&mut self, address: &Address, token: &Token) -> Result<(), TxError> {
+            let balances = self.accounts.entry(*address).or_default();
+            let balance = balances.get_mut(token.kind());
+            *balance = balance
+                .checked_add(token.amount())
+                .ok_or(TxError::BalanceOverflow(*address))?;
+            Ok(())
+        }
+
+        pub fn accounts(&self) -> impl Iterator<Item = (&Address, &Balances)> {
+            self.accounts.iter()
+        }
+
+        /// Balances of `address`, or `None` if it has never held any tokens.
+        pub fn account(&self, address: &Address) -> Option<Balances> {
+            self.accounts.get(address).copied()
+        }
+
+        /// Puts back balances saved with `account`, e.g. when a block is rolled back.
+        pub fn restore(&mut self, address: &Address, balances: Option<Balances>) {
+            match balances {
+                Some(balances) => self.accounts.insert(*address, balances),
+                None => self.accounts.remove(address),
+            };
+        }
+
+        /// Commits the balances touched by an accepted block.
+        pub fn apply(&mut self, changes: StateChanges) {
+            self.accounts.extend(changes.accounts);
+        }
+    }
+
+    /// Balances touched by the block being validated, layered over the committed
+    /// `AccountState` so that a rejected block can simply be dropped.
+    #[derive(Debug, Default)]
+    pub struct StateChanges {
+        accounts: HashMap<Address, Balances>,
+    }
+
+    impl StateChanges {
+        fn balances(&self, base: &AccountState, address: &Address) -> Balances {
+            self.accounts.get(address).copied().unwrap_or_else(|| base.balances(address))
+        }
+
+        /// The accounts whose balances these changes set.
+        pub fn addresses(&self) -> impl Iterator<Item = &Address> {
+            self.accounts.keys()
+        }
+
+        /// Removes `token` from the balance of `address`, failing if it cannot cover it.
+        pub fn debit(&mut self, base: &AccountState, address: &Address, token: &Token) -> Result<(), TxError> {
+            let kind = token.kind();
+            let amount = token.amount();
+            let mut balances = self.balances(base, address);
+            let available = balances.get(kind);
+            *balances.get_mut(kind) = available
+                .checked_sub(amount)
+                .ok_or(TxError::InsufficientFunds { kind, needed: amount, available })?;
+            self.accounts.insert(*address, balances);
+            Ok(())
+        }
+
+        /// Adds `token` to the balance of `address`, failing if it would overflow.
+        pub fn credit(&mut self, base: &AccountState, address: &Address, token: &Token) -> Result<(), TxError> {
+            let mut balances = self.balances(base, address);
+            let balance = balances.get_mut(token.kind());
+            *balance = balance
+                .checked_add(token.amount())
+                .ok_or(TxError::BalanceOverflow(*address))?;
+            self.accounts.insert(*address, balances);
+            Ok(())
+        }
+
+        /// Moves `token` from `sender` to `receiver`, failing if the sender cannot cover it.
+        pub fn transfer(&mut self, base: &AccountState, sender: &Address, receiver: &Address, token: &Token) -> Result<(), TxError> {
+            let kind = token.kind();
+            let amount = token.amount();
+
+            let mut from = self.balances(base, sender);
+            let available = from.get(kind);
+            *from.get_mut(kind) = available
+                .checked_sub(amount)
+                .ok_or(TxError::InsufficientFunds { kind, needed: amount, available })?;
+
+            let mut to = if receiver == sender { from } else { self.balances(base, receiver) };
+            let balance = to.get_mut(kind);
+            *balance = balance
+                .checked_add(amount)
+                .ok_or(TxError::BalanceOverflow(*receiver))?;
+
+            // Nothing is recorded until both sides are known to succeed.
+            self.accounts.insert(*sender, from);
+            self.accounts.insert(*receiver, to);
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::blockchain::tests::{alice, alice_genesis, alice_key, bob, chain_at, genesis_clock, pay_bob};
+        use crate::blockchain::ChainError;
+
+        fn custody(tokens: u64) -> Token {
+            Token::CustodyToken(Amount::from_tokens(tokens).unwrap())
+        }
+
+        fn funded() -> AccountState {
+            let mut state = AccountState::default();
+            state.credit(&alice(), &custody(10)).unwrap();
+            state
+        }
+
+        #[test]
+        fn debits_and_credits_move_balances() {
+            let state = funded();
+            let mut changes = StateChanges::default();
+            changes.debit(&state, &alice(), &custody(3)).unwrap();
+            changes.credit(&state, &bob(), &custody(3)).unwrap();
+            changes.transfer(&state, &alice(), &bob(), &custody(2)).unwrap();
+            let energy = Token::EnergyToken(Amount::from_tokens(1).unwrap());
+            changes.credit(&state, &bob(), &energy).unwrap();
+
+            let mut committed = state.clone();
+            committed.apply(changes);
+            assert_eq!(committed.balance(&alice(), TokenKind::Custody), Amount::from_tokens(5).unwrap());
+            assert_eq!(
+                committed.balances(&bob()),
+                Balances { custody: Amount::from_tokens(5).unwrap(), energy: Amount::from_tokens(1).unwrap() }
+            );
+        }
+
+        #[test]
+        fn overdrafts_and_overflows_are_refused() {
+            let state = funded();
+            let mut changes = StateChanges::default();
+            changes.debit(&state, &alice(), &custody(4)).unwrap();
+            let overspend = TxError::InsufficientFunds {
+                kind: TokenKind::Custody,
+                needed: Amount::from_tokens(7).unwrap(),
+                available: Amount::from_tokens(6).unwrap(),
+            };
+            assert_eq!(changes.debit(&state, &alice(), &custody(7)), Err(overspend.clone()));
+            assert_eq!(changes.transfer(&state, &alice(), &bob(), &custody(7)), Err(overspend));
+            // A failed transfer changes neither side.
+            assert_eq!(changes.addresses().collect::<Vec<_>>(), vec![&alice()]);
+
+            let max = Token::CustodyToken(Amount::MAX);
+            let mut full = AccountState::default();
+            full.credit(&bob(), &max).unwrap();
+            assert_eq!(full.credit(&bob(), &custody(1)), Err(TxError::BalanceOverflow(bob())));
+            assert_eq!(changes.transfer(&full, &alice(), &bob(), &custody(1)), Err(TxError::BalanceOverflow(bob())));
+            assert_eq!(changes.balances(&full, &alice()).custody, Amount::from_tokens(6).unwrap());
+        }
+
+        #[test]
+        fn changes_only_count_once_applied() {
+            let mut state = funded();
+            let before = state.clone();
+            let mut changes = StateChanges::default();
+            changes.transfer(&state, &alice(), &bob(), &custody(10)).unwrap();
+            assert_eq!(state, before);
+            drop(changes);
+            assert_eq!(state, before);
+
+            let saved = state.account(&bob());
+            let mut changes = StateChanges::default();
+            changes.transfer(&state, &alice(), &bob(), &custody(10)).unwrap();
+            state.apply(changes);
+            assert!(state.balances(&alice()).custody.is_zero());
+            state.restore(&bob(), saved);
+            assert_eq!(state.account(&bob()), None);
+        }
+
+        #[test]
+        fn rejected_blocks_leave_balances_untouched() {
+            let clock = genesis_clock();
+            let mut chain = chain_at(&alice_genesis(), &clock);
+            let shard_id = chain.assign_shard(&alice());
+            let before = chain.state.clone();
+            assert_eq!(before.balance(&alice(), TokenKind::Custody), Amount::from_tokens(100).unwrap());
+
+            // The first transfer alone is affordable, but not together with the second.
+            let first = pay_bob(&chain, 60);
+            let mut second = pay_bob(&chain, 60);
+            second.nonce = first.nonce + 1;
+            second.sign(&alice_key());
+            let error = TxError::InsufficientFunds {
+                kind: TokenKind::Custody,
+                needed: Amount::from_tokens(60).unwrap(),
+                available: Amount::from_tokens(40).unwrap(),
+            };
+            assert_eq!(
+                chain.add_block(vec![first, second], shard_id).unwrap_err(),
+                ChainError::InvalidTransaction { index: 1, error }
+            );
+            assert_eq!(chain.state, before);
+
+            chain.add_block(vec![pay_bob(&chain, 60)], shard_id).unwrap();
+            assert_eq!(chain.state.balance(&alice(), TokenKind::Custody), Amount::from_tokens(40).unwrap());
+        }
+    }
+}
+
+pub mod receipts {
+    //! The two halves of a cross-shard transfer. The source shard debits the sender and
+    //! leaves a `Receipt`; a later block on the destination shard credits the receiver,
+    //! or rejects the receipt so that the source shard refunds the sender.
+
+    use super::*;
+    use crate::blockchain::{BlockHeader, Token, Transaction, verify_inclusion};
+    use crate::encoding::Encoder;
+    use crate::hash::Hash;
+    use crate::merkle::MerkleProof;
+    use serde::{Serialize, Deserialize};
+    use std::fmt;
+
+    /// Domain tag that starts every receipt entry encoding.
+    pub const RECEIPT_DOMAIN: &[u8] = b"CRAWCHAIN/RECEIPT";
+    /// Version of the receipt entry encoding.
+    pub const RECEIPT_ENCODING_VERSION: u8 = 3;
+    /// Source-shard blocks after which an unsettled receipt is refunded.
+    pub const RECEIPT_TIMEOUT_BLOCKS: u64 = 16;
+
+    /// A transfer debited on its source shard and owed to a receiver on another shard.
+    /// Identified by the id of the transaction that created it.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct Receipt {
+        pub tx_hash: Hash,
+        pub source_shard: u64,
+        pub source_index: u64, // Index of the source block that includes the transaction
+        pub destination_shard: u64,
+        pub sender: Address,
+        pub receiver: Address,
+        pub token: Token,
+    }
+
+    impl Receipt {
+        pub fn new(transaction: &Transaction, source_index: u64, destination_shard: u64) -> Self {
+            Receipt {
+                tx_hash: transaction.hash(),
+                source_shard: transaction.shard_id,
+                source_index,
+                destination_shard,
+                sender: transaction.sender,
+                receiver: transaction.receiver,
+                token: transaction.token.clone(),
+            }
+        }
+
+        /// The last source-shard block index at which the receipt cannot be refunded
+        /// without a rejection.
+        pub fn deadline(&self) -> u64 {
+            self.source_index.saturating_add(RECEIPT_TIMEOUT_BLOCKS)
+        }
+
+        /// Checks, using only the source block's header, that the receipt's transaction is
+        /// included in that block.
+        pub fn verify(&self, source: &BlockHeader, proof: &MerkleProof) -> bool {
+            source.shard_id == Some(self.source_shard)
+                && source.index == self.source_index
+                && verify_inclusion(source, &self.tx_hash, proof)
+        }
+    }
+
+    /// What a block did with a receipt.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ReceiptAction {
+        /// The destination shard credited the receiver.
+        Credit,
+        /// The destination shard could not credit the receiver.
+        Reject,
+        /// The source shard paid the amount back to the sender.
+        Refund,
+    }
+
+    impl ReceiptAction {
+        /// Tag identifying the action in canonical encodings.
+        pub fn tag(self) -> u8 {
+            match self {
+                ReceiptAction::Credit => 0,
+                ReceiptAction::Reject => 1,
+                ReceiptAction::Refund => 2,
+            }
+        }
+
+        /// The status a receipt ends up in after this action.
+        pub fn status(self) -> ReceiptStatus {
+            match self {
+                ReceiptAction::Credit => ReceiptStatus::Credited,
+                ReceiptAction::Reject => ReceiptStatus::Rejected,
+                ReceiptAction::Refund => ReceiptStatus::Refunded,
+            }
+        }
+    }
+
+    /// A receipt settled by a block, with the beacon height that committed the source
+    /// block and the proof that its transaction is included in that block.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct ReceiptEntry {
+        pub receipt: Receipt,
+        pub action: ReceiptAction,
+        pub beacon_height: u64,
+        pub proof: MerkleProof,
+    }
+
+    impl ReceiptEntry {
+        /// SHA-256 of the canonical entry encoding described in `docs/hashing.md`. The
+        /// proof is not covered; it is checked against the source block instead.
+        pub fn hash(&self) -> Hash {
+            let receipt = &self.receipt;
+            let mut encoder = Encoder::new();
+            encoder
+                .bytes(RECEIPT_DOMAIN)
+                .u8(RECEIPT_ENCODING_VERSION)
+                .raw(receipt.tx_hash.as_bytes())
+                .u64(receipt.source_shard)
+                .u64(receipt.source_index)
+                .u64(receipt.destination_shard)
+                .raw(receipt.sender.as_bytes())
+                .raw(receipt.receiver.as_bytes())
+                .u8(receipt.token.kind().tag())
+                .u64(receipt.token.amount().base_units())
+                .u8(self.action.tag())
+                .u64(self.beacon_height);
+            Hash::digest(&encoder.finish())
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ReceiptStatus {
+        Pending,
+        Credited,
+        Rejected,
+        Refunded,
+    }
+
+    /// A receipt known to the chain and how far it has been settled.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct ReceiptRecord {
+        pub receipt: Receipt,
+        pub status: ReceiptStatus,
+        pub settled_at: Option<u64>, // Index of the block that last settled it, on the shard that did
+    }
+
+    /// Why a receipt entry in a block is invalid.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum ReceiptError {
+        WrongShard { expected: u64, got: u64 },
+        UnknownSource,
+        InvalidProof,
+        Mismatch,
+        NotCommitted,
+        NotDue,
+        AlreadySettled,
+        NotRefundable,
+    }
+
+    impl fmt::Display for ReceiptError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                ReceiptError::WrongShard { expected, got } => write!(f, "settled on shard {}, not {}", got, expected),
+                ReceiptError::UnknownSource => write!(f, "source block does not exist"),
+                ReceiptError::InvalidProof => write!(f, "inclusion proof does not match the source block"),
+                ReceiptError::Mismatch => write!(f, "receipt does not match its cross-shard transaction"),
+                ReceiptError::NotCommitted => {
+                    write!(f, "source block is not committed by the referenced beacon block before settlement")
+                }
+                ReceiptError::NotDue => write!(f, "receipt is not due for this action in this block"),
+                ReceiptError::AlreadySettled => write!(f, "receipt has already been settled"),
+                ReceiptError::NotRefundable => write!(f, "receipt was refunded before it was rejected or timed out"),
+            }
+        }
+    }
+
+    impl std::error::Error for ReceiptError {}
+}
+
+pub mod fork {
+    //! Fork choice rules: how a shard picks its canonical head among competing branches.
+
+    use super::*;
+    use crate::amount::Amount;
+    use crate::blockchain::Block;
+    use std::collections::{HashMap, HashSet};
+    use std::fmt;
+
+    /// Scores a branch; the branch with the highest score becomes the shard's head.
+    pub trait ForkChoice: fmt::Debug {
+        /// `branch` runs from the genesis block to the candidate head, and `stakes` are the
+        /// validators' current stakes.
+        fn score(&self, branch: &[&Block], stakes: &HashMap<Address, Amount>) -> u128;
+    }
+
+    fn stake_of(block: &Block, stakes: &HashMap<Address, Amount>) -> u128 {
+        block
+            .proposer
+            .as_ref()
+            .and_then(|proposer| stakes.get(proposer))
+            .map_or(0, |stake| stake.base_units() as u128)
+    }
+
+    /// Prefers the branch with the most blocks.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct LongestChain;
+
+    impl ForkChoice for LongestChain {
+        fn score(&self, branch: &[&Block], _stakes: &HashMap<Address, Amount>) -> u128 {
+            branch.len() as u128
+        }
+    }
+
+    /// Prefers the branch whose blocks were proposed by the most stake in total.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct HeaviestStake;
+
+    impl ForkChoice for HeaviestStake {
+        fn score(&self, branch: &[&Block], stakes: &HashMap<Address, Amount>) -> u128 {
+            branch.iter().map(|block| stake_of(block, stakes)).sum()
+        }
+    }
+
+    /// Prefers the branch with the highest stake-finalized block, then the longest one.
+    /// A block is stake-finalized on a branch once validators holding at least two thirds
+    /// of all stake have proposed blocks on top of it.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct FinalizedFirst;
+
+    impl FinalizedFirst {
+        /// Index of the highest stake-finalized block of `branch`, plus one; zero if none.
+        fn finalized_height(branch: &[&Block], stakes: &HashMap<Address, Amount>) -> u64 {
+            let total: u128 = stakes.values().map(|stake| stake.base_units() as u128).sum();
+            if total == 0 {
+                return 0;
+            }
+            let mut proposers = HashSet::new();
+            let mut confirming = 0u128;
+            for block in branch.iter().rev() {
+                if confirming * 3 >= total * 2 {
+                    return block.index + 1;
+                }
+                if let Some(proposer) = &block.proposer {
+                    if proposers.insert(proposer) {
+                        confirming += stake_of(block, stakes);
+                    }
+                }
+            }
+            0
+        }
+    }
+
+    impl ForkChoice for FinalizedFirst {
+        fn score(&self, branch: &[&Block], stakes: &HashMap<Address, Amount>) -> u128 {
+            ((Self::finalized_height(branch, stakes) as u128) << 64) | branch.len() as u128
+        }
+    }
+}
+
+pub mod beacon {
+    //! The beacon chain ties the shards together. Each beacon block commits the head of
+    //! every shard, which gives the shards a single global order and lets a light client
+    //! follow the beacon chain alone to check headers of any shard.
+
+    use crate::blockchain::BlockHeader;
+    use crate::encoding::Encoder;
+    use crate::hash::Hash;
+    use serde::{Serialize, Deserialize};
+    use std::fmt;
+
+    /// Domain tag that starts every beacon block encoding.
+    pub const BEACON_DOMAIN: &[u8] = b"CRAWCHAIN/BEACON";
+    /// Version of the beacon block encoding.
+    pub const BEACON_ENCODING_VERSION: u8 = 2;
+
+    /// The latest block of a shard as committed by a beacon block.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct ShardHead {
+        pub shard_id: u64,
+        pub index: u64,
+        pub hash: Hash,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct BeaconBlock {
+        pub index: u64,
+        pub timestamp: u64, // Unix time in milliseconds
+        pub shard_heads: Vec<ShardHead>, // One per shard, by shard id
+        pub previous_hash: Hash,
+        pub hash: Hash,
+    }
+
+    impl BeaconBlock {
+        pub fn new(index: u64, timestamp: u64, shard_heads: Vec<ShardHead>, previous_hash: Hash) -> Self {
+            let mut block = BeaconBlock {
+                index,
+                timestamp,
+                shard_heads,
+                previous_hash,
+                hash: Hash::ZERO,
+            };
+            block.hash = block.calculate_hash();
+            block
+        }
+
+        /// SHA-256 of the canonical beacon block encoding described in `docs/hashing.md`.
+        pub fn calculate_hash(&self) -> Hash {
+            let mut encoder = Encoder::new();
+            encoder
+                .bytes(BEACON_DOMAIN)
+                .u8(BEACON_ENCODING_VERSION)
+                .u64(self.index)
+                .u64(self.timestamp)
+                .raw(self.previous_hash.as_bytes())
+                .u32(self.shard_heads.len() as u32);
+            for head in &self.shard_heads {
+                encoder.u64(head.shard_id).u64(head.index).raw(head.hash.as_bytes());
+            }
+            Hash::digest(&encoder.finish())
+        }
+
+        pub fn shard_head(&self, shard_id: u64) -> Option<&ShardHead> {
+            self.shard_heads.iter().find(|head| head.shard_id == shard_id)
+        }
+
+        /// Whether block `index` of `shard_id` is part of the history this block commits.
+        pub fn commits(&self, shard_id: u64, index: u64) -> bool {
+            self.shard_head(shard_id).is_some_and(|head| index <= head.index)
+        }
+    }
+
+    /// Checks that `headers` are consecutive headers of one shard, the last of which is
+    /// the head that `beacon` commits for that shard. The first header is then part of the
+    /// committed history, without trusting anything but the beacon block.
+    pub fn verify_shard_headers(beacon: &BeaconBlock, headers: &[BlockHeader]) -> bool {
+        let Some(last) = headers.last() else {
+            return false;
+        };
+        let Some(head) = last.shard_id.and_then(|shard_id| beacon.shard_head(shard_id)) else {
+            return false;
+        };
+        last.index == head.index
+            && last.calculate_hash() == head.hash
+            && headers.windows(2).all(|pair| {
+                pair[1].shard_id == pair[0].shard_id
+                    && pair[1].index == pair[0].index + 1
+                    && pair[1].previous_hash == pair[0].calculate_hash()
+            })
+    }
+
+    /// Why `Blockchain::verify_chain` considers a beacon block invalid.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum BeaconError {
+        MissingGenesis,
+        IndexMismatch { expected: u64, got: u64 },
+        PreviousHashMismatch { expected: Hash, got: Hash },
+        HashMismatch { expected: Hash, got: Hash },
+        TimestampNotIncreasing { previous: u64, got: u64 },
+        ShardCountMismatch { expected: usize, got: usize },
+        UnknownHead { shard_id: u64 },
+        HeadRegressed { shard_id: u64 },
+    }
+
+    impl fmt::Display for BeaconError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                BeaconError::MissingGenesis => write!(f, "beacon chain has no genesis block"),
+                BeaconError::IndexMismatch { expected, got } => write!(f, "expected index {}, got {}", expected, got),
+                BeaconError::PreviousHashMismatch { expected, got } => {
+                    write!(f, "previous_hash is {}, but the previous block hashes to {}", got, expected)
+                }
+                BeaconError::HashMismatch { expected, got } => write!(f, "hash is {}, but the block hashes to {}", got, expected),
+                BeaconError::TimestampNotIncreasing { previous, got } => {
+                    write!(f, "timestamp {} is not after the previous block's {}", got, previous)
+                }
+                BeaconError::ShardCountMismatch { expected, got } => {
+                    write!(f, "commits {} shard heads, but the chain has {} shards", got, expected)
+                }
+                BeaconError::UnknownHead { shard_id } => write!(f, "head of shard {} is not a block of that shard", shard_id),
+                BeaconError::HeadRegressed { shard_id } => write!(f, "head of shard {} is behind the previous beacon block", shard_id),
+            }
+        }
+    }
+
+    impl std::error::Error for BeaconError {}
+}
+
+pub mod shard {
+    use crate::address::Address;
+    use crate::blockchain::Block;
+    use crate::hash::Hash;
+    use serde::{Serialize, Deserialize};
+    use std::collections::{HashMap, HashSet};
+
+    /// Id of the shard recording custody locks in the default topology.
+    pub const LOCK_SHARD: u64 = 0;
+    /// Id of the shard recording virtual power plant activity in the default topology.
+    pub const VPP_SHARD: u64 = 1;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ShardPurpose {
+        Lock,
+        Vpp,
+        General,
+    }
+
+    impl ShardPurpose {
+        /// Tag identifying the purpose in canonical encodings.
+        pub fn tag(self) -> u8 {
+            match self {
+                ShardPurpose::Lock => 0,
+                ShardPurpose::Vpp => 1,
+                ShardPurpose::General => 2,
+            }
+        }
+    }
+
+    /// Metadata of a shard, fixed at genesis.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct ShardInfo {
+        pub name: String,
+        pub purpose: ShardPurpose,
+    }
+
+    /// The lock and VPP shards, in that order.
+    pub fn default_shards() -> Vec<ShardInfo> {
+        vec![
+            ShardInfo {
+                name: "lock".to_string(),
+                purpose: ShardPurpose::Lock,
+            },
+            ShardInfo {
+                name: "vpp".to_string(),
+                purpose: ShardPurpose::Vpp,
+            },
+        ]
+    }
+
+    /// One shard's block tree: the canonical chain, starting with its genesis block, and
+    /// the known blocks on competing branches.
+    #[derive(Debug, Clone)]
+    pub struct Shard {
+        pub id: u64,
+        pub info: ShardInfo,
+        pub blocks: Vec<Block>,
+        pub forks: HashMap<Hash, Block>, // Known blocks that are not canonical, by hash
+        canonical: HashMap<Hash, u64>,   // Indexes of the canonical blocks, by hash
+    }
+
+    impl Shard {
+        pub fn new(id: u64, info: ShardInfo, blocks: Vec<Block>) -> Self {
+            let canonical = blocks.iter().map(|block| (block.hash, block.index)).collect();
+            Shard {
+                id,
+                info,
+                blocks,
+                forks: HashMap::new(),
+                canonical,
+            }
+        }
+
+        fn push(&mut self, block: Block) {
+            self.canonical.insert(block.hash, block.index);
+            self.blocks.push(block);
+        }
+
+        fn pop(&mut self) -> Option<Block> {
+            let block = self.blocks.pop()?;
+            self.canonical.remove(&block.hash);
+            Some(block)
+        }
+
+        pub fn is_canonical(&self, block: &Block) -> bool {
+            self.blocks.get(block.index as usize).is_some_and(|canonical| canonical.hash == block.hash)
+        }
+
+        /// A known block, canonical or not.
+        pub fn block(&self, hash: &Hash) -> Option<&Block> {
+            self.forks.get(hash).or_else(|| {
+                let index = *self.canonical.get(hash)?;
+                self.blocks.get(usize::try_from(index).ok()?)
+            })
+        }
+
+        pub fn contains(&self, hash: &Hash) -> bool {
+            self.block(hash).is_some()
+        }
+
+        /// Fork blocks without known children.
+        pub fn fork_tips(&self) -> Vec<Hash> {
+            let parents: HashSet<Hash> = self.forks.values().map(|block| block.previous_hash).collect();
+            let mut tips: Vec<Hash> = self.forks.keys().filter(|hash| !parents.contains(hash)).copied().collect();
+            tips.sort();
+            tips
+        }
+
+        /// The blocks from genesis up to the known block `tip`, in order.
+        pub fn branch(&self, tip: &Hash) -> Option<Vec<&Block>> {
+            let mut forked = Vec::new();
+            let mut block = self.block(tip)?;
+            while !self.is_canonical(block) {
+                forked.push(block);
+                block = self.block(&block.previous_hash)?;
+            }
+            let mut branch: Vec<&Block> = self.blocks[..=block.index as usize].iter().collect();
+            branch.extend(forked.into_iter().rev());
+            Some(branch)
+        }
+
+        /// Drops forks that branch off the canonical chain at or below `index`, and forks
+        /// whose ancestry is unknown.
+        pub fn prune_forks(&mut self, index: u64) {
+            loop {
+                let stale: Vec<Hash> = self
+                    .forks
+                    .values()
+                    .filter(|block| match self.block(&block.previous_hash) {
+                        Some(parent) => self.is_canonical(parent) && parent.index < index,
+                        None => true,
+                    })
+                    .map(|block| block.hash)
+                    .collect();
+                if stale.is_empty() {
+                    break;
+                }
+                for hash in stale {
+                    self.forks.remove(&hash);
+                }
+            }
+        }
+
+        /// Drops fork blocks whose ancestry is no longer known, such as the descendants of
+        /// a fork block that failed validation and was discarded.
+        pub fn prune_orphans(&mut self) {
+            self.prune_forks(0);
+        }
+
+        /// The latest block. Every shard has at least its genesis block.
+        pub fn head(&self) -> &Block {
+            self.blocks.last().expect("a shard always has a genesis block")
+        }
+
+        pub fn height(&self) -> u64 {
+            self.head().index
+        }
+    }
+
+    /// All shards of a chain, addressed by id. Ids run from 0 to `len() - 1`, and there is
+    /// always at least one.
+    #[derive(Debug, Clone)]
+    pub struct ShardRegistry {
+        shards: Vec<Shard>,
+    }
+
+    impl ShardRegistry {
+        /// The registry of `shards`, or `None` if there are none for addresses to live on.
+        pub fn new(shards: Vec<Shard>) -> Option<Self> {
+            (!shards.is_empty()).then_some(ShardRegistry { shards })
+        }
+
+        pub fn len(&self) -> usize {
+            self.shards.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.shards.is_empty()
+        }
+
+        pub fn get(&self, shard_id: u64) -> Option<&Shard> {
+            self.shards.get(usize::try_from(shard_id).ok()?)
+        }
+
+        pub fn iter(&self) -> impl Iterator<Item = &Shard> {
+            self.shards.iter()
+        }
+
+        pub(crate) fn get_mut(&mut self, shard_id: u64) -> Option<&mut Shard> {
+            self.shards.get_mut(usize::try_from(shard_id).ok()?)
+        }
+
+        /// The shard an address lives on: the first 8 bytes of `SHA-256(address)`, read as
+        /// a little-endian integer, modulo the number of shards.
+        pub fn assign(&self, address: &Address) -> u64 {
+            let hash = Hash::digest(address.as_bytes());
+            let prefix = u64::from_le_bytes(hash.0[..8].try_into().expect("8-byte slice"));
+            prefix % self.shards.len() as u64
+        }
+
+        pub(crate) fn push_block(&mut self, shard_id: u64, block: Block) {
+            if let Some(shard) = self.shards.get_mut(shard_id as usize) {
+                shard.push(block);
+            }
+        }
+
+        pub(crate) fn pop_block(&mut self, shard_id: u64) -> Option<Block> {
+            let shard = self.shards.get_mut(shard_id as usize)?;
+            // The genesis block stays.
+            if shard.blocks.len() > 1 {
+                shard.pop()
+            } else {
+                None
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn registry(count: u64) -> Option<ShardRegistry> {
+            let info = ShardInfo { name: "general".to_string(), purpose: ShardPurpose::General };
+            ShardRegistry::new((0..count).map(|id| Shard::new(id, info.clone(), Vec::new())).collect())
+        }
+
+        #[test]
+        fn every_address_has_a_shard() {
+            assert!(registry(0).is_none());
+            let address: Address = "crwbbb47e396351524a1298a3b6d355f224ec3d9e022642a3b8".parse().unwrap();
+            assert_eq!(registry(1).unwrap().assign(&address), 0);
+            for count in 2..8 {
+                assert!(registry(count).unwrap().assign(&address) < count);
+            }
+        }
+    }
+}
+
+pub mod genesis {
+    use super::*;
+    use crate::amount::{self, Amount};
+    use crate::blockchain::NoncePolicy;
+    use crate::encoding::Encoder;
+    use crate::hash::Hash;
+    use crate::shard::{self, ShardInfo};
+    use crate::crypto::{PublicKey, SignatureScheme};
+    use serde::{Serialize, Deserialize};
+    use std::collections::HashSet;
+    use std::fmt;
+    use std::path::Path;
+
+    /// Domain tag that starts the genesis configuration encoding.
+    pub const GENESIS_DOMAIN: &[u8] = b"CRAWCHAIN/GENESIS";
+    /// Version of the genesis configuration encoding.
+    pub const GENESIS_ENCODING_VERSION: u8 = 5;
+
+    /// Chain id of `GenesisConfig::default()`.
+    pub const DEFAULT_CHAIN_ID: u64 = 1;
+
+    /// The initial state of a chain, usually loaded from a JSON file (see
+    /// `genesis.example.json`). Nodes started from the same configuration agree on
+    /// `hash()` and therefore on the genesis blocks.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct GenesisConfig {
+        pub chain_id: u64,
+        pub genesis_time: String, // RFC 3339; the genesis blocks are stamped with it in unix milliseconds
+        #[serde(default = "default_nonce_policy")]
+        pub nonce_policy: NoncePolicy,
+        #[serde(default = "shard::default_shards")]
+        pub shards: Vec<ShardInfo>, // Shard `i` of the chain is described by entry `i`
+        #[serde(default)]
+        pub validators: Vec<GenesisValidator>,
+        #[serde(default)]
+        pub accounts: Vec<GenesisAccount>,
+        #[serde(default)]
+        pub public_keys: Vec<GenesisKey>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct GenesisValidator {
+        pub address: Address,
+        #[serde(with = "amount::decimal")]
+        pub stake: Amount,
+        #[serde(default)]
+        pub scheme: SignatureScheme,
+        pub public_key: String, // Key the validator signs its blocks with, in the scheme's encoding, hex
+    }
+
+    impl GenesisValidator {
+        pub fn public_key(&self) -> Result<PublicKey, GenesisError> {
+            parse_key(self.scheme, &self.public_key, &self.address)
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct GenesisAccount {
+        pub address: Address,
+        #[serde(default, with = "amount::decimal")]
+        pub custody: Amount,
+        #[serde(default, with = "amount::decimal")]
+        pub energy: Amount,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct GenesisKey {
+        pub address: Address,
+        #[serde(default)]
+        pub scheme: SignatureScheme,
+        pub public_key: String, // Key in the scheme's encoding, hex
+    }
+
+    impl GenesisKey {
+        pub fn public_key(&self) -> Result<PublicKey, GenesisError> {
+            parse_key(self.scheme, &self.public_key, &self.address)
+        }
+    }
+
+    fn parse_key(scheme: SignatureScheme, key: &str, address: &Address) -> Result<PublicKey, GenesisError> {
+        hex::decode(key)
+            .ok()
+            .and_then(|bytes| PublicKey::from_bytes(scheme, &bytes).ok())
+            .ok_or_else(|| GenesisError::Invalid(format!("invalid {} public key for {}", scheme, address)))
+    }
+
+    fn default_nonce_policy() -> NoncePolicy {
+        NoncePolicy::Increasing
+    }
+
+    impl Default for GenesisConfig {
+        /// An empty development chain.
+        fn default() -> Self {
+            GenesisConfig {
+                chain_id: DEFAULT_CHAIN_ID,
+                genesis_time: "2024-01-01T00:00:00+00:00".to_string(),
+                nonce_policy: default_nonce_policy(),
+                shards: shard::default_shards(),
+                validators: vec![],
+                accounts: vec![],
+                public_keys: vec![],
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum GenesisError {
+        Io(std::io::Error),
+        Format(serde_json::Error),
+        Invalid(String),
+    }
+
+    impl fmt::Display for GenesisError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                GenesisError::Io(e) => write!(f, "cannot read genesis file: {}", e),
+                GenesisError::Format(e) => write!(f, "malformed genesis file: {}", e),
+                GenesisError::Invalid(message) => write!(f, "invalid genesis: {}", message),
+            }
+        }
+    }
+
+    impl std::error::Error for GenesisError {}
+
+    impl GenesisConfig {
+        pub fn load(path: impl AsRef<Path>) -> Result<Self, GenesisError> {
+            let bytes = std::fs::read(path).map_err(GenesisError::Io)?;
+            serde_json::from_slice(&bytes).map_err(GenesisError::Format)
+        }
+
+        /// `genesis_time` as unix milliseconds. Fails if it is not RFC 3339 or before 1970.
+        pub fn genesis_millis(&self) -> Result<u64, GenesisError> {
+            let time = chrono::DateTime::parse_from_rfc3339(&self.genesis_time)
+                .map_err(|e| GenesisError::Invalid(format!("genesis_time: {}", e)))?;
+            u64::try_from(time.timestamp_millis())
+                .map_err(|_| GenesisError::Invalid("genesis_time is before 1970".to_string()))
+        }
+
+        /// SHA-256 of the canonical encoding described in `docs/hashing.md`. Entries are
+        /// sorted by address and keys are normalized to compressed form first, so the
+        /// order and key encoding used in the file do not matter. Fails on invalid keys or
+        /// keys that do not derive their listed address, a bad genesis time, no shards, or an address listed twice in the same section.
+        pub fn hash(&self) -> Result<Hash, GenesisError> {
+            self.genesis_millis()?;
+            if self.shards.is_empty() {
+                return Err(GenesisError::Invalid("at least one shard is required".to_string()));
+            }
+
+            let mut validators = Vec::with_capacity(self.validators.len());
+            for validator in &self.validators {
+                let public_key = validator.public_key()?;
+                if Address::from_key(&public_key) != validator.address {
+                    return Err(GenesisError::Invalid(format!("validator key listed for {} derives another address", validator.address)));
+                }
+                validators.push((validator, public_key));
+            }
+            validators.sort_by_key(|(validator, _)| validator.address);
+            let mut accounts: Vec<&GenesisAccount> = self.accounts.iter().collect();
+            accounts.sort_by_key(|a| a.address);
+            let mut keys = Vec::with_capacity(self.public_keys.len());
+            for key in &self.public_keys {
+                let public_key = key.public_key()?;
+                if Address::from_key(&public_key) != key.address {
+                    return Err(GenesisError::Invalid(format!("public key listed for {} derives another address", key.address)));
+                }
+                keys.push((&key.address, public_key.scheme().tag(), public_key.to_bytes()));
+            }
+            keys.sort();
+            check_unique("validators", validators.iter().map(|(v, _)| &v.address))?;
+            check_unique("accounts", accounts.iter().map(|a| &a.address))?;
+            check_unique("public_keys", keys.iter().map(|(address, _, _)| *address))?;
+
+            let mut encoder = Encoder::new();
+            encoder
+                .bytes(GENESIS_DOMAIN)
+                .u8(GENESIS_ENCODING_VERSION)
+                .u64(self.chain_id)
+                .str(&self.genesis_time)
+                .u8(match self.nonce_policy {
+                    NoncePolicy::Increasing => 0,
+                    NoncePolicy::GapFree => 1,
+                })
+                .u32(self.shards.len() as u32);
+            for shard in &self.shards {
+                encoder.str(&shard.name).u8(shard.purpose.tag());
+            }
+            encoder.u32(validators.len() as u32);
+            for (validator, public_key) in validators {
+                encoder
+                    .raw(validator.address.as_bytes())
+                    .u64(validator.stake.base_units())
+                    .u8(public_key.scheme().tag())
+                    .bytes(&public_key.to_bytes());
+            }
+            encoder.u32(accounts.len() as u32);
+            for account in accounts {
+                encoder
+                    .raw(account.address.as_bytes())
+                    .u64(account.custody.base_units())
+                    .u64(account.energy.base_units());
+            }
+            encoder.u32(keys.len() as u32);
+            for (address, scheme, key) in keys {
+                encoder.raw(address.as_bytes()).u8(scheme).bytes(&key);
+            }
+            Ok(Hash::digest(&encoder.finish()))
+        }
+    }
+
+    fn check_unique<'a>(section: &str, addresses: impl Iterator<Item = &'a Address>) -> Result<(), GenesisError> {
+        let mut seen = HashSet::new();
+        for address in addresses {
+            if !seen.insert(address) {
+                return Err(GenesisError::Invalid(format!("{} lists {} more than once", section, address)));
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::blockchain::Blockchain;
+
+        fn example() -> GenesisConfig {
+            GenesisConfig::load(concat!(env!("CARGO_MANIFEST_DIR"), "/genesis.example.json")).unwrap()
+        }
+
+        #[test]
+        fn genesis_hash_vectors() {
+            let chain = Blockchain::from_genesis(&GenesisConfig::default()).unwrap();
+            assert_eq!(chain.genesis_hash.to_string(), "56fae8f9db9dffd3eec817daaa2c82762dfdd5120980c938955e5890ae0a90c8");
+            assert_eq!(chain.beacon_head().hash.to_string(), "f31724b91ac48f56aa9d731a6b4ba9e90be1d6d6d45a7004c3865dbdd8e47d91");
+            assert_eq!(example().hash().unwrap().to_string(), "cfa031b118a08fba0be0005972d6559f71a564cde9219a749a58c9c1e3c87b9e");
+        }
+
+        #[test]
+        fn hash_ignores_entry_order_and_key_encoding() {
+            let genesis = example();
+            let mut reordered = genesis.clone();
+            reordered.validators.reverse();
+            reordered.accounts.reverse();
+            assert_eq!(reordered.hash().unwrap(), genesis.hash().unwrap());
+
+            let key = genesis.public_keys[0].public_key().unwrap();
+            let PublicKey::P256(point) = key else { panic!("the example key is P-256") };
+            reordered.public_keys[0].public_key = hex::encode(point.to_encoded_point(false).as_bytes());
+            assert_eq!(reordered.hash().unwrap(), genesis.hash().unwrap());
+
+            let mut reshuffled = genesis.clone();
+            reshuffled.shards.reverse();
+            assert_ne!(reshuffled.hash().unwrap(), genesis.hash().unwrap(), "shard order is part of the hash");
+        }
+
+        #[test]
+        fn invalid_configurations_are_rejected() {
+            let genesis = example();
+            let mut duplicate = genesis.clone();
+            duplicate.accounts.push(duplicate.accounts[0].clone());
+            assert!(matches!(duplicate.hash(), Err(GenesisError::Invalid(_))));
+
+            let mut foreign_key = genesis.clone();
+            foreign_key.validators[0].public_key = foreign_key.validators[1].public_key.clone();
+            assert!(matches!(foreign_key.hash(), Err(GenesisError::Invalid(_))));
+
+            let mut bad_time = genesis.clone();
+            bad_time.genesis_time = "yesterday".to_string();
+            assert!(matches!(bad_time.hash(), Err(GenesisError::Invalid(_))));
+
+            let no_shards = GenesisConfig { shards: vec![], ..genesis };
+            assert!(matches!(no_shards.hash(), Err(GenesisError::Invalid(_))));
+        }
+
+        #[test]
+        fn chain_starts_from_the_configuration() {
+            let genesis = example();
+            let chain = Blockchain::from_genesis(&genesis).unwrap();
+            assert_eq!(chain.chain_id, 7);
+            assert_eq!(chain.shards.len(), 3);
+            for shard in chain.shards.iter() {
+                let block = shard.head();
+                assert_eq!((block.index, block.previous_hash), (0, chain.genesis_hash));
+                assert_eq!(block.timestamp, genesis.genesis_millis().unwrap());
+            }
+            let account = &genesis.accounts[0];
+            let balances = chain.state.balances(&account.address);
+            assert_eq!((balances.custody, balances.energy), (account.custody, account.energy));
+            assert_eq!(chain.stakes[&genesis.validators[1].address], "2500.5".parse().unwrap());
+            assert!(chain.authority(&genesis.public_keys[0].address).is_some());
+        }
+    }
+}
+
+pub mod storage {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::beacon::BeaconBlock;
+    use crate::blockchain::{Block, BlockUndo, ChainError, NoncePolicy};
+    use crate::crypto::PublicKey;
+    use crate::multisig::KeyHistory;
+    use crate::genesis::GenesisError;
+    use crate::hash::Hash;
+    use crate::receipts::ReceiptRecord;
+    use crate::state::AccountState;
+    use serde::{Serialize, Deserialize, de::DeserializeOwned};
+    use std::collections::{BTreeMap, HashMap};
+    use std::fmt;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Read, Write};
+    use std::path::{Path, PathBuf};
+
+    /// Everything besides the blocks that `Blockchain::open` needs to restore a chain.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct StateSnapshot {
+        pub chain_id: u64,
+        pub genesis_hash: Hash,
+        pub heads: BTreeMap<u64, Hash>, // Last committed block of each shard
+        pub beacon_head: Hash, // Last committed beacon block
+        pub validators: HashMap<Address, PublicKey>,
+        pub stakes: HashMap<Address, Amount>,
+        pub nonces: HashMap<Address, u64>,
+        pub nonce_policy: NoncePolicy,
+        pub authorities: HashMap<Address, KeyHistory>,
+        pub state: AccountState,
+        pub receipts: BTreeMap<Hash, ReceiptRecord>,
+        pub undo: HashMap<Hash, BlockUndo>, // Undo journals of the canonical blocks not yet finalized
+    }
+
+    #[derive(Debug)]
+    pub enum StorageError {
+        Io(io::Error),
+        Format(serde_json::Error),
+        Corrupt(String),
+        InvalidChain(ChainError),
+        Genesis(GenesisError),
+        GenesisMismatch { stored: Hash, expected: Hash },
+    }
+
+    impl fmt::Display for StorageError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                StorageError::Io(e) => write!(f, "I/O error: {}", e),
+                StorageError::Format(e) => write!(f, "malformed record: {}", e),
+                StorageError::Corrupt(message) => write!(f, "corrupt storage: {}", message),
+                StorageError::InvalidChain(e) => write!(f, "stored chain is invalid: {}", e),
+                StorageError::Genesis(e) => write!(f, "invalid genesis: {}", e),
+                StorageError::GenesisMismatch { stored, expected } => {
+                    write!(f, "stored chain has genesis {}, expected {}", stored, expected)
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for StorageError {}
+
+    impl From<io::Error> for StorageError {
+        fn from(e: io::Error) -> Self {
+            StorageError::Io(e)
+        }
+    }
+
+    impl From<serde_json::Error> for StorageError {
+        fn from(e: serde_json::Error) -> Self {
+            StorageError::Format(e)
+        }
+    }
+
+    /// Where a `Blockchain` keeps its blocks and state snapshot.
+    pub trait StorageBackend: fmt::Debug {
+        fn append_block(&mut self, shard_id: u64, block: &Block) -> Result<(), StorageError>;
+        /// All blocks logged for the shard, oldest first.
+        fn load_blocks(&self, shard_id: u64) -> Result<Vec<Block>, StorageError>;
+        /// Drops every block of the shard after the first `len`.
+        fn truncate_blocks(&mut self, shard_id: u64, len: u64) -> Result<(), StorageError>;
+        fn append_beacon_block(&mut self, block: &BeaconBlock) -> Result<(), StorageError>;
+        /// All logged beacon blocks, oldest first.
+        fn load_beacon_blocks(&self) -> Result<Vec<BeaconBlock>, StorageError>;
+        /// Drops every beacon block after the first `len`.
+        fn truncate_beacon_blocks(&mut self, len: u64) -> Result<(), StorageError>;
+        /// Replaces the stored snapshot.
+        fn save_snapshot(&mut self, snapshot: &StateSnapshot) -> Result<(), StorageError>;
+        fn load_snapshot(&self) -> Result<Option<StateSnapshot>, StorageError>;
+    }
+
+    /// Keeps everything in memory; for tests and throwaway chains.
+    #[derive(Debug, Default)]
+    pub struct MemoryBackend {
+        shards: HashMap<u64, Vec<Block>>,
+        beacon: Vec<BeaconBlock>,
+        snapshot: Option<StateSnapshot>,
+    }
+
+    impl StorageBackend for MemoryBackend {
+        fn append_block(&mut self, shard_id: u64, block: &Block) -> Result<(), StorageError> {
+            self.shards.entry(shard_id).or_default().push(block.clone());
+            Ok(())
+        }
+
+        fn load_blocks(&self, shard_id: u64) -> Result<Vec<Block>, StorageError> {
+            Ok(self.shards.get(&shard_id).cloned().unwrap_or_default())
+        }
+
+        fn truncate_blocks(&mut self, shard_id: u64, len: u64) -> Result<(), StorageError> {
+            if let Some(blocks) = self.shards.get_mut(&shard_id) {
+                blocks.truncate(len as usize);
+            }
+            Ok(())
+        }
+
+        fn append_beacon_block(&mut self, block: &BeaconBlock) -> Result<(), StorageError> {
+            self.beacon.push(block.clone());
+            Ok(())
+        }
+
+        fn load_beacon_blocks(&self) -> Result<Vec<BeaconBlock>, StorageError> {
+            Ok(self.beacon.clone())
+        }
+
+        fn truncate_beacon_blocks(&mut self, len: u64) -> Result<(), StorageError> {
+            self.beacon.truncate(len as usize);
+            Ok(())
+        }
+
+        fn save_snapshot(&mut self, snapshot: &StateSnapshot) -> Result<(), StorageError> {
+            self.snapshot = Some(snapshot.clone());
+            Ok(())
+        }
+
+        fn load_snapshot(&self) -> Result<Option<StateSnapshot>, StorageError> {
+            Ok(self.snapshot.clone())
+        }
+    }
+
+    // Index entry: block offset (u64), block length (u32) and block hash, big-endian.
+    const INDEX_ENTRY_LEN: usize = 8 + 4 + 32;
+
+    struct IndexEntry {
+        offset: u64,
+        len: u32,
+        hash: Hash,
+    }
+
+    // Name of the beacon chain's log files.
+    const BEACON_LOG: &str = "beacon";
+
+    /// Stores each shard as an append-only file of JSON-encoded blocks
+    /// (`shard-<id>.blocks`) plus an index of their offsets and hashes
+    /// (`shard-<id>.index`), the beacon chain the same way (`beacon.blocks`,
+    /// `beacon.index`), and the snapshot as `state.json`.
+    #[derive(Debug)]
+    pub struct FileBackend {
+        dir: PathBuf,
+    }
+
+    impl FileBackend {
+        pub fn open(dir: impl AsRef<Path>) -> Result<Self, StorageError> {
+            fs::create_dir_all(dir.as_ref())?;
+            Ok(FileBackend { dir: dir.as_ref().to_path_buf() })
+        }
+
+        fn shard_log(shard_id: u64) -> String {
+            format!("shard-{}", shard_id)
+        }
+
+        fn blocks_path(&self, log: &str) -> PathBuf {
+            self.dir.join(format!("{}.blocks", log))
+        }
+
+        fn index_path(&self, log: &str) -> PathBuf {
+            self.dir.join(format!("{}.index", log))
+        }
+
+        fn snapshot_path(&self) -> PathBuf {
+            self.dir.join("state.json")
+        }
+
+        fn read_index(&self, log: &str) -> Result<Vec<IndexEntry>, StorageError> {
+            let bytes = match fs::read(self.index_path(log)) {
+                Ok(bytes) => bytes,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e.into()),
+            };
+            if bytes.len() % INDEX_ENTRY_LEN != 0 {
+                return Err(StorageError::Corrupt(format!("index of {} has a partial entry", log)));
+            }
+            Ok(bytes
+                .chunks_exact(INDEX_ENTRY_LEN)
+                .map(|entry| {
+                    let (offset, rest) = entry.split_at(8);
+                    let (len, hash) = rest.split_at(4);
+                    IndexEntry {
+                        offset: u64::from_be_bytes(offset.try_into().expect("8-byte slice")),
+                        len: u32::from_be_bytes(len.try_into().expect("4-byte slice")),
+                        hash: Hash(hash.try_into().expect("32-byte slice")),
+                    }
+                })
+                .collect())
+        }
+
+        /// Appends a JSON-encoded block and its index entry to `log`.
+        fn append_record(&self, log: &str, block: &impl Serialize, index: u64, hash: &Hash) -> Result<(), StorageError> {
+            let data = serde_json::to_vec(block)?;
+            let len = u32::try_from(data.len())
+                .map_err(|_| StorageError::Corrupt(format!("block {} is too large to store", index)))?;
+
+            let mut blocks = OpenOptions::new().create(true).append(true).open(self.blocks_path(log))?;
+            let offset = blocks.metadata()?.len();
+            blocks.write_all(&data)?;
+            blocks.sync_data()?;
+
+            let mut entry = Vec::with_capacity(INDEX_ENTRY_LEN);
+            entry.extend_from_slice(&offset.to_be_bytes());
+            entry.extend_from_slice(&len.to_be_bytes());
+            entry.extend_from_slice(hash.as_bytes());
+            let mut index = OpenOptions::new().create(true).append(true).open(self.index_path(log))?;
+            index.write_all(&entry)?;
+            index.sync_data()?;
+            Ok(())
+        }
+
+        /// Reads every block of `log`, checking each against the hash in its index entry.
+        fn load_records<T: DeserializeOwned>(&self, log: &str, hash: impl Fn(&T) -> Hash) -> Result<Vec<T>, StorageError> {
+            let index = self.read_index(log)?;
+            if index.is_empty() {
+                return Ok(Vec::new());
+            }
+            let mut data = Vec::new();
+            File::open(self.blocks_path(log))?.read_to_end(&mut data)?;
+
+            let mut blocks = Vec::with_capacity(index.len());
+            for (position, entry) in index.iter().enumerate() {
+                let record = usize::try_from(entry.offset)
+                    .ok()
+                    .and_then(|start| data.get(start..start.checked_add(entry.len as usize)?))
+                    .ok_or_else(|| StorageError::Corrupt(format!("block {} of {} is truncated", position, log)))?;
+                let block: T = serde_json::from_slice(record)?;
+                if hash(&block) != entry.hash {
+                    return Err(StorageError::Corrupt(format!(
+                        "block {} of {} does not match its index entry",
+                        position, log
+                    )));
+                }
+                blocks.push(block);
+            }
+            Ok(blocks)
+        }
+
+        /// Drops every block of `log` after the first `len`.
+        fn truncate_log(&self, log: &str, len: u64) -> Result<(), StorageError> {
+            let index = self.read_index(log)?;
+            let len = len as usize;
+            if len >= index.len() {
+                return Ok(());
+            }
+            OpenOptions::new()
+                .write(true)
+                .open(self.blocks_path(log))?
+                .set_len(index[len].offset)?;
+            OpenOptions::new()
+                .write(true)
+                .open(self.index_path(log))?
+                .set_len((len * INDEX_ENTRY_LEN) as u64)?;
+            Ok(())
+        }
+    }
+
+    impl StorageBackend for FileBackend {
+        fn append_block(&mut self, shard_id: u64, block: &Block) -> Result<(), StorageError> {
+            self.append_record(&Self::shard_log(shard_id), block, block.index, &block.hash)
+        }
+
+        fn load_blocks(&self, shard_id: u64) -> Result<Vec<Block>, StorageError> {
+            self.load_records(&Self::shard_log(shard_id), |block: &Block| block.hash)
+        }
+
+        fn truncate_blocks(&mut self, shard_id: u64, len: u64) -> Result<(), StorageError> {
+            self.truncate_log(&Self::shard_log(shard_id), len)
+        }
+
+        fn append_beacon_block(&mut self, block: &BeaconBlock) -> Result<(), StorageError> {
+            self.append_record(BEACON_LOG, block, block.index, &block.hash)
+        }
+
+        fn load_beacon_blocks(&self) -> Result<Vec<BeaconBlock>, StorageError> {
+            self.load_records(BEACON_LOG, |block: &BeaconBlock| block.hash)
+        }
+
+        fn truncate_beacon_blocks(&mut self, len: u64) -> Result<(), StorageError> {
+            self.truncate_log(BEACON_LOG, len)
+        }
+
+        /// Written to a temporary file and renamed into place, so a crash leaves either the
+        /// old or the new snapshot.
+        fn save_snapshot(&mut self, snapshot: &StateSnapshot) -> Result<(), StorageError> {
+            let temp = self.dir.join("state.json.tmp");
+            let mut file = File::create(&temp)?;
+            file.write_all(&serde_json::to_vec_pretty(snapshot)?)?;
+            file.sync_all()?;
+            fs::rename(temp, self.snapshot_path())?;
+            Ok(())
+        }
+
+        fn load_snapshot(&self) -> Result<Option<StateSnapshot>, StorageError> {
+            match fs::read(self.snapshot_path()) {
+                Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::blockchain::tests::{alice, alice_genesis, assert_same_state, bob, dave, dave_genesis, dave_key, pay_bob, register_and_pay};
+        use crate::blockchain::{Blockchain, Token, TokenKind};
+        use crate::builder::TransactionBuilder;
+        use crate::genesis::GenesisConfig;
+        use std::sync::{Arc, Mutex};
+
+        /// A `MemoryBackend` that outlives the chain using it, so that the chain can be
+        /// opened from it again.
+        #[derive(Debug, Clone, Default)]
+        struct Shared(Arc<Mutex<MemoryBackend>>);
+
+        impl StorageBackend for Shared {
+            fn append_block(&mut self, shard_id: u64, block: &Block) -> Result<(), StorageError> {
+                self.0.lock().unwrap().append_block(shard_id, block)
+            }
+
+            fn load_blocks(&self, shard_id: u64) -> Result<Vec<Block>, StorageError> {
+                self.0.lock().unwrap().load_blocks(shard_id)
+            }
+
+            fn truncate_blocks(&mut self, shard_id: u64, len: u64) -> Result<(), StorageError> {
+                self.0.lock().unwrap().truncate_blocks(shard_id, len)
+            }
+
+            fn append_beacon_block(&mut self, block: &BeaconBlock) -> Result<(), StorageError> {
+                self.0.lock().unwrap().append_beacon_block(block)
+            }
+
+            fn load_beacon_blocks(&self) -> Result<Vec<BeaconBlock>, StorageError> {
+                self.0.lock().unwrap().load_beacon_blocks()
+            }
+
+            fn truncate_beacon_blocks(&mut self, len: u64) -> Result<(), StorageError> {
+                self.0.lock().unwrap().truncate_beacon_blocks(len)
+            }
+
+            fn save_snapshot(&mut self, snapshot: &StateSnapshot) -> Result<(), StorageError> {
+                self.0.lock().unwrap().save_snapshot(snapshot)
+            }
+
+            fn load_snapshot(&self) -> Result<Option<StateSnapshot>, StorageError> {
+                self.0.lock().unwrap().load_snapshot()
+            }
+        }
+
+        /// Opens a new handle to a test store.
+        type Reopen = Box<dyn Fn() -> Box<dyn StorageBackend>>;
+
+        /// An empty directory for a `FileBackend`, unique to this test process.
+        fn temp_dir(name: &str) -> PathBuf {
+            let dir = std::env::temp_dir().join(format!("crawchain-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            dir
+        }
+
+        /// Opens fresh handles to one in-memory and one on-disk store, each as empty as
+        /// `temp_dir` leaves them.
+        fn backends(name: &str) -> Vec<(PathBuf, Reopen)> {
+            let memory = Shared::default();
+            let dir = temp_dir(name);
+            let file_dir = dir.clone();
+            vec![
+                (PathBuf::new(), Box::new(move || Box::new(memory.clone()))),
+                (dir, Box::new(move || Box::new(FileBackend::open(&file_dir).unwrap()))),
+            ]
+        }
+
+        /// Alice pays Bob in two blocks, with the first committed by the beacon chain, and
+        /// every other shard gets a block settling whatever receipts are due.
+        fn populate(chain: &mut Blockchain) {
+            let shard_id = chain.assign_shard(&alice());
+            chain.add_block(vec![pay_bob(chain, 10)], shard_id).unwrap();
+            chain.commit_beacon().unwrap();
+            let others: Vec<u64> = chain.shards.iter().map(|shard| shard.id).filter(|id| *id != shard_id).collect();
+            for other in others {
+                chain.add_block(vec![], other).unwrap();
+            }
+            chain.add_block(vec![pay_bob(chain, 5)], shard_id).unwrap();
+        }
+
+        type Edit = fn(&mut StateSnapshot);
+
+        /// Changes the stored snapshot with `edit`.
+        fn edit_snapshot(storage: &mut dyn StorageBackend, edit: impl FnOnce(&mut StateSnapshot)) {
+            let mut snapshot = storage.load_snapshot().unwrap().unwrap();
+            edit(&mut snapshot);
+            storage.save_snapshot(&snapshot).unwrap();
+        }
+
+        #[test]
+        fn reopened_chain_matches_the_stored_one() {
+            let genesis = alice_genesis();
+            for (dir, storage) in backends("reopen") {
+                let mut chain = Blockchain::open(storage(), &genesis).unwrap();
+                populate(&mut chain);
+                assert_eq!(chain.state.balances(&bob()).custody, Amount::from_tokens(10).unwrap());
+
+                let mut reopened = Blockchain::open(storage(), &genesis).unwrap();
+                assert_same_state(&reopened, &chain);
+                assert_eq!(reopened.beacon.last().map(|block| &block.hash), chain.beacon.last().map(|block| &block.hash));
+                for (shard, stored) in reopened.shards.iter().zip(chain.shards.iter()) {
+                    assert_eq!(shard.head().hash, stored.head().hash);
+                }
+                assert_eq!(reopened.verify_chain(), Ok(()));
+
+                let shard_id = reopened.assign_shard(&alice());
+                reopened.add_block(vec![pay_bob(&reopened, 1)], shard_id).unwrap();
+                let again = Blockchain::open(storage(), &genesis).unwrap();
+                assert_same_state(&again, &reopened);
+                let _ = fs::remove_dir_all(dir);
+            }
+        }
+
+        #[test]
+        fn edited_snapshots_are_rejected() {
+            let genesis = alice_genesis();
+            let edits: [(&str, Edit); 7] = [
+                ("balances", |snapshot| {
+                    let token = Token::CustodyToken(Amount::from_tokens(1).unwrap());
+                    snapshot.state.credit(&alice(), &token).unwrap();
+                }),
+                ("nonces", |snapshot| {
+                    snapshot.nonces.insert(alice(), 0);
+                }),
+                ("keys and policies", |snapshot| {
+                    let history = snapshot.authorities[&alice()].clone();
+                    snapshot.authorities.insert(bob(), history);
+                }),
+                ("validators", |snapshot| {
+                    snapshot.stakes.insert(alice(), Amount::from_tokens(1).unwrap());
+                }),
+                ("chain id", |snapshot| {
+                    snapshot.chain_id += 1;
+                }),
+                ("nonce policy", |snapshot| {
+                    snapshot.nonce_policy = NoncePolicy::GapFree;
+                }),
+                ("undo journals", |snapshot| {
+                    assert!(!snapshot.undo.is_empty());
+                    for journal in snapshot.undo.values_mut() {
+                        *journal = BlockUndo::default();
+                    }
+                }),
+            ];
+            for (dir, storage) in backends("edited") {
+                populate(&mut Blockchain::open(storage(), &genesis).unwrap());
+                let original = storage().load_snapshot().unwrap().unwrap();
+                for (table, edit) in edits {
+                    edit_snapshot(storage().as_mut(), edit);
+                    match Blockchain::open(storage(), &genesis) {
+                        Err(StorageError::Corrupt(message)) => assert!(message.contains(table), "{}", message),
+                        other => panic!("edited {} were accepted: {:?}", table, other.map(|_| ())),
+                    }
+                    storage().save_snapshot(&original).unwrap();
+                }
+                assert!(Blockchain::open(storage(), &genesis).is_ok());
+                let _ = fs::remove_dir_all(dir);
+            }
+        }
+
+        #[test]
+        fn damaged_logs_are_rejected() {
+            let genesis = alice_genesis();
+            for (dir, storage) in backends("damaged") {
+                let mut chain = Blockchain::open(storage(), &genesis).unwrap();
+                populate(&mut chain);
+                let shard_id = chain.assign_shard(&alice());
+
+                let other = GenesisConfig { chain_id: 2, ..genesis.clone() };
+                assert!(matches!(Blockchain::open(storage(), &other), Err(StorageError::GenesisMismatch { .. })));
+
+                storage().truncate_beacon_blocks(1).unwrap();
+                assert!(matches!(Blockchain::open(storage(), &genesis), Err(StorageError::Corrupt(_))));
+
+                storage().truncate_blocks(shard_id, 2).unwrap();
+                assert!(matches!(Blockchain::open(storage(), &genesis), Err(StorageError::Corrupt(_))));
+                let _ = fs::remove_dir_all(dir);
+            }
+        }
+
+        #[test]
+        fn tampered_blocks_are_rejected() {
+            let genesis = alice_genesis();
+            let memory = Shared::default();
+            let mut chain = Blockchain::open(Box::new(memory.clone()), &genesis).unwrap();
+            populate(&mut chain);
+            let shard_id = chain.assign_shard(&alice());
+            if let Some(block) = memory.0.lock().unwrap().shards.get_mut(&shard_id).and_then(|blocks| blocks.get_mut(1)) {
+                block.transactions[0].token = Token::CustodyToken(Amount::from_tokens(1).unwrap());
+            }
+            assert!(matches!(Blockchain::open(Box::new(memory), &genesis), Err(StorageError::InvalidChain(_))));
+
+            let dir = temp_dir("tampered");
+            populate(&mut Blockchain::open(Box::new(FileBackend::open(&dir).unwrap()), &genesis).unwrap());
+            let log = dir.join(format!("shard-{}.blocks", shard_id));
+            let data = fs::read(&log).unwrap();
+            let tampered = String::from_utf8(data).unwrap().replacen("\"nonce\":1", "\"nonce\":9", 1);
+            fs::write(&log, tampered).unwrap();
+            assert!(matches!(
+                Blockchain::open(Box::new(FileBackend::open(&dir).unwrap()), &genesis),
+                Err(StorageError::InvalidChain(_))
+            ));
+
+            let index = dir.join(format!("shard-{}.index", shard_id));
+            let mut entries = fs::read(&index).unwrap();
+            entries.pop();
+            fs::write(&index, entries).unwrap();
+            assert!(matches!(
+                Blockchain::open(Box::new(FileBackend::open(&dir).unwrap()), &genesis),
+                Err(StorageError::Corrupt(_))
+            ));
+            let _ = fs::remove_dir_all(dir);
+        }
+
+        #[test]
+        fn registered_keys_survive_a_reload() {
+            let genesis = dave_genesis();
+            for (dir, storage) in backends("registered") {
+                let mut chain = Blockchain::open(storage(), &genesis).unwrap();
+                let shard_id = chain.assign_shard(&dave());
+                chain.add_block(register_and_pay(&chain), shard_id).unwrap();
+
+                let mut reopened = Blockchain::open(storage(), &genesis).unwrap();
+                assert_eq!(reopened.key_history(&dave()), chain.key_history(&dave()));
+                let token = Token::CustodyToken(Amount::from_tokens(1).unwrap());
+                let (transfer, _) = TransactionBuilder::transfer(bob(), token).build(&reopened, &dave_key()).unwrap();
+                reopened.add_block(vec![transfer], shard_id).unwrap();
+                assert_eq!(reopened.state.balance(&dave(), TokenKind::Custody), Amount::from_tokens(98).unwrap());
+                let _ = fs::remove_dir_all(dir);
+            }
+        }
+    }
+}
+
+pub mod wallet {
+    //! Keys of the node's operator, kept in a password-encrypted keystore file. The
+    //! password is stretched with scrypt into a key that seals each secret key with
+    //! ChaCha20-Poly1305; addresses and public keys are stored in the clear.
+
+    use crate::address::Address;
+    use crate::blockchain::Transaction;
+    use crate::crypto::{PublicKey, SecretKey, SignatureScheme};
+    use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+    use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+    use rand::RngCore;
+    use serde::{Deserialize, Serialize};
+    use std::fmt;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Write};
+    #[cfg(unix)]
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::{Path, PathBuf};
+
+    /// Version of the keystore file format.
+    pub const KEYSTORE_VERSION: u32 = 1;
+    /// Plaintext sealed in every keystore to check the password against.
+    const VERIFIER: &[u8] = b"CRAWCHAIN/KEYSTORE";
+    /// Largest scrypt work factor a keystore may ask for.
+    pub const MAX_KDF_LOG_N: u8 = 20;
+    /// Largest `r * p` a keystore may ask for.
+    pub const MAX_KDF_PARALLEL_COST: u64 = 64;
+    /// Most memory, in bytes, a keystore may make scrypt use.
+    pub const MAX_KDF_MEMORY: u64 = 1 << 30;
+
+    /// scrypt cost parameters of a keystore.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct KdfParams {
+        pub log_n: u8, // Work factor: 2^log_n iterations and 128 * r * 2^log_n bytes of memory
+        pub r: u32,
+        pub p: u32,
+    }
+
+    impl Default for KdfParams {
+        fn default() -> Self {
+            KdfParams { log_n: 15, r: 8, p: 1 }
+        }
+    }
+
+    impl KdfParams {
+        /// Whether the parameters stay within the limits above, so that a crafted keystore
+        /// cannot make opening it take unbounded time or memory.
+        pub fn is_bounded(&self) -> bool {
+            let (r, p) = (u64::from(self.r), u64::from(self.p));
+            self.log_n <= MAX_KDF_LOG_N && r * p <= MAX_KDF_PARALLEL_COST && (128 * r) << self.log_n <= MAX_KDF_MEMORY
+        }
+    }
+
+    /// Ciphertext of one sealed value and the nonce it was sealed with, hex.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    struct Sealed {
+        nonce: String,
+        ciphertext: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    struct StoredKey {
+        address: Address,
+        scheme: SignatureScheme,
+        public_key: String, // Hex, in the scheme's encoding
+        secret: Sealed, // Secret key bytes, bound to the address and scheme
+    }
+
+    /// The keystore file.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    struct KeystoreFile {
+        version: u32,
+        kdf: KdfParams,
+        salt: String, // Hex
+        verifier: Sealed, // `VERIFIER`, sealed with the password's key
+        keys: Vec<StoredKey>,
+    }
+
+    #[derive(Debug)]
+    pub enum WalletError {
+        Io(io::Error),
+        Format(serde_json::Error),
+        AlreadyExists(PathBuf),
+        UnsupportedVersion(u32),
+        InvalidKdfParams(KdfParams),
+        WrongPassword,
+        Corrupt(String),
+        UnknownAddress(Address),
+        DuplicateKey(Address),
+    }
+
+    impl fmt::Display for WalletError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                WalletError::Io(e) => write!(f, "I/O error: {}", e),
+                WalletError::Format(e) => write!(f, "malformed keystore: {}", e),
+                WalletError::AlreadyExists(path) => write!(f, "keystore {} already exists", path.display()),
+                WalletError::UnsupportedVersion(version) => write!(f, "unsupported keystore version {}", version),
+                WalletError::InvalidKdfParams(params) => write!(f, "invalid scrypt parameters {:?}", params),
+                WalletError::WrongPassword => write!(f, "wrong keystore password"),
+                WalletError::Corrupt(message) => write!(f, "corrupt keystore: {}", message),
+                WalletError::UnknownAddress(address) => write!(f, "no key for {} in the wallet", address),
+                WalletError::DuplicateKey(address) => write!(f, "the wallet already holds the key of {}", address),
+            }
+        }
+    }
+
+    impl std::error::Error for WalletError {}
+
+    impl From<io::Error> for WalletError {
+        fn from(e: io::Error) -> Self {
+            WalletError::Io(e)
+        }
+    }
+
+    impl From<serde_json::Error> for WalletError {
+        fn from(e: serde_json::Error) -> Self {
+            WalletError::Format(e)
+        }
+    }
+
+    /// An unlocked keystore. Every change is written back to the keystore file at once.
+    pub struct Wallet {
+        path: Option<PathBuf>, // None for a wallet that lives only in memory
+        file: KeystoreFile,
+        cipher: ChaCha20Poly1305,
+        keys: Vec<(Address, SecretKey)>, // In the order they were added
+    }
+
+    impl Wallet {
+        /// Creates an empty keystore at `path`, encrypted with `password`. Fails if the file
+        /// already exists, also if another process creates it first.
+        pub fn create(path: impl AsRef<Path>, password: &str, kdf: KdfParams) -> Result<Self, WalletError> {
+            let path = path.as_ref();
+            let mut wallet = Self::empty(password, kdf)?;
+            let file = match Self::new_file().open(path) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    return Err(WalletError::AlreadyExists(path.to_path_buf()));
+                }
+                file => file?,
+            };
+            wallet.write_to(file)?;
+            wallet.path = Some(path.to_path_buf());
+            Ok(wallet)
+        }
+
+        /// A wallet that is never written to disk, for throwaway keys.
+        pub fn in_memory() -> Self {
+            Self::empty("", KdfParams { log_n: 1, r: 1, p: 1 }).expect("the in-memory parameters are valid")
+        }
+
+        /// Opens the keystore at `path` and decrypts its keys with `password`.
+        pub fn open(path: impl AsRef<Path>, password: &str) -> Result<Self, WalletError> {
+            let path = path.as_ref();
+            let file: KeystoreFile = serde_json::from_slice(&fs::read(path)?)?;
+            if file.version != KEYSTORE_VERSION {
+                return Err(WalletError::UnsupportedVersion(file.version));
+            }
+            let salt = hex::decode(&file.salt).map_err(|_| WalletError::Corrupt("salt is not hex".to_string()))?;
+            let cipher = Self::derive_cipher(password, &salt, file.kdf)?;
+            match Self::unseal(&cipher, &file.verifier, &[]) {
+                Ok(verifier) if verifier == VERIFIER => {}
+                _ => return Err(WalletError::WrongPassword),
+            }
+            let mut keys = Vec::with_capacity(file.keys.len());
+            for stored in &file.keys {
+                let corrupt = || WalletError::Corrupt(format!("key of {} does not decrypt to its public key", stored.address));
+                let secret = Self::unseal(&cipher, &stored.secret, &Self::associated_data(&stored.address, stored.scheme))
+                    .map_err(|_| corrupt())?;
+                let key = SecretKey::from_bytes(stored.scheme, &secret).map_err(|_| corrupt())?;
+                let public_key = hex::encode(key.public_key().to_bytes());
+                if public_key != stored.public_key || Address::from_key(&key.public_key()) != stored.address {
+                    return Err(corrupt());
+                }
+                keys.push((stored.address, key));
+            }
+            Ok(Wallet { path: Some(path.to_path_buf()), file, cipher, keys })
+        }
+
+        /// Generates a key of `scheme` and adds it to the wallet.
+        pub fn generate(&mut self, scheme: SignatureScheme) -> Result<Address, WalletError> {
+            self.import(SecretKey::generate(scheme))
+        }
+
+        /// Adds `key` to the wallet and returns the address it derives.
+        pub fn import(&mut self, key: SecretKey) -> Result<Address, WalletError> {
+            let address = Address::from_key(&key.public_key());
+            if self.key(&address).is_ok() {
+                return Err(WalletError::DuplicateKey(address));
+            }
+            let secret = self.seal(&key.to_bytes(), &Self::associated_data(&address, key.scheme()));
+            self.file.keys.push(StoredKey {
+                address,
+                scheme: key.scheme(),
+                public_key: hex::encode(key.public_key().to_bytes()),
+                secret,
+            });
+            self.keys.push((address, key));
+            self.save()?;
+            Ok(address)
+        }
+
+        /// The secret key of `address`; `SecretKey::to_bytes` with its scheme is its portable
+        /// form.
+        pub fn export(&self, address: &Address) -> Result<&SecretKey, WalletError> {
+            self.key(address)
+        }
+
+        /// Deletes the key of `address` from the wallet.
+        pub fn remove(&mut self, address: &Address) -> Result<(), WalletError> {
+            let position = self
+                .keys
+                .iter()
+                .position(|(held, _)| held == address)
+                .ok_or(WalletError::UnknownAddress(*address))?;
+            self.keys.remove(position);
+            self.file.keys.retain(|stored| stored.address != *address);
+            self.save()
+        }
+
+        /// Addresses of the wallet's keys, in the order they were added.
+        pub fn addresses(&self) -> Vec<Address> {
+            self.keys.iter().map(|(address, _)| *address).collect()
+        }
+
+        pub fn public_key(&self, address: &Address) -> Result<PublicKey, WalletError> {
+            self.key(address).map(SecretKey::public_key)
+        }
+
+        /// Signs `transaction` with the key of its sender, over its canonical signing bytes.
+        pub fn sign(&self, transaction: &mut Transaction) -> Result<(), WalletError> {
+            transaction.sign(self.key(&transaction.sender)?);
+            Ok(())
+        }
+
+        /// Adds the cosignature of the key of `signer`, the key at position `index` of the
+        /// multisig policy that signs for the transaction's sender.
+        pub fn cosign(&self, transaction: &mut Transaction, index: u32, signer: &Address) -> Result<(), WalletError> {
+            transaction.cosign(index, self.key(signer)?);
+            Ok(())
+        }
+
+        fn key(&self, address: &Address) -> Result<&SecretKey, WalletError> {
+            self.keys
+                .iter()
+                .find(|(held, _)| held == address)
+                .map(|(_, key)| key)
+                .ok_or(WalletError::UnknownAddress(*address))
+        }
+
+        fn empty(password: &str, kdf: KdfParams) -> Result<Self, WalletError> {
+            let mut salt = [0u8; 16];
+            rand::rngs::OsRng.fill_bytes(&mut salt);
+            let cipher = Self::derive_cipher(password, &salt, kdf)?;
+            let verifier = Sealed { nonce: String::new(), ciphertext: String::new() };
+            let file = KeystoreFile { version: KEYSTORE_VERSION, kdf, salt: hex::encode(salt), verifier, keys: Vec::new() };
+            let mut wallet = Wallet { path: None, file, cipher, keys: Vec::new() };
+            wallet.file.verifier = wallet.seal(VERIFIER, &[]);
+            Ok(wallet)
+        }
+
+        fn derive_cipher(password: &str, salt: &[u8], kdf: KdfParams) -> Result<ChaCha20Poly1305, WalletError> {
+            if !kdf.is_bounded() {
+                return Err(WalletError::InvalidKdfParams(kdf));
+            }
+            let params = scrypt::Params::new(kdf.log_n, kdf.r, kdf.p, 32).map_err(|_| WalletError::InvalidKdfParams(kdf))?;
+            let mut key = [0u8; 32];
+            scrypt::scrypt(password.as_bytes(), salt, &params, &mut key).map_err(|_| WalletError::InvalidKdfParams(kdf))?;
+            let cipher = ChaCha20Poly1305::new(&key.into());
+            key.fill(0);
+            Ok(cipher)
+        }
+
+        /// Binds a sealed secret key to the entry it is stored in, so that it cannot be
+        /// moved to another address or scheme.
+        fn associated_data(address: &Address, scheme: SignatureScheme) -> Vec<u8> {
+            let mut data = address.as_bytes().to_vec();
+            data.push(scheme.tag());
+            data
+        }
+
+        fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Sealed {
+            let mut nonce = [0u8; 12];
+            rand::rngs::OsRng.fill_bytes(&mut nonce);
+            let ciphertext = self
+                .cipher
+                .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+                .expect("sealing fits in memory");
+            Sealed { nonce: hex::encode(nonce), ciphertext: hex::encode(ciphertext) }
+        }
+
+        fn unseal(cipher: &ChaCha20Poly1305, sealed: &Sealed, aad: &[u8]) -> Result<Vec<u8>, WalletError> {
+            let corrupt = || WalletError::Corrupt("sealed value is malformed".to_string());
+            let nonce = hex::decode(&sealed.nonce).ok().filter(|nonce| nonce.len() == 12).ok_or_else(corrupt)?;
+            let ciphertext = hex::decode(&sealed.ciphertext).map_err(|_| corrupt())?;
+            cipher
+                .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad })
+                .map_err(|_| WalletError::WrongPassword)
+        }
+
+        /// Written to a temporary file and renamed into place, so a crash leaves either the
+        /// old or the new keystore.
+        fn save(&self) -> Result<(), WalletError> {
+            let Some(path) = &self.path else {
+                return Ok(());
+            };
+            let temp = path.with_extension("tmp");
+            // A temporary file left by a crash may be readable by others; start afresh.
+            match fs::remove_file(&temp) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            self.write_to(Self::new_file().open(&temp)?)?;
+            fs::rename(temp, path)?;
+            Ok(())
+        }
+
+        fn write_to(&self, mut file: File) -> Result<(), WalletError> {
+            file.write_all(&serde_json::to_vec_pretty(&self.file)?)?;
+            file.sync_all()?;
+            Ok(())
+        }
+
+        /// Options creating a file that must not exist yet, readable by its owner only.
+        fn new_file() -> OpenOptions {
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            options
+        }
+    }
+
+    impl fmt::Debug for Wallet {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Wallet").field("path", &self.path).field("addresses", &self.addresses()).finish()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// Cheap parameters, so that the tests do not spend their time in scrypt.
+        const FAST: KdfParams = KdfParams { log_n: 4, r: 8, p: 1 };
+
+        /// A keystore path that does not exist yet, unique to this test process.
+        fn temp_path(name: &str) -> PathBuf {
+            let path = std::env::temp_dir().join(format!("crawchain-{}-{}.json", name, std::process::id()));
+            let _ = fs::remove_file(&path);
+            path
+        }
+
+        fn secret_bytes(wallet: &Wallet, address: &Address) -> Vec<u8> {
+            wallet.export(address).unwrap().to_bytes()
+        }
+
+        #[test]
+        fn keys_survive_reopening() {
+            let path = temp_path("wallet-reopen");
+            let mut wallet = Wallet::create(&path, "hunter2", FAST).unwrap();
+            let generated = wallet.generate(SignatureScheme::Ed25519).unwrap();
+            let imported = wallet.import(SecretKey::from_bytes(SignatureScheme::P256, &[9u8; 32]).unwrap()).unwrap();
+
+            let reopened = Wallet::open(&path, "hunter2").unwrap();
+            assert_eq!(reopened.addresses(), vec![generated, imported]);
+            for address in [generated, imported] {
+                assert_eq!(secret_bytes(&reopened, &address), secret_bytes(&wallet, &address));
+            }
+
+            wallet.remove(&generated).unwrap();
+            let reopened = Wallet::open(&path, "hunter2").unwrap();
+            assert_eq!(reopened.addresses(), vec![imported]);
+            assert!(matches!(reopened.export(&generated), Err(WalletError::UnknownAddress(_))));
+            let _ = fs::remove_file(path);
+        }
+
+        #[test]
+        fn wrong_password_is_rejected() {
+            let path = temp_path("wallet-password");
+            let mut wallet = Wallet::create(&path, "hunter2", FAST).unwrap();
+            wallet.generate(SignatureScheme::Secp256k1).unwrap();
+            assert!(matches!(Wallet::open(&path, "hunter3"), Err(WalletError::WrongPassword)));
+            assert!(matches!(Wallet::open(&path, ""), Err(WalletError::WrongPassword)));
+            assert!(matches!(Wallet::create(&path, "hunter2", FAST), Err(WalletError::AlreadyExists(_))));
+            let _ = fs::remove_file(path);
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn keystores_are_private() {
+            use std::os::unix::fs::PermissionsExt;
+
+            let path = temp_path("wallet-private");
+            let mut wallet = Wallet::create(&path, "hunter2", FAST).unwrap();
+            let mode = || fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(), 0o600);
+            // A stale temporary file does not pass its permissions on to the keystore.
+            fs::write(path.with_extension("tmp"), b"").unwrap();
+            wallet.generate(SignatureScheme::Ed25519).unwrap();
+            assert_eq!(mode(), 0o600);
+            assert!(!path.with_extension("tmp").exists());
+            let _ = fs::remove_file(path);
+        }
+
+        #[test]
+        fn keys_are_held_once() {
+            let mut wallet = Wallet::in_memory();
+            let key = SecretKey::from_bytes(SignatureScheme::Ed25519, &[5u8; 32]).unwrap();
+            let address = wallet.import(key.clone()).unwrap();
+            assert!(matches!(wallet.import(key), Err(WalletError::DuplicateKey(_))));
+            wallet.remove(&address).unwrap();
+            assert!(matches!(wallet.remove(&address), Err(WalletError::UnknownAddress(_))));
+        }
+
+        #[test]
+        fn kdf_parameters_are_bounded() {
+            assert!(KdfParams::default().is_bounded());
+            let unbounded = [
+                KdfParams { log_n: MAX_KDF_LOG_N + 1, r: 1, p: 1 },
+                KdfParams { log_n: 10, r: 8, p: 9 },
+                KdfParams { log_n: 20, r: 16, p: 1 },
+                KdfParams { log_n: 4, r: u32::MAX, p: u32::MAX },
+            ];
+            for kdf in unbounded {
+                assert!(!kdf.is_bounded(), "{:?}", kdf);
+                assert!(matches!(Wallet::create(temp_path("wallet-kdf"), "hunter2", kdf), Err(WalletError::InvalidKdfParams(_))));
+            }
+
+            // A keystore edited to ask for more work is refused before scrypt runs.
+            let path = temp_path("wallet-edited-kdf");
+            Wallet::create(&path, "hunter2", FAST).unwrap();
+            let mut file: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+            file["kdf"]["log_n"] = 63.into();
+            fs::write(&path, serde_json::to_vec(&file).unwrap()).unwrap();
+            assert!(matches!(Wallet::open(&path, "hunter2"), Err(WalletError::InvalidKdfParams(_))));
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+pub mod builder {
+    //! Assembles transactions for a chain: fills in the chain id, the sender's shard and
+    //! its next nonce, then signs the canonical signing payload.
+
+    use crate::address::Address;
+    use crate::amount::Amount;
+    use crate::blockchain::{Blockchain, Token, Transaction, TxError, TxKind, ZKProof};
+    use crate::crypto::{PublicKey, SecretKey, SignatureScheme};
+    use crate::hash::Hash;
+
+    /// Gas limit of a built transaction unless `gas_limit` sets another.
+    pub const DEFAULT_GAS_LIMIT: u64 = 1000;
+
+    #[derive(Debug, Clone)]
+    pub struct TransactionBuilder {
+        kind: TxKind,
+        receiver: Option<Address>, // None sends the transaction to its sender
+        token: Token,
+        gas_limit: u64,
+        contract_code: Option<Vec<u8>>,
+        zkp: Option<ZKProof>,
+        sender: Option<Address>, // None for the address the signing key derives
+        nonce: Option<u64>, // None for the sender's next nonce on the chain
+    }
+
+    impl TransactionBuilder {
+        /// A transaction of `kind` that moves no tokens and is sent to its sender, as key
+        /// registrations and changes are.
+        pub fn new(kind: TxKind) -> Self {
+            TransactionBuilder {
+                kind,
+                receiver: None,
+                token: Token::CustodyToken(Amount::ZERO),
+                gas_limit: DEFAULT_GAS_LIMIT,
+                contract_code: None,
+                zkp: None,
+                sender: None,
+                nonce: None,
+            }
+        }
+
+        /// A transfer of `token` to `receiver`.
+        pub fn transfer(receiver: Address, token: Token) -> Self {
+            TransactionBuilder { receiver: Some(receiver), token, ..Self::new(TxKind::Transfer) }
+        }
+
+        /// The registration of `key`, which must then sign the transaction.
+        pub fn register_key(key: &PublicKey) -> Self {
+            Self::new(TxKind::RegisterKey { public_key: key.to_bytes() })
+        }
+
+        pub fn gas_limit(mut self, gas_limit: u64) -> Self {
+            self.gas_limit = gas_limit;
+            self
+        }
+
+        pub fn contract_code(mut self, code: Vec<u8>) -> Self {
+            self.contract_code = Some(code);
+            self
+        }
+
+        pub fn proof(mut self, zkp: ZKProof) -> Self {
+            self.zkp = Some(zkp);
+            self
+        }
+
+        /// Sends from `sender` rather than from the address the signing key derives, for
+        /// accounts whose key was rotated.
+        pub fn sender(mut self, sender: Address) -> Self {
+            self.sender = Some(sender);
+            self
+        }
+
+        /// Uses `nonce` rather than the sender's next nonce on the chain, e.g. after other
+        /// transactions of the sender that are still waiting in a mempool.
+        pub fn nonce(mut self, nonce: u64) -> Self {
+            self.nonce = Some(nonce);
+            self
+        }
+
+        /// The transaction from `sender` on `chain`, not yet signed. Multisig senders add
+        /// their cosignatures to it with `Transaction::cosign`. Fails if the sender has no
+        /// nonce left.
+        pub fn unsigned(self, chain: &Blockchain, sender: Address) -> Result<Transaction, TxError> {
+            let nonce = match self.nonce {
+                Some(nonce) => nonce,
+                None => chain.next_nonce(&sender).ok_or(TxError::NoncesExhausted(sender))?,
+            };
+            Ok(Transaction {
+                chain_id: chain.chain_id,
+                shard_id: chain.assign_shard(&sender),
+                sender,
+                receiver: self.receiver.unwrap_or(sender),
+                token: self.token,
+                nonce,
+                contract_code: self.contract_code,
+                gas_limit: self.gas_limit,
+                zkp: self.zkp,
+                kind: self.kind,
+                scheme: SignatureScheme::default(),
+                signature: String::new(),
+                cosignatures: Vec::new(),
+            })
+        }
+
+        /// The transaction on `chain`, signed with `key`, and its id.
+        pub fn build(self, chain: &Blockchain, key: &SecretKey) -> Result<(Transaction, Hash), TxError> {
+            let sender = self.sender.unwrap_or_else(|| Address::from_key(&key.public_key()));
+            let mut transaction = self.unsigned(chain, sender)?;
+            transaction.sign(key);
+            let hash = transaction.hash();
+            Ok((transaction, hash))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::blockchain::tests::{alice, alice_genesis, alice_key, bob, chain_at, genesis_clock};
+        use crate::multisig::{Authority, MultisigPolicy};
+
+        fn one_token() -> Token {
+            Token::CustodyToken(Amount::from_tokens(1).unwrap())
+        }
+
+        #[test]
+        fn built_transactions_are_signed_for_the_chain() {
+            let clock = genesis_clock();
+            let mut chain = chain_at(&alice_genesis(), &clock);
+            let (first, hash) = TransactionBuilder::transfer(bob(), one_token()).build(&chain, &alice_key()).unwrap();
+            assert_eq!(hash, first.hash());
+            assert_eq!((first.chain_id, first.shard_id, first.sender), (chain.chain_id, chain.assign_shard(&alice()), alice()));
+            assert_eq!((first.nonce, first.gas_limit), (0, DEFAULT_GAS_LIMIT));
+            assert!(first.contract_code.is_none() && first.zkp.is_none());
+            assert_eq!(chain.check_transaction(&first), Ok(()));
+
+            chain.add_block(vec![first], chain.assign_shard(&alice())).unwrap();
+            let (next, _) = TransactionBuilder::transfer(bob(), one_token()).build(&chain, &alice_key()).unwrap();
+            assert_eq!(next.nonce, 1);
+            let (later, _) = TransactionBuilder::transfer(bob(), one_token()).nonce(5).build(&chain, &alice_key()).unwrap();
+            assert_eq!(later.nonce, 5);
+        }
+
+        #[test]
+        fn contract_code_and_proof_are_optional() {
+            let chain = chain_at(&alice_genesis(), &genesis_clock());
+            let zkp = ZKProof { public_input: vec![1, 2], proof: vec![3, 4, 5] };
+            let builder = TransactionBuilder::transfer(bob(), one_token())
+                .contract_code(vec![0, 0x61, 0x73, 0x6d])
+                .proof(zkp)
+                .gas_limit(500);
+            let (transaction, hash) = builder.build(&chain, &alice_key()).unwrap();
+            assert_eq!(transaction.contract_code, Some(vec![0, 0x61, 0x73, 0x6d]));
+            let zkp = transaction.zkp.as_ref().map(|zkp| (zkp.public_input.as_slice(), zkp.proof.as_slice()));
+            assert_eq!(zkp, Some((&[1, 2][..], &[3, 4, 5][..])));
+            assert_eq!(transaction.gas_limit, 500);
+            assert_eq!(hash, transaction.hash());
+        }
+
+        #[test]
+        fn rotated_accounts_name_their_sender() {
+            let clock = genesis_clock();
+            let mut chain = chain_at(&alice_genesis(), &clock);
+            let shard_id = chain.assign_shard(&alice());
+            let rotated = SecretKey::from_bytes(SignatureScheme::Ed25519, &[5u8; 32]).unwrap();
+            let rotate = TransactionBuilder::new(TxKind::RotateKey { authority: Authority::Key(rotated.public_key()) });
+            chain.add_block(vec![rotate.build(&chain, &alice_key()).unwrap().0], shard_id).unwrap();
+
+            // Without `sender`, the new key would send from the address it derives.
+            let (stray, _) = TransactionBuilder::transfer(bob(), one_token()).build(&chain, &rotated).unwrap();
+            assert_eq!(stray.sender, Address::from_key(&rotated.public_key()));
+
+            let (transfer, _) = TransactionBuilder::transfer(bob(), one_token()).sender(alice()).build(&chain, &rotated).unwrap();
+            assert_eq!((transfer.sender, transfer.scheme, transfer.nonce), (alice(), SignatureScheme::Ed25519, 1));
+            chain.add_block(vec![transfer], shard_id).unwrap();
+        }
+
+        #[test]
+        fn multisig_transactions_are_left_unsigned() {
+            let chain = chain_at(&alice_genesis(), &genesis_clock());
+            let cosigner = SecretKey::from_bytes(SignatureScheme::Ed25519, &[5u8; 32]).unwrap();
+            let policy = MultisigPolicy::new(2, vec![alice_key().public_key(), cosigner.public_key()]).unwrap();
+            let vault = policy.address();
+            let mut transaction = TransactionBuilder::transfer(bob(), one_token()).unsigned(&chain, vault).unwrap();
+            assert_eq!((transaction.sender, transaction.shard_id, transaction.nonce), (vault, chain.assign_shard(&vault), 0));
+            assert!(transaction.signature.is_empty() && transaction.cosignatures.is_empty());
+
+            let hash = transaction.hash();
+            transaction.cosign(0, &alice_key());
+            transaction.cosign(1, &cosigner);
+            assert!(transaction.signature.is_empty());
+            assert_eq!(transaction.cosignatures.len(), 2);
+            assert_eq!(transaction.hash(), hash, "cosignatures are not part of the id");
+        }
+    }
+}
+
+pub mod mempool {
+    use super::*;
+    use crate::blockchain::{Block, Blockchain, Transaction, TxError};
+    use crate::hash::Hash;
+    use std::cmp::Ordering;
+    use std::collections::{btree_map, BTreeMap, BinaryHeap, HashMap};
+    use std::fmt;
+
+    #[derive(Debug, Clone)]
+    pub struct MempoolConfig {
+        pub max_transactions: usize,
+        pub max_per_sender: usize,
+    }
+
+    impl Default for MempoolConfig {
+        fn default() -> Self {
+            MempoolConfig {
+                max_transactions: 10_000,
+                max_per_sender: 64,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum MempoolError {
+        Invalid(TxError),
+        Duplicate(Hash),
+        /// The sender already has a pooled transaction with this nonce and at least as much gas.
+        NonceTaken(u64),
+        SenderLimit,
+        /// The pool is full of transactions that rank higher than this one.
+        PoolFull,
+    }
+
+    impl fmt::Display for MempoolError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                MempoolError::Invalid(e) => write!(f, "invalid transaction: {}", e),
+                MempoolError::Duplicate(hash) => write!(f, "transaction {} is already pooled", hash),
+                MempoolError::NonceTaken(nonce) => write!(f, "a transaction with nonce {} is already pooled", nonce),
+                MempoolError::SenderLimit => write!(f, "sender has too many pooled transactions"),
+                MempoolError::PoolFull => write!(f, "mempool is full"),
+            }
+        }
+    }
+
+    impl std::error::Error for MempoolError {}
+
+    #[derive(Debug)]
+    struct PooledTransaction {
+        transaction: Transaction,
+        arrival: u64,
+    }
+
+    /// Ranking of pooled transactions: more gas first, then earlier arrival.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Priority {
+        gas_limit: u64,
+        arrival: u64,
+    }
+
+    impl Ord for Priority {
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.gas_limit
+                .cmp(&other.gas_limit)
+                .then_with(|| other.arrival.cmp(&self.arrival))
+        }
+    }
+
+    impl PartialOrd for Priority {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl PooledTransaction {
+        fn priority(&self) -> Priority {
+            Priority {
+                gas_limit: self.transaction.gas_limit,
+                arrival: self.arrival,
+            }
+        }
+    }
+
+    /// Transactions waiting to be included in a block, deduplicated by id and kept in
+    /// nonce order per sender.
+    #[derive(Debug, Default)]
+    pub struct Mempool {
+        config: MempoolConfig,
+        transactions: HashMap<Hash, PooledTransaction>,
+        by_sender: HashMap<Address, BTreeMap<u64, Hash>>,
+        arrivals: u64,
+    }
+
+    impl Mempool {
+        pub fn new(config: MempoolConfig) -> Self {
+            Mempool {
+                config,
+                ..Default::default()
+            }
+        }
+
+        pub fn len(&self) -> usize {
+            self.transactions.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.transactions.is_empty()
+        }
+
+        pub fn contains(&self, hash: &Hash) -> bool {
+            self.transactions.contains_key(hash)
+        }
+
+        /// Admits `transaction` after checking it against `chain`. A transaction reusing a
+        /// pooled sender/nonce pair replaces it only if it has a higher gas limit; when the
+        /// pool is full the lowest-ranked evictable transaction makes room, if it ranks
+        /// below the newcomer.
+        pub fn insert(&mut self, chain: &Blockchain, transaction: Transaction) -> Result<Hash, MempoolError> {
+            let hash = transaction.hash();
+            if self.transactions.contains_key(&hash) {
+                return Err(MempoolError::Duplicate(hash));
+            }
+            chain.check_transaction(&transaction).map_err(MempoolError::Invalid)?;
+
+            let sender = transaction.sender;
+            let replaced = self.by_sender.get(&sender).and_then(|nonces| nonces.get(&transaction.nonce)).copied();
+            match replaced {
+                Some(existing) => {
+                    if self.transactions[&existing].transaction.gas_limit >= transaction.gas_limit {
+                        return Err(MempoolError::NonceTaken(transaction.nonce));
+                    }
+                    self.remove(&existing);
+                }
+                None => {
+                    let sender_count = self.by_sender.get(&sender).map_or(0, BTreeMap::len);
+                    if sender_count >= self.config.max_per_sender {
+                        return Err(MempoolError::SenderLimit);
+                    }
+                    if self.transactions.len() >= self.config.max_transactions {
+                        let incoming = Priority {
+                            gas_limit: transaction.gas_limit,
+                            arrival: self.arrivals,
+                        };
+                        match self.eviction_candidate() {
+                            Some((victim, priority)) if priority < incoming => {
+                                self.remove(&victim);
+                            }
+                            _ => return Err(MempoolError::PoolFull),
+                        }
+                    }
+                }
+            }
+
+            self.by_sender.entry(sender).or_default().insert(transaction.nonce, hash);
+            self.transactions.insert(
+                hash,
+                PooledTransaction {
+                    transaction,
+                    arrival: self.arrivals,
+                },
+            );
+            self.arrivals += 1;
+            Ok(hash)
+        }
+
+        /// All pooled transactions in the order a block should take them: highest priority
+        /// first, but never ahead of a lower nonce from the same sender.
+        pub fn pending(&self) -> Vec<Transaction> {
+            let mut queues: HashMap<&Address, btree_map::Values<'_, u64, Hash>> = self
+                .by_sender
+                .iter()
+                .map(|(sender, nonces)| (sender, nonces.values()))
+                .collect();
+            let mut heads = BinaryHeap::new();
+            for (sender, queue) in queues.iter_mut() {
+                if let Some(hash) = queue.next() {
+                    heads.push((self.transactions[hash].priority(), *sender, *hash));
+                }
+            }
+
+            let mut ordered = Vec::with_capacity(self.transactions.len());
+            while let Some((_, sender, hash)) = heads.pop() {
+                ordered.push(self.transactions[&hash].transaction.clone());
+                if let Some(next) = queues.get_mut(sender).and_then(Iterator::next) {
+                    heads.push((self.transactions[next].priority(), sender, *next));
+                }
+            }
+            ordered
+        }
+
+        pub fn remove(&mut self, hash: &Hash) -> Option<Transaction> {
+            let pooled = self.transactions.remove(hash)?;
+            let sender = &pooled.transaction.sender;
+            if let Some(nonces) = self.by_sender.get_mut(sender) {
+                nonces.remove(&pooled.transaction.nonce);
+                if nonces.is_empty() {
+                    self.by_sender.remove(sender);
+                }
+            }
+            Some(pooled.transaction)
+        }
+
+        /// Drops the transactions included in `block`, then every pooled transaction that
+        /// no longer passes `Blockchain::check_transaction`, e.g. because its nonce was used
+        /// or its sender can no longer pay for it.
+        pub fn on_block_committed(&mut self, chain: &Blockchain, block: &Block) {
+            for transaction in &block.transactions {
+                self.remove(&transaction.hash());
+            }
+            let stale: Vec<Hash> = self
+                .transactions
+                .iter()
+                .filter(|(_, pooled)| chain.check_transaction(&pooled.transaction).is_err())
+                .map(|(hash, _)| *hash)
+                .collect();
+            for hash in stale {
+                self.remove(&hash);
+            }
+        }
+
+        /// The lowest-ranked transaction that is last in its sender's nonce order, so that
+        /// evicting it leaves no gap.
+        fn eviction_candidate(&self) -> Option<(Hash, Priority)> {
+            self.by_sender
+                .values()
+                .filter_map(|nonces| nonces.values().next_back())
+                .map(|hash| (*hash, self.transactions[hash].priority()))
+                .min_by_key(|(_, priority)| *priority)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::amount::Amount;
+        use crate::blockchain::tests::{alice, alice_genesis, alice_key, bob};
+        use crate::blockchain::{Token, TokenKind};
+        use crate::builder::TransactionBuilder;
+        use crate::crypto::{SecretKey, SignatureScheme};
+        use crate::genesis::{GenesisAccount, GenesisKey};
+
+        fn carol_key() -> SecretKey {
+            SecretKey::from_bytes(SignatureScheme::Ed25519, &[3u8; 32]).unwrap()
+        }
+
+        fn carol() -> Address {
+            Address::from_key(&carol_key().public_key())
+        }
+
+        /// The chain of `alice_genesis`, with Carol's key and 100 CustodyToken added.
+        fn chain() -> Blockchain {
+            let mut genesis = alice_genesis();
+            genesis.accounts.push(GenesisAccount { address: carol(), custody: Amount::from_tokens(100).unwrap(), energy: Amount::ZERO });
+            genesis.public_keys.push(GenesisKey {
+                address: carol(),
+                scheme: SignatureScheme::Ed25519,
+                public_key: hex::encode(carol_key().public_key().to_bytes()),
+            });
+            Blockchain::from_genesis(&genesis).unwrap()
+        }
+
+        /// A transfer of `tokens` CustodyToken to Bob, signed by `key`, with the given nonce
+        /// and gas limit.
+        fn transfer(chain: &Blockchain, key: &SecretKey, tokens: u64, nonce: u64, gas_limit: u64) -> Transaction {
+            let token = Token::CustodyToken(Amount::from_tokens(tokens).unwrap());
+            let builder = TransactionBuilder::transfer(bob(), token).nonce(nonce).gas_limit(gas_limit);
+            builder.build(chain, key).unwrap().0
+        }
+
+        fn pool(max_transactions: usize, max_per_sender: usize) -> Mempool {
+            Mempool::new(MempoolConfig { max_transactions, max_per_sender })
+        }
+
+        #[test]
+        fn admission_checks_signatures_and_nonces() {
+            let mut chain = chain();
+            let mut mempool = Mempool::default();
+            let mut forged = transfer(&chain, &alice_key(), 1, 0, 1000);
+            forged.sign(&SecretKey::from_bytes(SignatureScheme::P256, &[9u8; 32]).unwrap());
+            assert_eq!(mempool.insert(&chain, forged), Err(MempoolError::Invalid(TxError::InvalidSignature)));
+
+            let shard_id = chain.assign_shard(&alice());
+            chain.add_block(vec![transfer(&chain, &alice_key(), 1, 0, 1000)], shard_id).unwrap();
+            let reused = transfer(&chain, &alice_key(), 2, 0, 1000);
+            assert_eq!(mempool.insert(&chain, reused), Err(MempoolError::Invalid(TxError::NonceReuse(0))));
+            assert!(mempool.is_empty());
+            assert!(mempool.insert(&chain, transfer(&chain, &alice_key(), 2, 1, 1000)).is_ok());
+        }
+
+        #[test]
+        fn duplicates_are_refused() {
+            let chain = chain();
+            let mut mempool = Mempool::default();
+            let transaction = transfer(&chain, &alice_key(), 1, 0, 1000);
+            let hash = mempool.insert(&chain, transaction.clone()).unwrap();
+            assert!(mempool.contains(&hash));
+            assert_eq!(mempool.insert(&chain, transaction), Err(MempoolError::Duplicate(hash)));
+            assert_eq!(mempool.len(), 1);
+        }
+
+        #[test]
+        fn replacing_a_nonce_needs_more_gas() {
+            let chain = chain();
+            let mut mempool = Mempool::default();
+            let first = mempool.insert(&chain, transfer(&chain, &alice_key(), 1, 0, 1000)).unwrap();
+            let same_gas = transfer(&chain, &alice_key(), 2, 0, 1000);
+            assert_eq!(mempool.insert(&chain, same_gas), Err(MempoolError::NonceTaken(0)));
+            let replacement = mempool.insert(&chain, transfer(&chain, &alice_key(), 2, 0, 2000)).unwrap();
+            assert_eq!(mempool.len(), 1);
+            assert!(!mempool.contains(&first));
+            assert!(mempool.contains(&replacement));
+        }
+
+        #[test]
+        fn pending_keeps_each_sender_in_nonce_order() {
+            let chain = chain();
+            let mut mempool = Mempool::default();
+            let alice_0 = mempool.insert(&chain, transfer(&chain, &alice_key(), 1, 0, 1000)).unwrap();
+            let alice_1 = mempool.insert(&chain, transfer(&chain, &alice_key(), 1, 1, 5000)).unwrap();
+            let carol_0 = mempool.insert(&chain, transfer(&chain, &carol_key(), 1, 0, 3000)).unwrap();
+            let carol_1 = mempool.insert(&chain, transfer(&chain, &carol_key(), 1, 1, 3000)).unwrap();
+            let order: Vec<Hash> = mempool.pending().iter().map(Transaction::hash).collect();
+            // Both of Carol's transactions outrank Alice's first one. Alice's second outranks
+            // everything but has to wait for her first.
+            assert_eq!(order, vec![carol_0, carol_1, alice_0, alice_1]);
+        }
+
+        #[test]
+        fn senders_are_limited() {
+            let chain = chain();
+            let mut mempool = pool(10, 2);
+            for nonce in 0..2 {
+                mempool.insert(&chain, transfer(&chain, &alice_key(), 1, nonce, 1000)).unwrap();
+            }
+            assert_eq!(mempool.insert(&chain, transfer(&chain, &alice_key(), 1, 2, 1000)), Err(MempoolError::SenderLimit));
+            assert!(mempool.insert(&chain, transfer(&chain, &carol_key(), 1, 0, 1000)).is_ok());
+        }
+
+        #[test]
+        fn eviction_takes_only_a_sender_last_nonce() {
+            let chain = chain();
+            let mut mempool = pool(2, 10);
+            let alice_0 = mempool.insert(&chain, transfer(&chain, &alice_key(), 1, 0, 1000)).unwrap();
+            let alice_1 = mempool.insert(&chain, transfer(&chain, &alice_key(), 1, 1, 5000)).unwrap();
+
+            // Alice's first transaction ranks lowest, but evicting it would leave a gap, and
+            // her last one outranks the newcomer.
+            let outranked = transfer(&chain, &carol_key(), 1, 0, 3000);
+            assert_eq!(mempool.insert(&chain, outranked), Err(MempoolError::PoolFull));
+            let carol_0 = mempool.insert(&chain, transfer(&chain, &carol_key(), 1, 0, 6000)).unwrap();
+            assert_eq!(mempool.len(), 2);
+            assert!(mempool.contains(&alice_0) && mempool.contains(&carol_0));
+            assert!(!mempool.contains(&alice_1));
+        }
+
+        #[test]
+        fn committed_blocks_drop_included_and_stale_transactions() {
+            let mut chain = chain();
+            let mut mempool = Mempool::default();
+            let pooled = mempool.insert(&chain, transfer(&chain, &alice_key(), 1, 0, 1000)).unwrap();
+            let next = mempool.insert(&chain, transfer(&chain, &alice_key(), 1, 1, 1000)).unwrap();
+            let included = transfer(&chain, &carol_key(), 1, 0, 1000);
+            let included_hash = mempool.insert(&chain, included.clone()).unwrap();
+
+            // Another transaction of Alice's takes nonce 0 before the pooled one.
+            let shard_id = chain.assign_shard(&alice());
+            let block = chain.add_block(vec![transfer(&chain, &alice_key(), 2, 0, 1000)], shard_id).unwrap();
+            mempool.on_block_committed(&chain, &block);
+            assert!(!mempool.contains(&pooled));
+            assert!(mempool.contains(&next));
+
+            let block = chain.add_block(vec![included], chain.assign_shard(&carol())).unwrap();
+            mempool.on_block_committed(&chain, &block);
+            assert!(!mempool.contains(&included_hash));
+            assert_eq!(mempool.len(), 1);
+        }
+
+        #[test]
+        fn unaffordable_transfers_leave_the_pool() {
+            let mut chain = chain();
+            let mut mempool = Mempool::default();
+            let needed = Amount::from_tokens(101).unwrap();
+            let available = Amount::from_tokens(100).unwrap();
+            let overspend = TxError::InsufficientFunds { kind: TokenKind::Custody, needed, available };
+            assert_eq!(mempool.insert(&chain, transfer(&chain, &alice_key(), 101, 0, 1000)), Err(MempoolError::Invalid(overspend)));
+
+            // Each transfer is affordable on its own, but not both.
+            let first = transfer(&chain, &alice_key(), 80, 0, 1000);
+            mempool.insert(&chain, first.clone()).unwrap();
+            let second = mempool.insert(&chain, transfer(&chain, &alice_key(), 30, 1, 1000)).unwrap();
+            let block = chain.add_block(vec![first], chain.assign_shard(&alice())).unwrap();
+            mempool.on_block_committed(&chain, &block);
+            assert!(!mempool.contains(&second), "a transfer Alice can no longer pay would block her later ones");
+
+            let smaller = mempool.insert(&chain, transfer(&chain, &alice_key(), 10, 1, 1000)).unwrap();
+            assert_eq!(mempool.pending().iter().map(Transaction::hash).collect::<Vec<_>>(), vec![smaller]);
+        }
+    }
+}
+
+pub mod producer {
+    use crate::beacon::BeaconBlock;
+    use crate::blockchain::{Block, BlockDraft, Blockchain, ChainError};
+    use crate::crypto::SecretKey;
+    use crate::mempool::Mempool;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    #[derive(Debug, Clone)]
+    pub struct ProducerConfig {
+        pub max_block_gas: u64,
+        pub max_block_bytes: usize, // Sum of `Transaction::size` over the block
+        pub interval: Duration, // Time between rounds run by `tick`
+        pub proposer: Option<SecretKey>, // Validator key that signs produced blocks; required once the chain has validators
+    }
+
+    impl Default for ProducerConfig {
+        fn default() -> Self {
+            ProducerConfig {
+                max_block_gas: 10_000_000,
+                max_block_bytes: 1 << 20,
+                interval: Duration::from_secs(5),
+                proposer: None,
+            }
+        }
+    }
+
+    /// What one round of block production did. Blocks already committed stay committed
+    /// when another shard's block is refused.
+    #[derive(Debug, Default)]
+    pub struct Round {
+        pub blocks: Vec<Block>, // Committed blocks, in shard order
+        pub failed: Vec<(u64, ChainError)>, // Shards whose block was refused, with the reason
+        pub beacon: Option<Result<BeaconBlock, ChainError>>, // The beacon commit, made if any block was added
+    }
+
+    /// Turns pooled transactions into blocks, one per shard with pending work.
+    #[derive(Debug)]
+    pub struct BlockProducer {
+        config: ProducerConfig,
+        last_round: Option<u64>, // Chain clock time of the last round run by `tick`, in milliseconds
+    }
+
+    impl BlockProducer {
+        pub fn new(config: ProducerConfig) -> Self {
+            BlockProducer { config, last_round: None }
+        }
+
+        /// Whether a round is due at `now`, in unix milliseconds: none has run yet, or
+        /// `interval` has passed since the last one.
+        pub fn is_due(&self, now: u64) -> bool {
+            let interval = u64::try_from(self.config.interval.as_millis()).unwrap_or(u64::MAX);
+            match self.last_round {
+                None => true,
+                Some(last) => now.saturating_sub(last) >= interval,
+            }
+        }
+
+        /// Runs `produce` if a round is due at the time of the chain's clock, and returns an
+        /// empty round otherwise.
+        pub fn tick(&mut self, chain: &mut Blockchain, mempool: &mut Mempool) -> Round {
+            let now = chain.clock.now_millis();
+            if !self.is_due(now) {
+                return Round::default();
+            }
+            self.last_round = Some(now);
+            self.produce(chain, mempool)
+        }
+
+        /// Builds and commits one block per shard from the mempool, in mempool order, then a
+        /// beacon block committing the new shard heads if any shard got a block.
+        ///
+        /// Transactions go to the shard they were signed for. Each block is filled until
+        /// the next transaction would exceed the gas or byte limit; transactions that fail
+        /// validation are skipped, as are later transactions from the same sender, so no
+        /// nonce is taken out of order. Shards whose draft ends up empty get no block unless
+        /// they have cross-shard receipts to settle. A shard whose block is refused is
+        /// reported in the round and does not keep the other shards from producing.
+        pub fn produce(&mut self, chain: &mut Blockchain, mempool: &mut Mempool) -> Round {
+            let pending = mempool.pending();
+            let mut round = Round::default();
+            let shard_ids: Vec<u64> = chain.shards.iter().map(|shard| shard.id).collect();
+            for shard_id in shard_ids {
+                let mut draft = BlockDraft::new(shard_id);
+                let mut gas = 0u64;
+                let mut bytes = 0usize;
+                let mut skipped_senders = HashSet::new();
+
+                for transaction in pending.iter().filter(|tx| tx.shard_id == shard_id) {
+                    if skipped_senders.contains(&transaction.sender) {
+                        continue;
+                    }
+                    let size = transaction.size();
+                    let fits = gas
+                        .checked_add(transaction.gas_limit)
+                        .is_some_and(|total| total <= self.config.max_block_gas)
+                        && bytes + size <= self.config.max_block_bytes;
+                    if !fits || chain.try_include(&mut draft, transaction.clone()).is_err() {
+                        skipped_senders.insert(transaction.sender);
+                        continue;
+                    }
+                    gas += transaction.gas_limit;
+                    bytes += size;
+                }
+
+                if draft.is_empty() && !chain.has_due_receipts(shard_id) {
+                    continue;
+                }
+                match chain.propose_block(draft.into_transactions(), shard_id, self.config.proposer.as_ref()) {
+                    Ok(block) => {
+                        mempool.on_block_committed(chain, &block);
+                        round.blocks.push(block);
+                    }
+                    Err(e) => round.failed.push((shard_id, e)),
+                }
+            }
+            if !round.blocks.is_empty() {
+                round.beacon = Some(chain.commit_beacon());
+            }
+            round
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::address::Address;
+        use crate::amount::Amount;
+        use crate::blockchain::tests::{alice, alice_genesis, alice_key, bob, genesis_clock, pay_bob};
+        use crate::blockchain::{Token, Transaction};
+        use crate::builder::TransactionBuilder;
+        use crate::clock::{Clock, ManualClock};
+        use crate::crypto::SignatureScheme;
+        use crate::genesis::{GenesisAccount, GenesisConfig, GenesisKey};
+        use crate::storage::{MemoryBackend, StateSnapshot, StorageBackend, StorageError};
+        use std::sync::Arc;
+
+        /// Adds an Ed25519 key with 100 CustodyToken to `genesis`, for an address on Alice's
+        /// shard or on another one.
+        fn funded_key(genesis: &mut GenesisConfig, on_alice_shard: bool) -> SecretKey {
+            let chain = Blockchain::from_genesis(genesis).unwrap();
+            let key = (1u8..)
+                .map(|seed| SecretKey::from_bytes(SignatureScheme::Ed25519, &[seed; 32]).unwrap())
+                .find(|key| {
+                    let shard_id = chain.assign_shard(&Address::from_key(&key.public_key()));
+                    (shard_id == chain.assign_shard(&alice())) == on_alice_shard
+                })
+                .unwrap();
+            let address = Address::from_key(&key.public_key());
+            genesis.accounts.push(GenesisAccount { address, custody: Amount::from_tokens(100).unwrap(), energy: Amount::ZERO });
+            genesis.public_keys.push(GenesisKey {
+                address,
+                scheme: SignatureScheme::Ed25519,
+                public_key: hex::encode(key.public_key().to_bytes()),
+            });
+            key
+        }
+
+        /// A chain of `genesis` on `clock`, and a pool holding `transactions`.
+        fn pooled(
+            genesis: &GenesisConfig,
+            clock: &Arc<ManualClock>,
+            transactions: impl Fn(&Blockchain) -> Vec<Transaction>,
+        ) -> (Blockchain, Mempool) {
+            let mut chain = Blockchain::from_genesis(genesis).unwrap();
+            chain.clock = clock.clone();
+            let mut mempool = Mempool::default();
+            for transaction in transactions(&chain) {
+                mempool.insert(&chain, transaction).unwrap();
+            }
+            (chain, mempool)
+        }
+
+        /// Alice's transfer of `tokens` CustodyToken to Bob with the given nonce and gas limit.
+        fn pay_bob_at(chain: &Blockchain, tokens: u64, nonce: u64, gas_limit: u64) -> Transaction {
+            let token = Token::CustodyToken(Amount::from_tokens(tokens).unwrap());
+            let builder = TransactionBuilder::transfer(bob(), token).nonce(nonce).gas_limit(gas_limit);
+            builder.build(chain, &alice_key()).unwrap().0
+        }
+
+        fn producer(config: ProducerConfig) -> BlockProducer {
+            BlockProducer::new(config)
+        }
+
+        fn pay_bob_from(chain: &Blockchain, key: &SecretKey) -> Transaction {
+            let token = Token::CustodyToken(Amount::from_tokens(1).unwrap());
+            TransactionBuilder::transfer(bob(), token).build(chain, key).unwrap().0
+        }
+
+        /// A `MemoryBackend` that refuses to log any block of one shard after its genesis.
+        #[derive(Debug, Default)]
+        struct BrokenShard {
+            inner: MemoryBackend,
+            shard_id: u64,
+        }
+
+        impl StorageBackend for BrokenShard {
+            fn append_block(&mut self, shard_id: u64, block: &Block) -> Result<(), StorageError> {
+                if shard_id == self.shard_id && block.index > 0 {
+                    return Err(StorageError::Corrupt("disk full".to_string()));
+                }
+                self.inner.append_block(shard_id, block)
+            }
+
+            fn load_blocks(&self, shard_id: u64) -> Result<Vec<Block>, StorageError> {
+                self.inner.load_blocks(shard_id)
+            }
+
+            fn truncate_blocks(&mut self, shard_id: u64, len: u64) -> Result<(), StorageError> {
+                self.inner.truncate_blocks(shard_id, len)
+            }
+
+            fn append_beacon_block(&mut self, block: &BeaconBlock) -> Result<(), StorageError> {
+                self.inner.append_beacon_block(block)
+            }
+
+            fn load_beacon_blocks(&self) -> Result<Vec<BeaconBlock>, StorageError> {
+                self.inner.load_beacon_blocks()
+            }
+
+            fn truncate_beacon_blocks(&mut self, len: u64) -> Result<(), StorageError> {
+                self.inner.truncate_beacon_blocks(len)
+            }
+
+            fn save_snapshot(&mut self, snapshot: &StateSnapshot) -> Result<(), StorageError> {
+                self.inner.save_snapshot(snapshot)
+            }
+
+            fn load_snapshot(&self) -> Result<Option<StateSnapshot>, StorageError> {
+                self.inner.load_snapshot()
+            }
+        }
+
+        #[test]
+        fn refused_blocks_do_not_stop_the_other_shards() {
+            let mut genesis = alice_genesis();
+            let dave = funded_key(&mut genesis, false);
+            let shard_id = Blockchain::from_genesis(&genesis).unwrap().assign_shard(&alice());
+            let storage = BrokenShard { shard_id, ..Default::default() };
+            let mut chain = Blockchain::open(Box::new(storage), &genesis).unwrap();
+            let mut mempool = Mempool::default();
+            mempool.insert(&chain, pay_bob(&chain, 10)).unwrap();
+            let daves = mempool.insert(&chain, pay_bob_from(&chain, &dave)).unwrap();
+
+            let round = BlockProducer::new(ProducerConfig::default()).produce(&mut chain, &mut mempool);
+            let other = chain.assign_shard(&Address::from_key(&dave.public_key()));
+            assert_eq!(round.blocks.iter().map(|block| block.shard_id).collect::<Vec<_>>(), vec![Some(other)]);
+            assert_eq!(round.blocks[0].transactions[0].hash(), daves);
+            assert_eq!(round.failed.len(), 1);
+            assert!(matches!(round.failed[0], (failed, ChainError::Storage(_)) if failed == shard_id));
+            let beacon = round.beacon.unwrap().unwrap();
+            assert!(beacon.commits(other, 1));
+            assert_eq!(chain.finalized_index(shard_id), 0);
+            assert_eq!(mempool.len(), 1, "Alice's transfer waits for the next round");
+        }
+
+        #[test]
+        fn tick_runs_a_round_per_interval() {
+            let clock = genesis_clock();
+            let (mut chain, mut mempool) = pooled(&alice_genesis(), &clock, |chain| vec![pay_bob_at(chain, 1, 0, 1000)]);
+            let mut producer = producer(ProducerConfig { interval: Duration::from_secs(5), ..Default::default() });
+            assert_eq!(producer.tick(&mut chain, &mut mempool).blocks.len(), 1, "the first round is due at once");
+
+            mempool.insert(&chain, pay_bob_at(&chain, 1, 1, 1000)).unwrap();
+            clock.advance(4_999);
+            let round = producer.tick(&mut chain, &mut mempool);
+            assert!(round.blocks.is_empty() && round.beacon.is_none());
+            assert_eq!(mempool.len(), 1);
+            clock.advance(1);
+            let round = producer.tick(&mut chain, &mut mempool);
+            // Bob's shard may get a block too, settling the first transfer.
+            assert!(round.blocks.iter().any(|block| block.transactions.iter().any(|tx| tx.nonce == 1)));
+            assert!(round.beacon.unwrap().is_ok());
+            assert!(mempool.is_empty());
+            assert!(!producer.is_due(clock.now_millis()));
+        }
+
+        #[test]
+        fn blocks_stay_within_the_gas_limit() {
+            let clock = genesis_clock();
+            let (mut chain, mut mempool) =
+                pooled(&alice_genesis(), &clock, |chain| (0..3).map(|nonce| pay_bob_at(chain, 1, nonce, 1000)).collect());
+            let round = producer(ProducerConfig { max_block_gas: 2500, ..Default::default() }).produce(&mut chain, &mut mempool);
+            assert_eq!(round.blocks[0].transactions.iter().map(|tx| tx.nonce).collect::<Vec<_>>(), vec![0, 1]);
+            assert_eq!(mempool.len(), 1);
+        }
+
+        #[test]
+        fn blocks_stay_within_the_byte_limit() {
+            let clock = genesis_clock();
+            let (mut chain, mut mempool) =
+                pooled(&alice_genesis(), &clock, |chain| (0..2).map(|nonce| pay_bob_at(chain, 1, nonce, 1000)).collect());
+            let sizes: Vec<usize> = mempool.pending().iter().map(Transaction::size).collect();
+            let max_block_bytes = sizes[0] + sizes[1] - 1;
+            let round = producer(ProducerConfig { max_block_bytes, ..Default::default() }).produce(&mut chain, &mut mempool);
+            assert_eq!(round.blocks[0].transactions.len(), 1);
+            assert_eq!(mempool.len(), 1);
+        }
+
+        #[test]
+        fn invalid_transactions_are_skipped() {
+            let mut genesis = alice_genesis();
+            let carol = funded_key(&mut genesis, true);
+            // Alice can pay either transfer, but not both; Carol's still goes in.
+            let (mut chain, mut mempool) = pooled(&genesis, &genesis_clock(), |chain| {
+                vec![pay_bob_at(chain, 80, 0, 1000), pay_bob_at(chain, 30, 1, 1000), pay_bob_from(chain, &carol)]
+            });
+            let round = producer(ProducerConfig::default()).produce(&mut chain, &mut mempool);
+            assert!(round.failed.is_empty());
+            let included: Vec<(Address, u64)> = round.blocks[0].transactions.iter().map(|tx| (tx.sender, tx.nonce)).collect();
+            assert_eq!(included, vec![(alice(), 0), (Address::from_key(&carol.public_key()), 0)]);
+            assert!(mempool.is_empty(), "the transfer Alice can no longer pay is dropped");
+        }
+    }
+}
+
+pub mod consensus {
+    pub trait Consensus {
+        fn validate_block(&self, block: &super::blockchain::Block) -> bool;
+    }
+
+    pub struct ProofOfStake;
+
+    impl Consensus for ProofOfStake {
+        fn validate_block(&self, block: &super::blockchain::Block) -> bool {
+            // Implement Proof of Stake logic, including stake validation and penalties
+            // Example placeholder
+            block.transactions.iter().all(|tx| tx.gas_limit > 0)
+        }
+    }
+}
+
+pub mod contracts {
+    pub fn execute_wasm_contract(wasm_code: Vec<u8>, gas_limit: u64) -> Result<(), String> {
+        use wasmer::{Instance, Module, Store, imports};
+        let store = Store::default();
+        let module = Module::new(&store, wasm_code).map_err(|e| e.to_string())?;
+        let import_object = imports! {};
+        let instance = Instance::new(&module, &import_object).map_err(|e| e.to_string())?;
+
+        // Implement gas metering (example placeholder)
+        if gas_limit < 500 { // Arbitrary minimum gas
+            return Err("Insufficient gas".to_string());
+        }
+
+        let main = instance.exports.get_function("main").map_err(|e| e.to_string())?;
+        main.call(&[]).map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+}
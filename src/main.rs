@@ -0,0 +1,136 @@
+// CrawChain demo: funds Alice, sends Bob a transfer through the mempool and block producer,
+// and prints the resulting chain.
+
+use crawchain::address::Address;
+use crawchain::{blockchain, builder, genesis, mempool, producer, storage, wallet};
+
+fn main() {
+    use crawchain::amount::Amount;
+    use crawchain::crypto::{SecretKey, SignatureScheme};
+    // Keep Alice's key in the keystore given as the third argument, encrypted with the
+    // password in CRAWCHAIN_PASSWORD. Without a keystore Alice gets a fixed demo key, so
+    // that the genesis funding her stays the same and a stored chain opens again.
+    let mut wallet = match std::env::args().nth(3) {
+        Some(path) => {
+            let password = match std::env::var("CRAWCHAIN_PASSWORD") {
+                Ok(password) if !password.is_empty() => password,
+                _ => {
+                    println!("Set CRAWCHAIN_PASSWORD to the keystore password");
+                    return;
+                }
+            };
+            let opened = match std::path::Path::new(&path).exists() {
+                true => wallet::Wallet::open(&path, &password),
+                false => wallet::Wallet::create(&path, &password, wallet::KdfParams::default()),
+            };
+            match opened {
+                Ok(wallet) => wallet,
+                Err(e) => {
+                    println!("Failed to open keystore: {}", e);
+                    return;
+                }
+            }
+        }
+        None => {
+            let mut wallet = wallet::Wallet::in_memory();
+            let demo_key = SecretKey::from_bytes(SignatureScheme::P256, &[1u8; 32]).expect("valid demo key");
+            wallet.import(demo_key).expect("storing Alice's demo key");
+            wallet
+        }
+    };
+    let alice = match wallet.addresses().first() {
+        Some(address) => *address,
+        None => wallet.generate(SignatureScheme::P256).expect("storing Alice's key"),
+    };
+    let alice_public_key = wallet.public_key(&alice).expect("Alice's key is in the wallet");
+    let bob = Address::from_key(&SecretKey::generate(SignatureScheme::Ed25519).public_key());
+
+    // Keep the chain in the directory given as the first argument, or in memory.
+    let storage: Box<dyn storage::StorageBackend> = match std::env::args().nth(1) {
+        Some(dir) => Box::new(storage::FileBackend::open(dir).expect("opening storage directory")),
+        None => Box::new(storage::MemoryBackend::default()),
+    };
+    // Start from the genesis file given as the second argument, or the development genesis.
+    let mut genesis = match std::env::args().nth(2) {
+        Some(path) => match genesis::GenesisConfig::load(path) {
+            Ok(genesis) => genesis,
+            Err(e) => {
+                println!("Failed to load genesis: {}", e);
+                return;
+            }
+        },
+        None => genesis::GenesisConfig::default(),
+    };
+    // Alice starts with 100 CustodyToken unless the genesis already funds her. This changes
+    // the genesis hash, so a stored chain only opens again with the same key for Alice.
+    if !genesis.accounts.iter().any(|account| account.address == alice) {
+        genesis.accounts.push(genesis::GenesisAccount {
+            address: alice,
+            custody: Amount::from_tokens(100).expect("valid amount"),
+            energy: Amount::ZERO,
+        });
+    }
+    let mut blockchain = match blockchain::Blockchain::open(storage, &genesis) {
+        Ok(blockchain) => blockchain,
+        Err(e) => {
+            println!("Failed to load blockchain: {}", e);
+            return;
+        }
+    };
+    let mut transactions = Vec::new();
+    // Alice registers her key on chain before she can send anything else.
+    if blockchain.authority(&alice).is_none() {
+        transactions.push(builder::TransactionBuilder::register_key(&alice_public_key));
+    }
+    transactions.push(builder::TransactionBuilder::transfer(
+        bob,
+        blockchain::Token::CustodyToken("10".parse().expect("valid amount")),
+    ));
+
+    let mut mempool = mempool::Mempool::new(mempool::MempoolConfig::default());
+    // Blocks are signed by the first genesis validator whose key is in the wallet; a chain
+    // with validators rejects them otherwise.
+    let proposer = genesis.validators.iter().find_map(|validator| wallet.export(&validator.address).ok().cloned());
+    let interval = std::time::Duration::from_secs(1);
+    let mut producer = producer::BlockProducer::new(producer::ProducerConfig { proposer, interval, ..Default::default() });
+    for transaction in transactions {
+        // Built only now, so that the nonce follows the transactions produced before.
+        let inserted = transaction
+            .build(&blockchain, wallet.export(&alice).expect("Alice's key is in the wallet"))
+            .map_err(mempool::MempoolError::Invalid)
+            .and_then(|(transaction, _)| mempool.insert(&blockchain, transaction));
+        match inserted {
+            Ok(hash) => println!("Submitted transaction {}", hash),
+            Err(e) => println!("Transaction rejected: {}", e),
+        }
+        while !producer.is_due(blockchain.clock.now_millis()) {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        let round = producer.tick(&mut blockchain, &mut mempool);
+        for block in &round.blocks {
+            println!("Added block {} to shard {:?}", block.index, block.shard_id);
+        }
+        for (shard_id, e) in &round.failed {
+            println!("Block for shard {} rejected: {}", shard_id, e);
+        }
+        if let Some(Err(e)) = &round.beacon {
+            println!("Beacon block rejected: {}", e);
+        }
+    }
+
+    for (name, address) in [("Alice", alice), ("Bob", bob)] {
+        let balances = blockchain.state.balances(&address);
+        println!("{} ({}) balances: {} CustodyToken, {} EnergyToken", name, address, balances.custody, balances.energy);
+    }
+
+    println!("Genesis hash: {}", blockchain.genesis_hash);
+    println!("Beacon height: {}", blockchain.beacon_head().index);
+    match blockchain.verify_chain() {
+        Ok(()) => println!("Chain verified"),
+        Err(e) => println!("Chain verification failed: {}", e),
+    }
+
+    println!("Blockchain state:");
+    println!("{:#?}", blockchain);
+}
+